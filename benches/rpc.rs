@@ -0,0 +1,54 @@
+//! Benchmark of serving a cached object as an RPC response
+//!
+//! `copy` is the path before the bodies were reference counted: the object is
+//! copied out of the cache into the response, then into the write buffer.
+//! `zero_copy` shares the object with the response, and writes it with a
+//! vectored write after the encoded head. The frames are written to a sink,
+//! so only the copies in user space are measured, not the socket.
+
+use bytes::{Bytes, BytesMut};
+use cache_proxy::rpc::codec::{FrameWriter, RPCCodec};
+use cache_proxy::rpc::RPCResponse;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::runtime;
+use tokio_util::codec::Encoder;
+
+fn frame_write(c: &mut Criterion) {
+    let runtime = runtime::Builder::new_current_thread().build().unwrap();
+    let mut group = c.benchmark_group("frame_write");
+
+    for size in [4 * 1024, 64 * 1024, 1024 * 1024] {
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let cached = vec![7_u8; size];
+        let mut codec = RPCCodec::default();
+        let mut sink = io::sink();
+        group.bench_with_input(BenchmarkId::new("copy", size), &cached, |b, cached| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let response = RPCResponse::new(1, 1, None, Some(Bytes::from(cached.clone())));
+                    let mut buf = BytesMut::new();
+                    codec.encode(response.into(), &mut buf).unwrap();
+                    sink.write_all(&buf).await.unwrap();
+                })
+            })
+        });
+
+        let cached = Bytes::from(cached);
+        let mut writer = FrameWriter::new(io::sink(), RPCCodec::default());
+        group.bench_with_input(BenchmarkId::new("zero_copy", size), &cached, |b, cached| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let response = RPCResponse::new(1, 1, None, Some(cached.clone()));
+                    writer.write_frames([response.into()]).await.unwrap();
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, frame_write);
+criterion_main!(benches);
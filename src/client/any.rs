@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+
+use super::blocking::Blocking;
+use super::etcd::ETCDClient;
+use super::file::FileMetaClient;
+use super::memory::InMemoryMetaClient;
+use super::redis::RedisClient;
+use super::watch::ChannelWatcher;
+use super::{MetaClient, Txn};
+
+/// Meta data client of any supported backend
+///
+/// It's chosen at runtime by `Config::meta_type`, see `new_meta_client`.
+#[derive(Debug)]
+pub enum AnyMetaClient {
+    /// ETCD
+    ETCD(Blocking<ETCDClient>),
+    /// Redis
+    Redis(Blocking<RedisClient>),
+    /// In-memory
+    Memory(InMemoryMetaClient),
+    /// Local directory
+    File(Blocking<FileMetaClient>),
+}
+
+/// Call the method on the client of the backend
+macro_rules! dispatch {
+    ($client:expr, $method:ident($($arg:expr),*)) => {
+        match *$client {
+            AnyMetaClient::ETCD(ref client) => client.$method($($arg),*).await,
+            AnyMetaClient::Redis(ref client) => client.$method($($arg),*).await,
+            AnyMetaClient::Memory(ref client) => client.$method($($arg),*).await,
+            AnyMetaClient::File(ref client) => client.$method($($arg),*).await,
+        }
+    };
+}
+
+#[async_trait]
+impl MetaClient for AnyMetaClient {
+    type Watcher = ChannelWatcher;
+
+    fn from_endpoints(_endpoints: Vec<String>) -> Result<Self, Error> {
+        Err(anyhow!("the backend is unknown from the endpoints, create the client with `new_meta_client`"))
+    }
+
+    async fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        dispatch!(self, create(path, data))
+    }
+
+    async fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        dispatch!(self, update(path, data))
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), Error> {
+        dispatch!(self, delete(path))
+    }
+
+    async fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+        dispatch!(self, read(path, must))
+    }
+
+    async fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+        dispatch!(self, list(path, must))
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        dispatch!(self, close())
+    }
+
+    async fn watch(&self, path: &str) -> Result<Self::Watcher, Error> {
+        dispatch!(self, watch(path))
+    }
+
+    async fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        dispatch!(self, read_revision(path))
+    }
+
+    async fn txn(&self, txn: Txn) -> Result<u64, Error> {
+        dispatch!(self, txn(txn))
+    }
+
+    async fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+        dispatch!(self, grant_lease(ttl))
+    }
+
+    async fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+        dispatch!(self, keep_alive(lease))
+    }
+
+    async fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+        dispatch!(self, revoke_lease(lease))
+    }
+}
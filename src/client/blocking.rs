@@ -0,0 +1,270 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use tokio::{task, time};
+
+use super::watch::ChannelWatcher;
+use super::{Compare, MetaClient, Txn, UnavailableError};
+
+/// Default timeout of a single call of `Blocking`
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Blocking meta data client trait
+///
+/// It's the synchronous counterpart of `MetaClient` for the clients doing
+/// blocking io, which are used in async code through `Blocking`.
+pub trait BlockingMetaClient: Send + Sync + 'static {
+    /// Create a meta data client connecting to the endpoints
+    fn from_endpoints(endpoints: Vec<String>) -> Result<Self, Error>
+    where
+        Self: Sized;
+
+    /// Create a new meta data
+    fn create(&self, path: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Update the meta data
+    fn update(&self, path: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Delete the meta data
+    fn delete(&self, path: &str) -> Result<(), Error>;
+
+    /// Read the meta data
+    fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error>;
+
+    /// List the meta data
+    fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error>;
+
+    /// Close the meta data client
+    fn close(&self) -> Result<(), Error>;
+
+    /// Watch the meta data changes under the path prefix, from the current revision
+    fn watch(&self, path: &str) -> Result<ChannelWatcher, Error>;
+
+    /// Read the meta data with its mod revision, None if it does not exist
+    fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error>;
+
+    /// Commit the ops atomically if all the compares hold, return the commit revision
+    fn txn(&self, txn: Txn) -> Result<u64, Error>;
+
+    /// Grant a lease which expires after `ttl` without keepalive, return the lease id
+    fn grant_lease(&self, ttl: Duration) -> Result<u64, Error>;
+
+    /// Refresh the lease to its full ttl, fail if it's expired or revoked
+    fn keep_alive(&self, lease: u64) -> Result<(), Error>;
+
+    /// Revoke the lease, the meta data attached to it are deleted
+    fn revoke_lease(&self, lease: u64) -> Result<(), Error>;
+
+    /// Update the meta data only if its mod revision is `revision`, 0 means it does not exist
+    fn compare_and_swap(&self, path: &str, revision: u64, data: &[u8]) -> Result<u64, Error> {
+        self.txn(Txn::new().when(Compare::ModRevision(path.to_owned(), revision)).put(path, data))
+    }
+}
+
+/// Async meta data client running a blocking client on the blocking thread pool
+///
+/// A call runs to completion on the blocking thread pool even if its future
+/// is dropped or timed out, so cancellation never leaves the inner client
+/// half way, e.g. with a reply not read from a connection.
+#[derive(Debug)]
+pub struct Blocking<B> {
+    /// The blocking client
+    inner: Arc<B>,
+    /// Timeout of a single call
+    timeout: Duration,
+}
+
+impl<B: BlockingMetaClient> Blocking<B> {
+    /// Create a new async client with the blocking client
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            timeout: DEFAULT_CALL_TIMEOUT,
+        }
+    }
+
+    /// Set the timeout of a single call
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Get the blocking client
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Run the call on the blocking thread pool with timeout
+    async fn call<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&B) -> Result<T, Error> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        match time::timeout(self.timeout, task::spawn_blocking(move || f(&inner))).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => Err(anyhow!("meta data call failed: {}", e)),
+            Err(_) => Err(UnavailableError::new(format!("meta data call timed out after {:?}", self.timeout)).into()),
+        }
+    }
+}
+
+impl<B> Clone for Blocking<B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<B: BlockingMetaClient> MetaClient for Blocking<B> {
+    type Watcher = ChannelWatcher;
+
+    fn from_endpoints(endpoints: Vec<String>) -> Result<Self, Error> {
+        Ok(Self::new(B::from_endpoints(endpoints)?))
+    }
+
+    async fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let (path, data) = (path.to_owned(), data.to_vec());
+        self.call(move |client| client.create(&path, &data)).await
+    }
+
+    async fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let (path, data) = (path.to_owned(), data.to_vec());
+        self.call(move |client| client.update(&path, &data)).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), Error> {
+        let path = path.to_owned();
+        self.call(move |client| client.delete(&path)).await
+    }
+
+    async fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+        let path = path.to_owned();
+        self.call(move |client| client.read(&path, must)).await
+    }
+
+    async fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+        let path = path.to_owned();
+        self.call(move |client| client.list(&path, must)).await
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.call(|client| client.close()).await
+    }
+
+    async fn watch(&self, path: &str) -> Result<Self::Watcher, Error> {
+        let path = path.to_owned();
+        self.call(move |client| client.watch(&path)).await
+    }
+
+    async fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        let path = path.to_owned();
+        self.call(move |client| client.read_revision(&path)).await
+    }
+
+    async fn txn(&self, txn: Txn) -> Result<u64, Error> {
+        self.call(move |client| client.txn(txn)).await
+    }
+
+    async fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+        self.call(move |client| client.grant_lease(ttl)).await
+    }
+
+    async fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+        self.call(move |client| client.keep_alive(lease)).await
+    }
+
+    async fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+        self.call(move |client| client.revoke_lease(lease)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::*;
+    use crate::client::Watcher;
+
+    /// A blocking client which sleeps in every call
+    #[derive(Debug, Default)]
+    struct SlowClient {
+        data: Mutex<Vec<u8>>,
+    }
+
+    impl BlockingMetaClient for SlowClient {
+        fn from_endpoints(_endpoints: Vec<String>) -> Result<Self, Error> {
+            Ok(Self::default())
+        }
+
+        fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+            self.update(path, data)
+        }
+
+        fn update(&self, _path: &str, data: &[u8]) -> Result<(), Error> {
+            thread::sleep(Duration::from_millis(50));
+            *self.data.lock().unwrap() = data.to_vec();
+            Ok(())
+        }
+
+        fn delete(&self, _path: &str) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn read(&self, _path: &str, _must: bool) -> Result<Vec<u8>, Error> {
+            Ok(self.data.lock().unwrap().clone())
+        }
+
+        fn list(&self, _path: &str, _must: bool) -> Result<Vec<String>, Error> {
+            Ok(Vec::new())
+        }
+
+        fn close(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn watch(&self, _path: &str) -> Result<ChannelWatcher, Error> {
+            let (_sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+            Ok(ChannelWatcher::new(receiver))
+        }
+
+        fn read_revision(&self, _path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+            Ok(None)
+        }
+
+        fn txn(&self, _txn: Txn) -> Result<u64, Error> {
+            Ok(0)
+        }
+
+        fn grant_lease(&self, _ttl: Duration) -> Result<u64, Error> {
+            Ok(1)
+        }
+
+        fn keep_alive(&self, _lease: u64) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn revoke_lease(&self, _lease: u64) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocking_timeout() {
+        let client = Blocking::<SlowClient>::from_endpoints(Vec::new()).unwrap().with_timeout(Duration::from_millis(10));
+
+        // The timed out call still completes in background
+        assert!(client.update("/slots", b"v1").await.is_err());
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(client.read("/slots", true).await.unwrap(), b"v1".to_vec());
+
+        let mut watcher = client.watch("/").await.unwrap();
+        assert!(watcher.next(Duration::from_millis(10)).await.is_err());
+    }
+}
@@ -0,0 +1,1061 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use super::blocking::BlockingMetaClient;
+use super::watch::ChannelWatcher;
+use super::{Compare, ConflictError, Txn, TxnOp, UnavailableError, WatchEvent};
+
+/// Default timeout for a single etcd request
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// ETCD client
+///
+/// This struct is used to interact with etcd server through the v3 JSON gateway
+/// (`/v3/kv/*`) of a single endpoint. `RetryClient` fails over between the
+/// clients of several endpoints.
+#[derive(Debug)]
+pub struct ETCDClient {
+    /// The etcd endpoint, e.g. `127.0.0.1:2379` or `http://127.0.0.1:2379`
+    endpoint: String,
+    /// Timeout for connecting, reading and writing a single request
+    timeout: Duration,
+    /// The client is closed or not
+    closed: AtomicBool,
+    /// The user name and password, None if auth is disabled
+    auth: Option<(String, String)>,
+    /// The cached auth token
+    token: Mutex<Option<String>>,
+}
+
+impl ETCDClient {
+    /// Create a new etcd client
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            endpoint,
+            timeout: DEFAULT_TIMEOUT,
+            closed: AtomicBool::new(false),
+            auth: None,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Authenticate with the user name and password, the token is sent with every request
+    pub fn with_auth(mut self, username: &str, password: &str) -> Self {
+        self.auth = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Set the timeout of a single request
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Get the endpoint
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Call the etcd gateway api
+    fn call(&self, api: &str, request: &Value) -> Result<Value, Error> {
+        self.check_closed()?;
+        let (status, response) = self.post(api, request.to_string().as_bytes())?;
+        if status >= 500 {
+            // Retried on another endpoint by the retry client
+            let message = parse_response(status, &response)
+                .err()
+                .map_or_else(|| format!("etcd endpoint {} returned status {}", self.endpoint, status), |e| e.to_string());
+            return Err(UnavailableError::new(message).into());
+        }
+
+        parse_response(status, &response)
+    }
+
+    /// Post the request with the auth token, authenticate again if the token is rejected
+    fn post(&self, api: &str, body: &[u8]) -> Result<(u16, Vec<u8>), Error> {
+        let token = self.token()?;
+        let (status, response) = post(&self.endpoint, api, body, token.as_deref(), self.timeout)?;
+        if status == 401 && token.is_some() {
+            // The token is expired
+            *self.token.lock().unwrap() = None;
+            let token = self.token()?;
+            return post(&self.endpoint, api, body, token.as_deref(), self.timeout);
+        }
+
+        Ok((status, response))
+    }
+
+    /// Get the auth token, authenticate on the endpoint if it's not cached
+    fn token(&self) -> Result<Option<String>, Error> {
+        let (name, password) = match self.auth {
+            Some((ref name, ref password)) => (name, password),
+            None => return Ok(None),
+        };
+        if let Some(token) = self.token.lock().unwrap().clone() {
+            return Ok(Some(token));
+        }
+
+        let request = json!({ "name": name, "password": password }).to_string();
+        let (status, response) = post(&self.endpoint, "/v3/auth/authenticate", request.as_bytes(), None, self.timeout)?;
+        let token = parse_response(status, &response)?
+            .get("token")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("etcd authenticate response has no token"))?
+            .to_owned();
+
+        *self.token.lock().unwrap() = Some(token.clone());
+        Ok(Some(token))
+    }
+
+    /// Fail if the client is closed
+    fn check_closed(&self) -> Result<(), Error> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(anyhow!("etcd client is closed"));
+        }
+        Ok(())
+    }
+
+    /// Range request on a single key or a prefix
+    fn range(&self, path: &str, prefix: bool, keys_only: bool) -> Result<Vec<Value>, Error> {
+        let mut request = json!({
+            "key": encode(path.as_bytes()),
+            "keys_only": keys_only,
+        });
+        if prefix {
+            request["range_end"] = Value::String(encode(&prefix_end(path.as_bytes())));
+        }
+
+        let response = self.call("/v3/kv/range", &request)?;
+        match response.get("kvs") {
+            Some(Value::Array(kvs)) => Ok(kvs.clone()),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+impl BlockingMetaClient for ETCDClient {
+    fn from_endpoints(endpoints: Vec<String>) -> Result<Self, Error> {
+        match <[String; 1]>::try_from(endpoints) {
+            Ok([endpoint]) => Ok(Self::new(endpoint)),
+            Err(endpoints) => Err(anyhow!("etcd client needs exactly one endpoint, got {:?}", endpoints)),
+        }
+    }
+
+    fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        // Only put the key if it has never been created
+        let key = encode(path.as_bytes());
+        let request = json!({
+            "compare": [{
+                "key": key,
+                "target": "CREATE",
+                "result": "EQUAL",
+                "create_revision": "0",
+            }],
+            "success": [{
+                "request_put": { "key": key, "value": encode(data) },
+            }],
+        });
+
+        let response = self.call("/v3/kv/txn", &request)?;
+        if response.get("succeeded").and_then(Value::as_bool).unwrap_or(false) {
+            Ok(())
+        } else {
+            Err(anyhow!("meta data {} already exists", path))
+        }
+    }
+
+    fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let request = json!({
+            "key": encode(path.as_bytes()),
+            "value": encode(data),
+        });
+        self.call("/v3/kv/put", &request)?;
+
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), Error> {
+        let request = json!({
+            "key": encode(path.as_bytes()),
+        });
+        self.call("/v3/kv/deleterange", &request)?;
+
+        Ok(())
+    }
+
+    fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+        let kvs = self.range(path, false, false)?;
+        match kvs.first() {
+            Some(kv) => field_bytes(kv, "value"),
+            None if must => Err(anyhow!("meta data {} not found", path)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+        let keys = self
+            .range(path, true, true)?
+            .iter()
+            .map(|kv| {
+                let key = field_bytes(kv, "key")?;
+                String::from_utf8(key).context("etcd key is not valid utf-8")
+            })
+            .collect::<Result<Vec<String>, Error>>()?;
+
+        if keys.is_empty() && must {
+            return Err(anyhow!("meta data under {} not found", path));
+        }
+
+        Ok(keys)
+    }
+
+    fn close(&self) -> Result<(), Error> {
+        self.closed.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn watch(&self, path: &str) -> Result<ChannelWatcher, Error> {
+        let request = json!({
+            "create_request": {
+                "key": encode(path.as_bytes()),
+                "range_end": encode(&prefix_end(path.as_bytes())),
+            },
+        })
+        .to_string();
+        self.check_closed()?;
+        let token = self.token()?;
+        let mut stream = WatchStream::open(&self.endpoint, request.as_bytes(), token.as_deref(), self.timeout)?;
+        let socket = stream.reader.get_ref().try_clone()?;
+
+        // Forward the events in background, the stream is closed when the watcher is dropped
+        let (sender, receiver) = mpsc::unbounded_channel();
+        thread::spawn(move || loop {
+            match stream.next_message().and_then(|message| parse_events(&message)) {
+                Ok(events) => {
+                    for event in events {
+                        if sender.send(Ok(event)).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            }
+        });
+
+        Ok(ChannelWatcher::with_drop(receiver, move || {
+            let _ = socket.shutdown(Shutdown::Both);
+        }))
+    }
+
+    fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        let kvs = self.range(path, false, false)?;
+        kvs.first()
+            .map(|kv| Ok((field_bytes(kv, "value")?, field_u64(kv, "mod_revision")?)))
+            .transpose()
+    }
+
+    fn txn(&self, txn: Txn) -> Result<u64, Error> {
+        let compares: Vec<Value> = txn
+            .compares
+            .iter()
+            .map(|compare| match *compare {
+                Compare::ModRevision(ref path, revision) => json!({
+                    "key": encode(path.as_bytes()),
+                    "target": "MOD",
+                    "result": "EQUAL",
+                    "mod_revision": revision.to_string(),
+                }),
+            })
+            .collect();
+        let ops: Vec<Value> = txn
+            .ops
+            .iter()
+            .map(|op| match *op {
+                TxnOp::Put(ref path, ref data, lease) => json!({
+                    "request_put": { "key": encode(path.as_bytes()), "value": encode(data), "lease": lease.to_string() },
+                }),
+                TxnOp::Delete(ref path) => json!({
+                    "request_delete_range": { "key": encode(path.as_bytes()) },
+                }),
+            })
+            .collect();
+
+        let response = self.call("/v3/kv/txn", &json!({ "compare": compares, "success": ops }))?;
+        if !response.get("succeeded").and_then(Value::as_bool).unwrap_or(false) {
+            // The gateway does not tell which compare failed
+            let paths = txn.compares.iter().map(|compare| compare.path().to_owned()).collect();
+            return Err(ConflictError::new(paths).into());
+        }
+
+        field_u64(response.get("header").unwrap_or(&Value::Null), "revision")
+    }
+
+    fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+        // The ttl of etcd lease is in seconds
+        let seconds = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+        let response = self.call("/v3/lease/grant", &json!({ "TTL": seconds.max(1).to_string() }))?;
+        if let Some(error) = response.get("error").and_then(Value::as_str).filter(|error| !error.is_empty()) {
+            return Err(anyhow!("etcd lease grant failed: {}", error));
+        }
+
+        match field_u64(&response, "ID")? {
+            0 => Err(anyhow!("etcd lease grant returned no id")),
+            id => Ok(id),
+        }
+    }
+
+    fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+        let response = self.call("/v3/lease/keepalive", &json!({ "ID": lease.to_string() }))?;
+        // The ttl is 0 if the lease is expired or revoked
+        let result = response.get("result").unwrap_or(&response);
+        if field_u64(result, "TTL")? == 0 {
+            return Err(anyhow!("etcd lease {} is expired", lease));
+        }
+        Ok(())
+    }
+
+    fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+        match self.call("/v3/lease/revoke", &json!({ "ID": lease.to_string() })) {
+            // The lease is already gone
+            Err(e) if e.to_string().contains("lease not found") => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+/// The response stream of the etcd watch api
+///
+/// The gateway sends one json message per line in a chunked body.
+#[derive(Debug)]
+struct WatchStream {
+    /// The response reader
+    reader: BufReader<TcpStream>,
+    /// The body is chunked or not
+    chunked: bool,
+    /// The received bytes not parsed yet
+    buf: Vec<u8>,
+}
+
+impl WatchStream {
+    /// Open a watch stream, wait until the watch is created
+    fn open(endpoint: &str, request: &[u8], token: Option<&str>, timeout: Duration) -> Result<Self, Error> {
+        let mut reader = send(endpoint, "/v3/watch", request, token, timeout)?;
+        let head = read_head(&mut reader)?;
+        if head.status != 200 {
+            let body = read_body(&mut reader, &head)?;
+            return Err(parse_response(head.status, &body).err().unwrap_or_else(|| anyhow!("etcd watch failed")));
+        }
+
+        let mut stream = Self {
+            reader,
+            chunked: head.chunked,
+            buf: Vec::new(),
+        };
+        let created = stream.next_message()?;
+        if created.pointer("/result/created").and_then(Value::as_bool) != Some(true) {
+            return Err(anyhow!("etcd watch is not created: {}", created));
+        }
+
+        // Events may not come for a long time
+        stream.reader.get_ref().set_read_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// Read the next json message
+    fn next_message(&mut self) -> Result<Value, Error> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return serde_json::from_slice(&line).context("invalid etcd watch message");
+            }
+
+            if self.chunked {
+                match read_chunk(&mut self.reader)? {
+                    Some(chunk) => self.buf.extend_from_slice(&chunk),
+                    None => return Err(anyhow!("etcd watch stream is closed")),
+                }
+            } else if self.reader.read_until(b'\n', &mut self.buf)? == 0 {
+                return Err(anyhow!("etcd watch stream is closed"));
+            }
+        }
+    }
+}
+
+/// Parse the events of a watch message
+fn parse_events(message: &Value) -> Result<Vec<WatchEvent>, Error> {
+    if let Some(error) = message.get("error") {
+        return Err(anyhow!("etcd watch error: {}", error));
+    }
+    let result = message.get("result").unwrap_or(message);
+    if result.get("canceled").and_then(Value::as_bool) == Some(true) {
+        return Err(anyhow!("etcd watch is canceled: {}", result.get("cancel_reason").unwrap_or(&Value::Null)));
+    }
+
+    let events = match result.get("events") {
+        Some(Value::Array(events)) => events,
+        _ => return Ok(Vec::new()),
+    };
+    events
+        .iter()
+        .map(|event| {
+            let kv = event.get("kv").unwrap_or(&Value::Null);
+            let key = String::from_utf8(field_bytes(kv, "key")?).context("etcd key is not valid utf-8")?;
+            let revision = field_u64(kv, "mod_revision")?;
+            // The event type PUT is the default value, which is omitted
+            match event.get("type").and_then(Value::as_str) {
+                Some("DELETE") => Ok(WatchEvent::delete(key, revision)),
+                _ => Ok(WatchEvent::put(key, field_bytes(kv, "value")?, revision)),
+            }
+        })
+        .collect()
+}
+
+/// Encode bytes to base64, which is required by the etcd json gateway
+fn encode(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+/// Decode a base64 field of a json object, missing field means empty bytes
+fn field_bytes(value: &Value, field: &str) -> Result<Vec<u8>, Error> {
+    match value.get(field).and_then(Value::as_str) {
+        Some(data) => STANDARD.decode(data).with_context(|| format!("invalid base64 field {}", field)),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Decode an int64 field of a json object, which is encoded as string by the gateway
+fn field_u64(value: &Value, field: &str) -> Result<u64, Error> {
+    match value.get(field) {
+        Some(&Value::String(ref number)) => number.parse().with_context(|| format!("invalid integer field {}", field)),
+        Some(&Value::Number(ref number)) => number.as_u64().ok_or_else(|| anyhow!("invalid integer field {}", field)),
+        _ => Ok(0),
+    }
+}
+
+/// Get the range end of a prefix, which is the prefix with the last byte increased
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+
+    // All bytes are 0xff or the prefix is empty, range to the end of keyspace
+    vec![0]
+}
+
+/// Parse the gateway response, non 2xx status is converted to error
+fn parse_response(status: u16, body: &[u8]) -> Result<Value, Error> {
+    let value: Value = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(body).context("invalid etcd response")?
+    };
+
+    if (200..300).contains(&status) {
+        return Ok(value);
+    }
+
+    let message = value
+        .get("message")
+        .or_else(|| value.get("error"))
+        .and_then(Value::as_str)
+        .unwrap_or("unknown error");
+    Err(anyhow!("etcd request failed, status: {}, message: {}", status, message))
+}
+
+/// Send a http post request to the endpoint, return the status and body
+fn post(endpoint: &str, api: &str, body: &[u8], token: Option<&str>, timeout: Duration) -> Result<(u16, Vec<u8>), Error> {
+    let mut reader = send(endpoint, api, body, token, timeout)?;
+    let head = read_head(&mut reader)?;
+    let body = read_body(&mut reader, &head)?;
+
+    Ok((head.status, body))
+}
+
+/// Connect to the endpoint and send a http post request, with the auth token if any
+fn send(endpoint: &str, api: &str, body: &[u8], token: Option<&str>, timeout: Duration) -> Result<BufReader<TcpStream>, Error> {
+    if endpoint.starts_with("https://") {
+        return Err(anyhow!("https etcd endpoint {} is not supported", endpoint));
+    }
+    let host = endpoint.trim_start_matches("http://").trim_end_matches('/');
+    let addr = host
+        .to_socket_addrs()
+        .with_context(|| format!("invalid etcd endpoint {}", endpoint))?
+        .next()
+        .ok_or_else(|| anyhow!("etcd endpoint {} is not resolved", endpoint))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)
+        .map_err(|e| UnavailableError::unsent(format!("failed to connect to etcd endpoint {}: {}", endpoint, e)))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let authorization = token.map(|token| format!("Authorization: {}\r\n", token)).unwrap_or_default();
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        api,
+        host,
+        body.len(),
+        authorization
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    Ok(BufReader::new(stream))
+}
+
+/// The status and body framing of a http response
+#[derive(Debug)]
+struct ResponseHead {
+    /// The status code
+    status: u16,
+    /// The content length, if not chunked
+    content_length: Option<usize>,
+    /// The body is chunked or not
+    chunked: bool,
+}
+
+/// Read the status line and headers of a http response
+fn read_head<R: BufRead>(reader: &mut R) -> Result<ResponseHead, Error> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("invalid http status line: {:?}", line))?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("unexpected eof in http headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.parse::<usize>().context("invalid content length")?);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+
+    Ok(ResponseHead {
+        status,
+        content_length,
+        chunked,
+    })
+}
+
+/// Read the whole body of a http response, support both content length and chunked body
+fn read_body<R: BufRead>(reader: &mut R, head: &ResponseHead) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    if head.chunked {
+        while let Some(chunk) = read_chunk(reader)? {
+            body.extend_from_slice(&chunk);
+        }
+    } else if let Some(length) = head.content_length {
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+
+    Ok(body)
+}
+
+/// Read one chunk of a chunked http body, return None on the last chunk
+fn read_chunk<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let size = line.trim_end().split(';').next().unwrap_or_default();
+    let size = usize::from_str_radix(size, 16).with_context(|| format!("invalid chunk size: {:?}", line))?;
+
+    let mut chunk = vec![0; size];
+    reader.read_exact(&mut chunk)?;
+    // Skip the CRLF after the chunk data (or the trailer of the last chunk)
+    line.clear();
+    reader.read_line(&mut line)?;
+
+    if size == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use super::*;
+    use crate::client::{is_conflict, is_unsent, EventType, Watcher};
+
+    /// A key value in the mock etcd
+    #[derive(Clone)]
+    struct MockKv {
+        value: Vec<u8>,
+        create_revision: u64,
+        mod_revision: u64,
+        lease: u64,
+    }
+
+    /// The state of the mock etcd
+    #[derive(Default)]
+    struct MockState {
+        kvs: BTreeMap<Vec<u8>, MockKv>,
+        revision: u64,
+        /// Watchers with the watched key range
+        watchers: Vec<(Vec<u8>, Vec<u8>, Sender<Value>)>,
+        /// Leases with the ttl in seconds and the deadline
+        leases: BTreeMap<u64, (u64, Instant)>,
+        last_lease: u64,
+    }
+
+    impl MockState {
+        /// The keys in the range of a request, `range_end` is optional
+        fn range_keys(&self, request: &Value) -> Vec<Vec<u8>> {
+            let key = field_bytes(request, "key").unwrap();
+            match request.get("range_end") {
+                Some(_) => {
+                    let end = field_bytes(request, "range_end").unwrap();
+                    self.kvs.range(key..end).map(|(k, _)| k.clone()).collect()
+                }
+                None => self.kvs.get(&key).map(|_| key).into_iter().collect(),
+            }
+        }
+
+        fn kv_json(&self, key: &[u8]) -> Value {
+            let kv = &self.kvs[key];
+            json!({
+                "key": encode(key),
+                "value": encode(&kv.value),
+                "create_revision": kv.create_revision.to_string(),
+                "mod_revision": kv.mod_revision.to_string(),
+            })
+        }
+
+        fn notify(&mut self, event: Value, key: &[u8]) {
+            let message = json!({ "result": { "events": [event] } });
+            self.watchers
+                .retain(|(start, end, sender)| !(start.as_slice() <= key && key < end.as_slice()) || sender.send(message.clone()).is_ok());
+        }
+
+        fn put(&mut self, request: &Value, revision: u64) {
+            let key = field_bytes(request, "key").unwrap();
+            let create_revision = self.kvs.get(&key).map_or(revision, |kv| kv.create_revision);
+            self.kvs.insert(key.clone(), MockKv {
+                value: field_bytes(request, "value").unwrap(),
+                create_revision,
+                mod_revision: revision,
+                lease: field_u64(request, "lease").unwrap(),
+            });
+            let event = json!({ "kv": self.kv_json(&key) });
+            self.notify(event, &key);
+        }
+
+        fn delete(&mut self, request: &Value, revision: u64) -> usize {
+            let keys = self.range_keys(request);
+            for key in &keys {
+                self.kvs.remove(key);
+                let event = json!({ "type": "DELETE", "kv": { "key": encode(key), "mod_revision": revision.to_string() } });
+                self.notify(event, key);
+            }
+            keys.len()
+        }
+
+        /// Delete the keys of the lease at a new revision
+        fn revoke(&mut self, lease: u64) {
+            self.leases.remove(&lease);
+            let keys: Vec<Vec<u8>> = self.kvs.iter().filter(|(_, kv)| kv.lease == lease).map(|(key, _)| key.clone()).collect();
+            if !keys.is_empty() {
+                self.revision += 1;
+                for key in keys {
+                    let revision = self.revision;
+                    self.delete(&json!({ "key": encode(&key) }), revision);
+                }
+            }
+        }
+
+        fn expire(&mut self) {
+            let now = Instant::now();
+            let expired: Vec<u64> = self.leases.iter().filter(|(_, (_, deadline))| *deadline <= now).map(|(id, _)| *id).collect();
+            for lease in expired {
+                self.revoke(lease);
+            }
+        }
+
+        fn compare(&self, compare: &Value) -> bool {
+            let key = field_bytes(compare, "key").unwrap();
+            let kv = self.kvs.get(&key);
+            let target = compare.get("target").and_then(Value::as_str).unwrap_or("VERSION");
+            let (actual, expected) = match target {
+                "CREATE" => (kv.map_or(0, |kv| kv.create_revision), field_u64(compare, "create_revision").unwrap()),
+                "MOD" => (kv.map_or(0, |kv| kv.mod_revision), field_u64(compare, "mod_revision").unwrap()),
+                _ => unimplemented!("compare target {}", target),
+            };
+            actual == expected
+        }
+    }
+
+    /// A tiny in-process stand-in of the etcd v3 json gateway
+    ///
+    /// It only supports the subset of kv and watch api used by `ETCDClient`.
+    /// Only the last issued token is valid if auth is enabled.
+    #[derive(Default)]
+    struct MockEtcd {
+        state: Mutex<MockState>,
+        /// The user name and password
+        auth: Option<(String, String)>,
+        /// The issued token count
+        tokens: AtomicUsize,
+    }
+
+    impl MockEtcd {
+        /// Start the mock server, return its endpoint
+        fn start() -> String {
+            Self::serve_on(MockEtcd::default())
+        }
+
+        /// Start the mock server with auth enabled, return its endpoint
+        fn start_with_auth(name: &str, password: &str) -> String {
+            Self::serve_on(MockEtcd {
+                auth: Some((name.to_owned(), password.to_owned())),
+                ..MockEtcd::default()
+            })
+        }
+
+        fn serve_on(mock: MockEtcd) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let mock = Arc::new(mock);
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    let mock = Arc::clone(&mock);
+                    thread::spawn(move || mock.serve(stream));
+                }
+            });
+
+            endpoint
+        }
+
+        fn serve(&self, mut stream: TcpStream) {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let api = line.split_whitespace().nth(1).unwrap().to_owned();
+
+            let mut length = 0;
+            let mut token = None;
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("authorization") {
+                        token = Some(value.trim().to_owned());
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+
+            if let Some((status, response)) = self.authorize(&api, &request, token.as_deref()) {
+                let response = response.to_string();
+                write!(stream, "HTTP/1.1 {} MOCK\r\nContent-Length: {}\r\n\r\n{}", status, response.len(), response).unwrap();
+                return;
+            }
+
+            if api == "/v3/watch" {
+                return self.serve_watch(stream, &request["create_request"]);
+            }
+
+            let (status, response) = self.handle(&api, &request);
+            let response = response.to_string();
+            write!(
+                stream,
+                "HTTP/1.1 {} MOCK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            )
+            .unwrap();
+        }
+
+        /// Handle the authenticate api, or reject the request without a valid token
+        fn authorize(&self, api: &str, request: &Value, token: Option<&str>) -> Option<(u16, Value)> {
+            let (name, password) = self.auth.as_ref()?;
+            if api == "/v3/auth/authenticate" {
+                if request["name"] != name.as_str() || request["password"] != password.as_str() {
+                    return Some((400, json!({ "error": "etcdserver: authentication failed, invalid user ID or password", "code": 3 })));
+                }
+                let id = self.tokens.fetch_add(1, Ordering::SeqCst) + 1;
+                return Some((200, json!({ "token": format!("token-{}", id) })));
+            }
+
+            let valid = format!("token-{}", self.tokens.load(Ordering::SeqCst));
+            if token != Some(valid.as_str()) {
+                return Some((401, json!({ "error": "etcdserver: invalid auth token", "code": 16 })));
+            }
+            None
+        }
+
+        /// Stream the watch messages as chunks, one message per line
+        fn serve_watch(&self, mut stream: TcpStream, request: &Value) {
+            let (sender, receiver) = mpsc::channel();
+            let start = field_bytes(request, "key").unwrap();
+            let end = field_bytes(request, "range_end").unwrap();
+            let created = json!({ "result": { "created": true } });
+            {
+                let mut state = self.state.lock().unwrap();
+                state.watchers.push((start, end, sender));
+                write!(stream, "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+                write_chunk(&mut stream, &created).unwrap();
+            }
+
+            for message in receiver {
+                if write_chunk(&mut stream, &message).is_err() {
+                    return;
+                }
+            }
+        }
+
+        fn handle(&self, api: &str, request: &Value) -> (u16, Value) {
+            let mut state = self.state.lock().unwrap();
+            state.expire();
+            let revision = state.revision + 1;
+            let response = match api {
+                "/v3/kv/put" => {
+                    state.put(request, revision);
+                    state.revision = revision;
+                    json!({ "header": { "revision": revision.to_string() } })
+                }
+                "/v3/kv/range" => {
+                    let kvs: Vec<Value> = state.range_keys(request).iter().map(|key| state.kv_json(key)).collect();
+                    json!({ "kvs": kvs, "count": kvs.len().to_string() })
+                }
+                "/v3/kv/deleterange" => {
+                    let deleted = state.delete(request, revision);
+                    state.revision = revision;
+                    json!({ "deleted": deleted.to_string() })
+                }
+                "/v3/kv/txn" => {
+                    let succeeded = request["compare"].as_array().unwrap().iter().all(|compare| state.compare(compare));
+                    let ops = request.get(if succeeded { "success" } else { "failure" });
+                    for op in ops.and_then(Value::as_array).into_iter().flatten() {
+                        if let Some(put) = op.get("request_put") {
+                            state.put(put, revision);
+                        } else if let Some(delete) = op.get("request_delete_range") {
+                            state.delete(delete, revision);
+                        }
+                    }
+                    state.revision = revision;
+                    json!({ "succeeded": succeeded, "header": { "revision": revision.to_string() } })
+                }
+                "/v3/lease/grant" => {
+                    let ttl = field_u64(request, "TTL").unwrap();
+                    state.last_lease += 1;
+                    let id = state.last_lease;
+                    state.leases.insert(id, (ttl, Instant::now() + Duration::from_secs(ttl)));
+                    json!({ "ID": id.to_string(), "TTL": ttl.to_string() })
+                }
+                "/v3/lease/keepalive" => {
+                    let id = field_u64(request, "ID").unwrap();
+                    match state.leases.get_mut(&id) {
+                        Some(&mut (ttl, ref mut deadline)) => {
+                            *deadline = Instant::now() + Duration::from_secs(ttl);
+                            json!({ "result": { "ID": id.to_string(), "TTL": ttl.to_string() } })
+                        }
+                        None => json!({ "result": { "ID": id.to_string() } }),
+                    }
+                }
+                "/v3/lease/revoke" => {
+                    let id = field_u64(request, "ID").unwrap();
+                    if !state.leases.contains_key(&id) {
+                        return (404, json!({ "error": "etcdserver: requested lease not found", "code": 5 }));
+                    }
+                    state.revoke(id);
+                    json!({})
+                }
+                _ => json!({}),
+            };
+            (200, response)
+        }
+    }
+
+    /// Write a json message as a chunk
+    fn write_chunk(stream: &mut TcpStream, message: &Value) -> std::io::Result<()> {
+        let line = format!("{}\n", message);
+        write!(stream, "{:x}\r\n{}\r\n", line.len(), line)
+    }
+
+    /// An endpoint which refuses connections
+    fn dead_endpoint() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        endpoint
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"/a"), b"/b".to_vec());
+        assert_eq!(prefix_end(&[b'a', 0xff]), b"b".to_vec());
+        assert_eq!(prefix_end(&[0xff]), vec![0]);
+    }
+
+    #[test]
+    fn test_etcd_client() {
+        let client = ETCDClient::new(MockEtcd::start());
+
+        client.create("/nodes/1", b"node1").unwrap();
+        assert!(client.create("/nodes/1", b"node1").is_err());
+        client.create("/nodes/2", b"node2").unwrap();
+        client.update("/slots", b"slots").unwrap();
+
+        assert_eq!(client.read("/nodes/1", true).unwrap(), b"node1".to_vec());
+        assert!(client.read("/nodes/3", true).is_err());
+        assert!(client.read("/nodes/3", false).unwrap().is_empty());
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/1", "/nodes/2"]);
+
+        client.delete("/nodes/1").unwrap();
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/2"]);
+        assert!(client.list("/missing/", true).is_err());
+        assert!(client.list("/missing/", false).unwrap().is_empty());
+
+        client.close().unwrap();
+        assert!(client.read("/slots", false).is_err());
+    }
+
+    #[test]
+    fn test_etcd_client_unavailable() {
+        assert!(ETCDClient::from_endpoints(vec![dead_endpoint(), MockEtcd::start()]).is_err());
+
+        // Not sent, so the retry client can retry it on another endpoint
+        let client = ETCDClient::from_endpoints(vec![dead_endpoint()]).unwrap();
+        assert!(is_unsent(&client.update("/slots", b"slots").unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn test_etcd_client_auth() {
+        let endpoint = MockEtcd::start_with_auth("proxy", "secret");
+        assert!(ETCDClient::new(endpoint.clone()).read("/slots", false).is_err());
+        assert!(ETCDClient::new(endpoint.clone()).with_auth("proxy", "wrong").read("/slots", false).is_err());
+
+        let client = ETCDClient::new(endpoint.clone()).with_auth("proxy", "secret");
+        client.update("/slots", b"slots").unwrap();
+        let mut watcher = client.watch("/").unwrap();
+
+        // The token of the first client is replaced, it authenticates again
+        let other = ETCDClient::new(endpoint).with_auth("proxy", "secret");
+        other.update("/nodes/1", b"node1").unwrap();
+        assert_eq!(client.read("/nodes/1", true).unwrap(), b"node1".to_vec());
+        let event = watcher.next(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(event.key, "/nodes/1");
+    }
+
+    #[tokio::test]
+    async fn test_etcd_client_watch() {
+        let client = ETCDClient::new(MockEtcd::start());
+        client.update("/nodes/1", b"node1").unwrap();
+
+        let mut watcher = client.watch("/nodes/").unwrap();
+        client.update("/slots", b"slots").unwrap();
+        client.update("/nodes/2", b"node2").unwrap();
+        client.delete("/nodes/1").unwrap();
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(watcher.next(timeout).await.unwrap(), Some(WatchEvent::put("/nodes/2".to_owned(), b"node2".to_vec(), 3)));
+        let event = watcher.next(timeout).await.unwrap().unwrap();
+        assert_eq!((event.event_type, event.key.as_str(), event.revision), (EventType::Delete, "/nodes/1", 4));
+        assert_eq!(watcher.next(Duration::from_millis(10)).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let message = json!({ "result": { "events": [
+            { "kv": { "key": encode(b"/a"), "value": encode(b"1"), "mod_revision": "5" } },
+            { "type": "DELETE", "kv": { "key": encode(b"/b"), "mod_revision": "6" } },
+        ] } });
+        assert_eq!(parse_events(&message).unwrap(), vec![
+            WatchEvent::put("/a".to_owned(), b"1".to_vec(), 5),
+            WatchEvent::delete("/b".to_owned(), 6),
+        ]);
+        assert!(parse_events(&json!({ "result": { "canceled": true } })).is_err());
+    }
+
+    #[test]
+    fn test_etcd_client_txn() {
+        let client = ETCDClient::new(MockEtcd::start());
+        let revision = client.compare_and_swap("/slots", 0, b"v1").unwrap();
+        assert_eq!(client.read_revision("/slots").unwrap(), Some((b"v1".to_vec(), revision)));
+        assert!(client.read_revision("/missing").unwrap().is_none());
+        client.update("/nodes/1", b"node1").unwrap();
+
+        let txn = Txn::new()
+            .when(Compare::ModRevision("/slots".to_owned(), revision))
+            .when(Compare::ModRevision("/ring_version".to_owned(), 0))
+            .put("/slots", b"v2")
+            .put("/ring_version", b"1")
+            .delete("/nodes/1");
+        let committed = client.txn(txn.clone()).unwrap();
+        assert_eq!(client.read_revision("/ring_version").unwrap(), Some((b"1".to_vec(), committed)));
+        assert!(client.list("/nodes/", false).unwrap().is_empty());
+
+        let error = client.txn(txn).unwrap_err();
+        assert!(is_conflict(&error));
+        assert_eq!(client.read_revision("/slots").unwrap(), Some((b"v2".to_vec(), committed)));
+    }
+
+    #[test]
+    fn test_etcd_client_lease() {
+        let client = ETCDClient::new(MockEtcd::start());
+        let lease = client.grant_lease(Duration::from_millis(1500)).unwrap();
+        client.txn(Txn::new().put_with_lease("/nodes/1", b"node1", lease)).unwrap();
+        client.keep_alive(lease).unwrap();
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/1"]);
+
+        client.revoke_lease(lease).unwrap();
+        assert!(client.list("/nodes/", false).unwrap().is_empty());
+        assert!(client.keep_alive(lease).is_err());
+        client.revoke_lease(lease).unwrap();
+    }
+}
@@ -0,0 +1,713 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Error};
+use tracing::{info, warn};
+
+use super::store::{Entry, KvStore};
+use super::blocking::BlockingMetaClient;
+use super::watch::ChannelWatcher;
+use super::{Txn, TxnOp};
+
+/// The sub directory to store the meta data files
+const DATA_DIR: &str = "data";
+/// The journal file name
+const JOURNAL_FILE: &str = "journal";
+/// The file to store the store revision
+const REVISION_FILE: &str = "revision";
+/// Length of the revision header of a data file
+const HEADER_LEN: usize = 16;
+/// The suffix of temporary files, which are ignored when loading
+const TMP_SUFFIX: &str = ".tmp";
+/// Max length of a file name on most file systems
+const MAX_FILE_NAME: usize = 255;
+/// Journal op to put a key
+const OP_PUT: u8 = 1;
+/// Journal op to delete a key
+const OP_DELETE: u8 = 2;
+/// Journal op to put a key attached to a lease
+const OP_PUT_LEASE: u8 = 3;
+
+/// File backed meta data client
+///
+/// This struct stores the meta data tree under a local directory, for the
+/// deployments without etcd. Each path is stored as one file under `data/`,
+/// with its create and mod revision ahead of the value, written with temporary
+/// file, fsync and atomic rename. A mutation is first appended to `journal` and
+/// synced, so an interrupted write is replayed on the next open. The directory
+/// should be owned by a single client in one process.
+///
+/// Leases never outlive the process, so the keys attached to a lease are kept
+/// in memory only, and their files are removed.
+#[derive(Debug)]
+pub struct FileMetaClient {
+    /// The root directory
+    root: PathBuf,
+    /// The in-memory index of the meta data, protected by the lock of mutation
+    store: Mutex<KvStore>,
+    /// The client is closed or not
+    closed: AtomicBool,
+    /// The batch failed half way applying with its revision, it's applied again before the next access
+    unapplied: Mutex<Option<(u64, Vec<TxnOp>)>>,
+}
+
+impl FileMetaClient {
+    /// Open the meta data directory, create it if not exists
+    pub fn open(root: impl AsRef<Path>) -> Result<Self, Error> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join(DATA_DIR))
+            .with_context(|| format!("failed to create meta data directory {:?}", root))?;
+
+        let (kvs, revision) = load_data(&root)?;
+        let client = Self {
+            store: Mutex::new(KvStore::with_data(kvs, revision)),
+            root,
+            closed: AtomicBool::new(false),
+            unapplied: Mutex::new(None),
+        };
+        client.recover()?;
+
+        Ok(client)
+    }
+
+    /// Get the root directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Lock the meta data and delete the keys of expired leases, fail if the client is closed
+    fn store(&self) -> Result<MutexGuard<'_, KvStore>, Error> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(anyhow!("file meta client is closed"));
+        }
+
+        let mut store = self.store.lock().unwrap();
+        self.apply_unapplied(&mut store)?;
+        let expired = store.expire(Instant::now());
+        if !expired.is_empty() {
+            self.commit(&mut store, &expired)?;
+        }
+        Ok(store)
+    }
+
+    /// Replay the journal left by an interrupted mutation
+    fn recover(&self) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        match File::open(self.root.join(JOURNAL_FILE)) {
+            Ok(mut journal) => {
+                journal.read_to_end(&mut buf)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        match decode_record(&buf) {
+            Some((revision, ops)) => {
+                // The leases are gone with the previous process
+                let ops: Vec<TxnOp> = ops
+                    .into_iter()
+                    .map(|op| match op {
+                        TxnOp::Put(key, _, lease) if lease != 0 => TxnOp::Delete(key),
+                        op => op,
+                    })
+                    .collect();
+                let mut store = self.store.lock().unwrap();
+                // The revision is already applied if the revision file is updated
+                if revision > store.revision() {
+                    info!("Replay {} meta data ops from journal", ops.len());
+                    self.apply(&mut store, &ops, revision)?;
+                }
+            }
+            // The record is torn, so the mutation was never applied
+            None => warn!("Drop incomplete meta data journal record"),
+        }
+
+        self.clear_journal()
+    }
+
+    /// Commit the ops atomically at the next revision: journal, apply to files, then clear the journal
+    ///
+    /// The keys are validated before the journal is written, so a journal
+    /// record can always be replayed. If applying fails half way, the batch
+    /// is kept in the journal and applied again before the next access, or
+    /// replayed on the next open, so it's never left half applied.
+    fn commit(&self, store: &mut KvStore, ops: &[TxnOp]) -> Result<u64, Error> {
+        for op in ops {
+            let (TxnOp::Put(ref key, _, _) | TxnOp::Delete(ref key)) = *op;
+            file_name(key)?;
+        }
+
+        let revision = store.revision() + 1;
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.root.join(JOURNAL_FILE))?;
+        journal.write_all(&encode_record(revision, ops))?;
+        journal.sync_all()?;
+
+        if let Err(e) = self.apply(store, ops, revision) {
+            *self.unapplied.lock().unwrap() = Some((revision, ops.to_vec()));
+            return Err(e);
+        }
+        self.clear_journal()?;
+        Ok(revision)
+    }
+
+    /// Apply the batch failed half way again, fail until it's applied
+    fn apply_unapplied(&self, store: &mut KvStore) -> Result<(), Error> {
+        let mut unapplied = self.unapplied.lock().unwrap();
+        if let Some((revision, ref ops)) = *unapplied {
+            self.apply(store, ops, revision)
+                .context("failed to apply the meta data journal record left by a failed commit")?;
+            info!("Applied the meta data journal record left by a failed commit at revision {}", revision);
+            *unapplied = None;
+            self.clear_journal()?;
+        }
+        Ok(())
+    }
+
+    /// Apply the ops to the data files and the store, it's idempotent
+    fn apply(&self, store: &mut KvStore, ops: &[TxnOp], revision: u64) -> Result<(), Error> {
+        let dir = self.root.join(DATA_DIR);
+        // The create revision of keys put more than once in the batch
+        let mut created = BTreeMap::new();
+        for op in ops {
+            match *op {
+                TxnOp::Put(ref key, _, lease) if lease != 0 => {
+                    remove_file(&dir, key)?;
+                }
+                TxnOp::Put(ref key, ref value, _) => {
+                    let create_revision = store
+                        .get(key)
+                        .map(|entry| entry.create_revision)
+                        .or_else(|| created.get(key).copied())
+                        .unwrap_or(revision);
+                    created.insert(key.clone(), create_revision);
+
+                    let mut content = Vec::with_capacity(HEADER_LEN + value.len());
+                    content.extend_from_slice(&create_revision.to_le_bytes());
+                    content.extend_from_slice(&revision.to_le_bytes());
+                    content.extend_from_slice(value);
+                    write_atomic(&dir, &file_name(key)?, &content)?;
+                }
+                TxnOp::Delete(ref key) => {
+                    created.remove(key);
+                    remove_file(&dir, key)?;
+                }
+            }
+        }
+        // Persist the renames and removals
+        File::open(&dir)?.sync_all()?;
+
+        write_atomic(&self.root, REVISION_FILE, &revision.to_le_bytes())?;
+        File::open(&self.root)?.sync_all()?;
+
+        store.apply(ops, revision);
+        Ok(())
+    }
+
+    /// Truncate the journal after the mutation is applied
+    fn clear_journal(&self) -> Result<(), Error> {
+        let journal = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.root.join(JOURNAL_FILE))?;
+        journal.sync_all()?;
+        Ok(())
+    }
+}
+
+impl BlockingMetaClient for FileMetaClient {
+    fn from_endpoints(endpoints: Vec<String>) -> Result<Self, Error> {
+        let root = endpoints
+            .first()
+            .ok_or_else(|| anyhow!("meta data directory is not configured"))?;
+        Self::open(root)
+    }
+
+    fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let mut store = self.store()?;
+        if store.get(path).is_some() {
+            return Err(anyhow!("meta data {} already exists", path));
+        }
+
+        self.commit(&mut store, &[TxnOp::Put(path.to_owned(), data.to_vec(), 0)])?;
+        Ok(())
+    }
+
+    fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let mut store = self.store()?;
+        self.commit(&mut store, &[TxnOp::Put(path.to_owned(), data.to_vec(), 0)])?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), Error> {
+        let mut store = self.store()?;
+        if store.get(path).is_none() {
+            return Ok(());
+        }
+
+        self.commit(&mut store, &[TxnOp::Delete(path.to_owned())])?;
+        Ok(())
+    }
+
+    fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+        match self.store()?.get(path) {
+            Some(entry) => Ok(entry.value.clone()),
+            None if must => Err(anyhow!("meta data {} not found", path)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+        let keys = self.store()?.keys(path);
+
+        if keys.is_empty() && must {
+            return Err(anyhow!("meta data under {} not found", path));
+        }
+
+        Ok(keys)
+    }
+
+    fn close(&self) -> Result<(), Error> {
+        self.closed.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn watch(&self, path: &str) -> Result<ChannelWatcher, Error> {
+        Ok(self.store()?.watch(path))
+    }
+
+    fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        Ok(self.store()?.get(path).map(|entry| (entry.value.clone(), entry.mod_revision)))
+    }
+
+    fn txn(&self, txn: Txn) -> Result<u64, Error> {
+        let mut store = self.store()?;
+        store.check(&txn.compares)?;
+        store.check_leases(&txn.ops)?;
+        if txn.ops.is_empty() {
+            return Ok(store.revision());
+        }
+
+        self.commit(&mut store, &txn.ops)
+    }
+
+    fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+        Ok(self.store()?.grant(ttl))
+    }
+
+    fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+        self.store()?.keep_alive(lease)
+    }
+
+    fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+        let mut store = self.store()?;
+        let ops = store.revoke(lease);
+        if !ops.is_empty() {
+            self.commit(&mut store, &ops)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove the data file of the key if it exists
+fn remove_file(dir: &Path, key: &str) -> Result<(), Error> {
+    match fs::remove_file(dir.join(file_name(key)?)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Write the file with temporary file, fsync and rename
+fn write_atomic(dir: &Path, name: &str, content: &[u8]) -> Result<(), Error> {
+    let tmp = dir.join(format!("{}{}", name, TMP_SUFFIX));
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(name))?;
+    Ok(())
+}
+
+/// Load all the data files and the store revision, temporary files of interrupted writes are removed
+///
+/// The revision file is written last when applying a journal record, so it's
+/// the revision of the last completely applied record.
+fn load_data(root: &Path) -> Result<(BTreeMap<String, Entry>, u64), Error> {
+    let mut kvs = BTreeMap::new();
+    for entry in fs::read_dir(root.join(DATA_DIR))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(TMP_SUFFIX) {
+            fs::remove_file(entry.path())?;
+            continue;
+        }
+
+        let key = key_name(&name).with_context(|| format!("invalid meta data file {:?}", entry.path()))?;
+        let content = fs::read(entry.path())?;
+        let create_revision = read_u64(&content, 0).with_context(|| format!("truncated meta data file {:?}", entry.path()))?;
+        let mod_revision = read_u64(&content, 8).with_context(|| format!("truncated meta data file {:?}", entry.path()))?;
+        kvs.insert(key, Entry {
+            value: content[HEADER_LEN..].to_vec(),
+            create_revision,
+            mod_revision,
+            lease: 0,
+        });
+    }
+
+    let revision = match fs::read(root.join(REVISION_FILE)) {
+        Ok(content) => read_u64(&content, 0).context("truncated meta data revision file")?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    Ok((kvs, revision))
+}
+
+/// Escape the key to a file name, every byte except `[A-Za-z0-9._-]` is `%XX` encoded
+fn file_name(key: &str) -> Result<String, Error> {
+    let mut name = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'_' | b'-') {
+            name.push(char::from(byte));
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    if name.len() + TMP_SUFFIX.len() > MAX_FILE_NAME {
+        return Err(anyhow!("meta data path {} is too long", key));
+    }
+    Ok(name)
+}
+
+/// Unescape the file name to the key
+fn key_name(name: &str) -> Result<String, Error> {
+    let bytes = name.as_bytes();
+    let mut key = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = name.get(index + 1..index + 3).ok_or_else(|| anyhow!("truncated escape"))?;
+            key.push(u8::from_str_radix(hex, 16)?);
+            index += 3;
+        } else {
+            key.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    Ok(String::from_utf8(key)?)
+}
+
+/// Encode the ops as a journal record: `[len: u32][crc32: u32][payload]`
+///
+/// The payload is `[revision: u64]` followed by a sequence of
+/// `[op: u8][key len: u32][key][value len: u32][value][lease: u64]`, the value
+/// is omitted for delete op and the lease is only for leased put op. All
+/// integers are little endian.
+fn encode_record(revision: u64, ops: &[TxnOp]) -> Vec<u8> {
+    let mut payload = revision.to_le_bytes().to_vec();
+    for op in ops {
+        match *op {
+            TxnOp::Put(ref key, ref value, lease) => {
+                payload.push(if lease == 0 { OP_PUT } else { OP_PUT_LEASE });
+                put_bytes(&mut payload, key.as_bytes());
+                put_bytes(&mut payload, value);
+                if lease != 0 {
+                    payload.extend_from_slice(&lease.to_le_bytes());
+                }
+            }
+            TxnOp::Delete(ref key) => {
+                payload.push(OP_DELETE);
+                put_bytes(&mut payload, key.as_bytes());
+            }
+        }
+    }
+
+    let mut record = Vec::with_capacity(payload.len() + 8);
+    put_bytes(&mut record, &payload);
+    record.splice(4..4, crc32fast::hash(&payload).to_le_bytes());
+    record
+}
+
+/// Decode a journal record, return None if it's incomplete or corrupted
+fn decode_record(buf: &[u8]) -> Option<(u64, Vec<TxnOp>)> {
+    let len = usize::try_from(read_u32(buf, 0)?).ok()?;
+    let crc = read_u32(buf, 4)?;
+    let payload = buf.get(8..8 + len)?;
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+
+    let revision = read_u64(payload, 0)?;
+    let mut ops = Vec::new();
+    let mut offset = 8;
+    while offset < payload.len() {
+        let op = payload[offset];
+        let key = read_bytes(payload, &mut offset, 1)?;
+        let key = String::from_utf8(key.to_vec()).ok()?;
+        match op {
+            OP_PUT => {
+                let value = read_bytes(payload, &mut offset, 0)?;
+                ops.push(TxnOp::Put(key, value.to_vec(), 0));
+            }
+            OP_PUT_LEASE => {
+                let value = read_bytes(payload, &mut offset, 0)?;
+                let lease = read_u64(payload, offset)?;
+                offset += 8;
+                ops.push(TxnOp::Put(key, value.to_vec(), lease));
+            }
+            OP_DELETE => ops.push(TxnOp::Delete(key)),
+            _ => return None,
+        }
+    }
+
+    Some((revision, ops))
+}
+
+/// Append a length prefixed byte slice
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    let len = u32::try_from(data.len()).unwrap_or(u32::MAX);
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Read a little endian u32 at offset
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Read a little endian u64 at offset
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    let bytes = buf.get(offset..offset + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Read a length prefixed byte slice after skipping `skip` bytes, advance the offset
+fn read_bytes<'a>(buf: &'a [u8], offset: &mut usize, skip: usize) -> Option<&'a [u8]> {
+    let start = *offset + skip;
+    let len = usize::try_from(read_u32(buf, start)?).ok()?;
+    let data = buf.get(start + 4..start + 4 + len)?;
+    *offset = start + 4 + len;
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::client::{is_conflict, Compare, WatchEvent, Watcher};
+
+    /// Create an empty directory for a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache_proxy_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_file_name() {
+        let name = file_name("/cluster/nodes/1").unwrap();
+        assert_eq!(name, "%2Fcluster%2Fnodes%2F1");
+        assert_eq!(key_name(&name).unwrap(), "/cluster/nodes/1");
+        assert!(file_name(&"a".repeat(MAX_FILE_NAME)).is_err());
+    }
+
+    #[test]
+    fn test_journal_record() {
+        let ops = vec![
+            TxnOp::Put("/a".to_owned(), b"1".to_vec(), 0),
+            TxnOp::Delete("/b".to_owned()),
+            TxnOp::Put("/c".to_owned(), b"1".to_vec(), 7),
+        ];
+        let record = encode_record(3, &ops);
+        assert_eq!(decode_record(&record).unwrap(), (3, ops));
+
+        // Torn or corrupted records are dropped
+        assert!(decode_record(&record[..record.len() - 1]).is_none());
+        let mut corrupted = record.clone();
+        corrupted[9] ^= 0xff;
+        assert!(decode_record(&corrupted).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_file_client() {
+        let dir = test_dir("file_client");
+        let client = FileMetaClient::from_endpoints(vec![dir.to_string_lossy().into_owned()]).unwrap();
+
+        client.create("/nodes/1", b"node1").unwrap();
+        assert!(client.create("/nodes/1", b"node1").is_err());
+        client.create("/nodes/2", b"node2").unwrap();
+        client.update("/slots", b"slots").unwrap();
+
+        assert_eq!(client.read("/nodes/1", true).unwrap(), b"node1".to_vec());
+        assert!(client.read("/nodes/3", true).is_err());
+        assert!(client.read("/nodes/3", false).unwrap().is_empty());
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/1", "/nodes/2"]);
+
+        client.delete("/nodes/1").unwrap();
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/2"]);
+        assert!(client.list("/missing/", true).is_err());
+
+        // Reopen the directory, the data should be persisted
+        client.close().unwrap();
+        assert!(client.read("/slots", true).is_err());
+        let client = FileMetaClient::open(&dir).unwrap();
+        assert_eq!(client.list("/", true).unwrap(), vec!["/nodes/2", "/slots"]);
+        assert_eq!(client.read("/slots", true).unwrap(), b"slots".to_vec());
+
+        // The revisions are persisted too
+        let mut watcher = client.watch("/nodes/").unwrap();
+        client.update("/nodes/2", b"node2").unwrap();
+        let event = watcher.next(Duration::from_millis(10)).await.unwrap().unwrap();
+        assert_eq!(event, WatchEvent::put("/nodes/2".to_owned(), b"node2".to_vec(), 5));
+        assert_eq!(client.store().unwrap().get("/nodes/2").unwrap().create_revision, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_client_txn() {
+        let dir = test_dir("file_client_txn");
+        let client = FileMetaClient::open(&dir).unwrap();
+        let revision = client.compare_and_swap("/slots", 0, b"v1").unwrap();
+        client.update("/nodes/1", b"node1").unwrap();
+
+        let txn = Txn::new()
+            .when(Compare::ModRevision("/slots".to_owned(), revision))
+            .put("/slots", b"v2")
+            .delete("/nodes/1");
+        assert_eq!(client.txn(txn.clone()).unwrap(), 3);
+        assert!(is_conflict(&client.txn(txn).unwrap_err()));
+        drop(client);
+
+        let client = FileMetaClient::open(&dir).unwrap();
+        assert_eq!(client.read_revision("/slots").unwrap(), Some((b"v2".to_vec(), 3)));
+        assert_eq!(client.list("/", false).unwrap(), vec!["/slots"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_client_lease() {
+        let dir = test_dir("file_client_lease");
+        let client = FileMetaClient::open(&dir).unwrap();
+        client.update("/nodes/1", b"persisted").unwrap();
+        let lease = client.grant_lease(Duration::from_secs(60)).unwrap();
+        let txn = Txn::new().put_with_lease("/nodes/1", b"node1", lease).put_with_lease("/nodes/2", b"node2", lease);
+        client.txn(txn).unwrap();
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/1", "/nodes/2"]);
+        client.keep_alive(lease).unwrap();
+        drop(client);
+
+        // The leased keys are gone with the process
+        let client = FileMetaClient::open(&dir).unwrap();
+        assert!(client.list("/nodes/", false).unwrap().is_empty());
+        let lease = client.grant_lease(Duration::from_millis(10)).unwrap();
+        client.txn(Txn::new().put_with_lease("/nodes/3", b"node3", lease)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(client.read_revision("/nodes/3").unwrap().is_none());
+        assert!(client.keep_alive(lease).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_client_recover() {
+        let dir = test_dir("file_client_recover");
+        let client = FileMetaClient::open(&dir).unwrap();
+        client.update("/nodes/1", b"node1").unwrap();
+        client.update("/slots", b"old").unwrap();
+        drop(client);
+
+        // Simulate a crash after the journal is synced but before it's applied,
+        // with a temporary file left by the interrupted write
+        let ops = vec![TxnOp::Put("/slots".to_owned(), b"new".to_vec(), 0), TxnOp::Delete("/nodes/1".to_owned())];
+        fs::write(dir.join(JOURNAL_FILE), encode_record(3, &ops)).unwrap();
+        fs::write(dir.join(DATA_DIR).join(format!("{}{}", file_name("/slots").unwrap(), TMP_SUFFIX)), b"ne").unwrap();
+
+        let client = FileMetaClient::open(&dir).unwrap();
+        assert_eq!(client.read("/slots", true).unwrap(), b"new".to_vec());
+        assert!(client.read("/nodes/1", false).unwrap().is_empty());
+        assert!(fs::read(dir.join(JOURNAL_FILE)).unwrap().is_empty());
+        assert_eq!(client.store().unwrap().revision(), 3);
+
+        // An applied journal record is not replayed again
+        fs::write(dir.join(JOURNAL_FILE), encode_record(3, &[TxnOp::Put("/slots".to_owned(), b"stale".to_vec(), 0)])).unwrap();
+        drop(client);
+        let client = FileMetaClient::open(&dir).unwrap();
+        assert_eq!(client.read("/slots", true).unwrap(), b"new".to_vec());
+
+        // A torn journal record is dropped
+        let record = encode_record(4, &[TxnOp::Put("/slots".to_owned(), b"torn".to_vec(), 0)]);
+        fs::write(dir.join(JOURNAL_FILE), &record[..record.len() - 2]).unwrap();
+        drop(client);
+        let client = FileMetaClient::open(&dir).unwrap();
+        assert_eq!(client.read("/slots", true).unwrap(), b"new".to_vec());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_client_invalid_key() {
+        let dir = test_dir("file_client_invalid_key");
+        let client = FileMetaClient::open(&dir).unwrap();
+        client.update("/slots", b"v1").unwrap();
+
+        // Rejected before it's journaled, so the directory can still be opened
+        let long = format!("/{}", "a".repeat(MAX_FILE_NAME));
+        assert!(client.txn(Txn::new().put("/slots", b"v2").put(&long, b"v2")).is_err());
+        assert!(fs::read(dir.join(JOURNAL_FILE)).unwrap().is_empty());
+        drop(client);
+
+        let client = FileMetaClient::open(&dir).unwrap();
+        assert_eq!(client.read_revision("/slots").unwrap(), Some((b"v1".to_vec(), 1)));
+        assert_eq!(client.compare_and_swap("/slots", 1, b"v3").unwrap(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_client_apply_failure() {
+        let dir = test_dir("file_client_apply_failure");
+        let client = FileMetaClient::open(&dir).unwrap();
+        client.update("/slots", b"v1").unwrap();
+
+        // The temporary file of the second key can not be created
+        let blocker = dir.join(DATA_DIR).join(format!("{}{}", file_name("/nodes/1").unwrap(), TMP_SUFFIX));
+        fs::create_dir(&blocker).unwrap();
+        let txn = Txn::new().put("/slots", b"v2").put("/nodes/1", b"node1");
+        assert!(client.txn(txn).is_err());
+        assert!(client.read("/slots", true).is_err());
+
+        // The half applied batch is finished before the next access, at its own revision
+        fs::remove_dir(&blocker).unwrap();
+        assert_eq!(client.read_revision("/slots").unwrap(), Some((b"v2".to_vec(), 2)));
+        assert_eq!(client.read_revision("/nodes/1").unwrap(), Some((b"node1".to_vec(), 2)));
+        assert_eq!(client.compare_and_swap("/slots", 2, b"v3").unwrap(), 3);
+        drop(client);
+
+        // Or replayed on the next open
+        let client = FileMetaClient::open(&dir).unwrap();
+        fs::create_dir(&blocker).unwrap();
+        assert!(client.txn(Txn::new().put("/slots", b"v4").put("/nodes/1", b"node2")).is_err());
+        drop(client);
+        fs::remove_dir(&blocker).unwrap();
+        let client = FileMetaClient::open(&dir).unwrap();
+        assert_eq!(client.read_revision("/slots").unwrap(), Some((b"v4".to_vec(), 4)));
+        assert_eq!(client.read_revision("/nodes/1").unwrap(), Some((b"node2".to_vec(), 4)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
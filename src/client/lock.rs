@@ -0,0 +1,283 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use tokio::time;
+use tracing::warn;
+
+use super::{is_conflict, is_unavailable, MetaClient};
+
+/// Lease backed distributed lock
+///
+/// The lock is a path holding the owner, attached to a lease granted by the
+/// lock. The holder keeps the lease alive by calling `try_lock` again within
+/// the ttl, otherwise the lock is released when the lease expires, so a
+/// crashed holder never blocks the others forever. A lease failed to be kept
+/// alive for an unavailable meta data service is retried until the ttl
+/// passes, so the lock is not given up for a transient error.
+#[derive(Debug)]
+pub struct MetaLock {
+    /// The lock path
+    path: String,
+    /// The owner written to the lock path
+    owner: String,
+    /// The ttl of the lease
+    ttl: Duration,
+    /// The lease attached to the lock path, Some if the lock is held
+    lease: Option<u64>,
+    /// When the lease was last granted or kept alive, it lives until `ttl` after it
+    renewed: Instant,
+}
+
+impl MetaLock {
+    /// Create a new lock on the path
+    pub fn new(path: &str, owner: &str, ttl: Duration) -> Self {
+        Self {
+            path: path.to_owned(),
+            owner: owner.to_owned(),
+            ttl,
+            lease: None,
+            renewed: Instant::now(),
+        }
+    }
+
+    /// Get the lock path
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Get the owner
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// The lock is held or not, as of the last `try_lock`
+    pub fn is_locked(&self) -> bool {
+        self.lease.is_some()
+    }
+
+    /// Try to acquire the lock, or keep it alive if it's held, return the lock is held or not
+    pub async fn try_lock<C: MetaClient>(&mut self, client: &C) -> Result<bool, Error> {
+        if let Some(lease) = self.lease.take() {
+            let deadline = self.renewed + self.ttl;
+            let interval = (self.ttl / 10).max(Duration::from_millis(10));
+            loop {
+                let sent = Instant::now();
+                match self.keep_alive(client, lease).await {
+                    Ok(true) => {
+                        self.lease = Some(lease);
+                        self.renewed = sent;
+                        return Ok(true);
+                    }
+                    Ok(false) => warn!("Lock {} is lost", self.path),
+                    Err(e) if is_expired(&e) => warn!("Lease of lock {} is expired: {:?}", self.path, e),
+                    Err(e) if Instant::now() + interval < deadline => {
+                        warn!("Keep lock {} alive failed, retry: {:?}", self.path, e);
+                        time::sleep(interval).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        // The lease is expired by now, no need to revoke it
+                        warn!("Lock {} is not kept alive within the ttl: {:?}", self.path, e);
+                        break;
+                    }
+                }
+                // Release it in case the lease is still alive
+                let _ = client.revoke_lease(lease).await;
+                break;
+            }
+        }
+
+        if client.read_revision(&self.path).await?.is_some() {
+            return Ok(false);
+        }
+
+        let sent = Instant::now();
+        let lease = client.grant_lease(self.ttl).await?;
+        match client.compare_and_swap_with_lease(&self.path, 0, self.owner.as_bytes(), lease).await {
+            Ok(_) => {
+                self.lease = Some(lease);
+                self.renewed = sent;
+                Ok(true)
+            }
+            Err(e) => {
+                let _ = client.revoke_lease(lease).await;
+                if is_conflict(&e) {
+                    Ok(false)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Acquire the lock, wait up to `timeout`
+    pub async fn lock<C: MetaClient>(&mut self, client: &C, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let interval = (self.ttl / 4).max(Duration::from_millis(10));
+        loop {
+            if self.try_lock(client).await? {
+                return Ok(());
+            }
+            if Instant::now() + interval > deadline {
+                return Err(anyhow!("lock {} is not acquired in {:?}", self.path, timeout));
+            }
+            time::sleep(interval).await;
+        }
+    }
+
+    /// Release the lock if it's held
+    pub async fn unlock<C: MetaClient>(&mut self, client: &C) -> Result<(), Error> {
+        match self.lease.take() {
+            Some(lease) => client.revoke_lease(lease).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Get the owner of the lock on the path, None if it's not held
+    pub async fn holder<C: MetaClient>(client: &C, path: &str) -> Result<Option<String>, Error> {
+        client
+            .read_revision(path)
+            .await?
+            .map(|(owner, _)| String::from_utf8(owner).map_err(Error::from))
+            .transpose()
+    }
+
+    /// Refresh the lease, and check the lock path is still owned
+    async fn keep_alive<C: MetaClient>(&self, client: &C, lease: u64) -> Result<bool, Error> {
+        client.keep_alive(lease).await?;
+        let owner = client.read_revision(&self.path).await?.map(|(owner, _)| owner);
+        Ok(owner.as_deref() == Some(self.owner.as_bytes()))
+    }
+}
+
+/// The error confirms the lease is gone, rather than failing to reach the meta data service
+fn is_expired(error: &Error) -> bool {
+    if is_conflict(error) {
+        return true;
+    }
+    let message = error.to_string();
+    !is_unavailable(error) && (message.contains("not found") || message.contains("expired"))
+}
+
+/// Leader election on top of `MetaLock`
+///
+/// The candidates campaign on the same path periodically, and the one holding
+/// the lock is the leader. If the leader stops campaigning, e.g. it crashed,
+/// its lease expires and another candidate wins the next campaign.
+#[derive(Debug)]
+pub struct Election {
+    /// The lock held by the leader
+    lock: MetaLock,
+}
+
+impl Election {
+    /// Create a new election on the path for the candidate
+    pub fn new(path: &str, candidate: &str, ttl: Duration) -> Self {
+        Self {
+            lock: MetaLock::new(path, candidate, ttl),
+        }
+    }
+
+    /// Get the candidate id
+    pub fn candidate(&self) -> &str {
+        self.lock.owner()
+    }
+
+    /// Campaign for the leadership, or keep it, return this candidate is the leader or not
+    ///
+    /// It should be called within the ttl to keep the leadership.
+    pub async fn campaign<C: MetaClient>(&mut self, client: &C) -> Result<bool, Error> {
+        self.lock.try_lock(client).await
+    }
+
+    /// This candidate is the leader or not, as of the last campaign
+    pub fn is_leader(&self) -> bool {
+        self.lock.is_locked()
+    }
+
+    /// Give up the leadership
+    pub async fn resign<C: MetaClient>(&mut self, client: &C) -> Result<(), Error> {
+        self.lock.unlock(client).await
+    }
+
+    /// Get the current leader, None if there is no leader
+    pub async fn leader<C: MetaClient>(&self, client: &C) -> Result<Option<String>, Error> {
+        MetaLock::holder(client, self.lock.path()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::client::memory::InMemoryMetaClient;
+    use crate::client::retry::tests::FlakyClient;
+
+    #[tokio::test]
+    async fn test_meta_lock() {
+        let client = InMemoryMetaClient::new();
+        let mut lock1 = MetaLock::new("/locks/rebalancing", "proxy1", Duration::from_secs(60));
+        let mut lock2 = MetaLock::new("/locks/rebalancing", "proxy2", Duration::from_secs(60));
+
+        assert!(lock1.try_lock(&client).await.unwrap());
+        assert!(lock1.try_lock(&client).await.unwrap());
+        assert!(!lock2.try_lock(&client).await.unwrap());
+        assert!(lock2.lock(&client, Duration::from_millis(50)).await.is_err());
+        assert_eq!(MetaLock::holder(&client, "/locks/rebalancing").await.unwrap().as_deref(), Some("proxy1"));
+
+        lock1.unlock(&client).await.unwrap();
+        assert!(MetaLock::holder(&client, "/locks/rebalancing").await.unwrap().is_none());
+        lock2.lock(&client, Duration::from_millis(50)).await.unwrap();
+        assert!(!lock1.try_lock(&client).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_election_failover() {
+        let client = InMemoryMetaClient::new();
+        let ttl = Duration::from_millis(50);
+        let mut election1 = Election::new("/election", "proxy1", ttl);
+        let mut election2 = Election::new("/election", "proxy2", ttl);
+
+        assert!(election1.campaign(&client).await.unwrap());
+        assert!(!election2.campaign(&client).await.unwrap());
+        assert_eq!(election2.leader(&client).await.unwrap().as_deref(), Some("proxy1"));
+
+        // The leader stops campaigning
+        time::sleep(ttl * 2).await;
+        assert!(election2.campaign(&client).await.unwrap());
+        assert!(!election1.campaign(&client).await.unwrap());
+        assert!(!election1.is_leader());
+        assert_eq!(election1.leader(&client).await.unwrap().as_deref(), Some("proxy2"));
+
+        election2.resign(&client).await.unwrap();
+        assert!(election1.leader(&client).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_meta_lock_unavailable() {
+        let client = FlakyClient::new(InMemoryMetaClient::new());
+        let ttl = Duration::from_millis(200);
+        let mut lock1 = MetaLock::new("/locks/rebalancing", "proxy1", ttl);
+        let mut lock2 = MetaLock::new("/locks/rebalancing", "proxy2", ttl);
+        assert!(lock1.try_lock(&client).await.unwrap());
+
+        // Kept alive once the meta data service is back within the ttl
+        client.down.store(true, Ordering::Relaxed);
+        let down = client.down.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(50)).await;
+            down.store(false, Ordering::Relaxed);
+        });
+        assert!(lock1.try_lock(&client).await.unwrap());
+        assert!(!lock2.try_lock(&client).await.unwrap());
+        assert_eq!(MetaLock::holder(&client, "/locks/rebalancing").await.unwrap().as_deref(), Some("proxy1"));
+
+        // Given up once the ttl passes
+        client.down.store(true, Ordering::Relaxed);
+        assert!(lock1.try_lock(&client).await.is_err());
+        assert!(!lock1.is_locked());
+        client.down.store(false, Ordering::Relaxed);
+        lock2.lock(&client, ttl * 2).await.unwrap();
+    }
+}
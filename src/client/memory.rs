@@ -0,0 +1,273 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+
+use super::store::KvStore;
+use super::watch::ChannelWatcher;
+use super::{MetaClient, Txn, TxnOp};
+
+/// In-memory meta data client
+///
+/// This struct keeps the meta data in process memory, with the same path and
+/// prefix semantics as `ETCDClient`. Clones share the same data, so several
+/// managers in one process can form a cluster, e.g. in unit tests or in a
+/// standalone proxy without external meta data service.
+#[derive(Debug, Default)]
+pub struct InMemoryMetaClient {
+    /// The shared meta data store
+    store: Arc<Mutex<KvStore>>,
+    /// This handle is closed or not, not shared between clones
+    closed: AtomicBool,
+}
+
+impl InMemoryMetaClient {
+    /// Create a new in-memory client with empty data
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lock the shared store and delete the keys of expired leases, fail if this handle is closed
+    fn store(&self) -> Result<MutexGuard<'_, KvStore>, Error> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(anyhow!("in-memory meta client is closed"));
+        }
+
+        let mut store = self.store.lock().unwrap();
+        let expired = store.expire(Instant::now());
+        if !expired.is_empty() {
+            Self::commit(&mut store, &expired);
+        }
+        Ok(store)
+    }
+
+    /// Apply the ops at the next revision
+    fn commit(store: &mut KvStore, ops: &[TxnOp]) -> u64 {
+        let revision = store.revision() + 1;
+        store.apply(ops, revision);
+        revision
+    }
+}
+
+impl Clone for InMemoryMetaClient {
+    fn clone(&self) -> Self {
+        Self {
+            store: Arc::clone(&self.store),
+            closed: AtomicBool::new(false),
+        }
+    }
+}
+
+#[async_trait]
+impl MetaClient for InMemoryMetaClient {
+    type Watcher = ChannelWatcher;
+
+    fn from_endpoints(_endpoints: Vec<String>) -> Result<Self, Error> {
+        Ok(Self::new())
+    }
+
+    async fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let mut store = self.store()?;
+        if store.get(path).is_some() {
+            return Err(anyhow!("meta data {} already exists", path));
+        }
+        Self::commit(&mut store, &[TxnOp::Put(path.to_owned(), data.to_vec(), 0)]);
+
+        Ok(())
+    }
+
+    async fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        Self::commit(&mut *self.store()?, &[TxnOp::Put(path.to_owned(), data.to_vec(), 0)]);
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), Error> {
+        let mut store = self.store()?;
+        if store.get(path).is_some() {
+            Self::commit(&mut store, &[TxnOp::Delete(path.to_owned())]);
+        }
+
+        Ok(())
+    }
+
+    async fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+        match self.store()?.get(path) {
+            Some(entry) => Ok(entry.value.clone()),
+            None if must => Err(anyhow!("meta data {} not found", path)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+        let keys = self.store()?.keys(path);
+
+        if keys.is_empty() && must {
+            return Err(anyhow!("meta data under {} not found", path));
+        }
+
+        Ok(keys)
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.closed.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    async fn watch(&self, path: &str) -> Result<Self::Watcher, Error> {
+        Ok(self.store()?.watch(path))
+    }
+
+    async fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        Ok(self.store()?.get(path).map(|entry| (entry.value.clone(), entry.mod_revision)))
+    }
+
+    async fn txn(&self, txn: Txn) -> Result<u64, Error> {
+        let mut store = self.store()?;
+        store.check(&txn.compares)?;
+        store.check_leases(&txn.ops)?;
+        if txn.ops.is_empty() {
+            return Ok(store.revision());
+        }
+
+        Ok(Self::commit(&mut store, &txn.ops))
+    }
+
+    async fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+        Ok(self.store()?.grant(ttl))
+    }
+
+    async fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+        self.store()?.keep_alive(lease)
+    }
+
+    async fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+        let mut store = self.store()?;
+        let ops = store.revoke(lease);
+        if !ops.is_empty() {
+            Self::commit(&mut store, &ops);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+    use crate::client::{is_conflict, Compare, WatchEvent, Watcher};
+
+    #[tokio::test]
+    async fn test_memory_client() {
+        let client = InMemoryMetaClient::new();
+
+        client.create("/nodes/1", b"node1").await.unwrap();
+        assert!(client.create("/nodes/1", b"node1").await.is_err());
+        client.create("/nodes/2", b"node2").await.unwrap();
+        client.create("/nodes0", b"other").await.unwrap();
+        client.update("/slots", b"slots").await.unwrap();
+
+        assert_eq!(client.read("/nodes/1", true).await.unwrap(), b"node1".to_vec());
+        assert!(client.read("/nodes/3", true).await.is_err());
+        assert!(client.read("/nodes/3", false).await.unwrap().is_empty());
+        assert_eq!(client.list("/nodes/", true).await.unwrap(), vec!["/nodes/1", "/nodes/2"]);
+
+        client.delete("/nodes/1").await.unwrap();
+        assert_eq!(client.list("/nodes/", true).await.unwrap(), vec!["/nodes/2"]);
+        assert!(client.list("/missing/", true).await.is_err());
+        assert!(client.list("/missing/", false).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_client_shared() {
+        let client = InMemoryMetaClient::new();
+        let handles: Vec<_> = (0..4)
+            .map(|id| {
+                let client = client.clone();
+                tokio::spawn(async move { client.create(&format!("/nodes/{}", id), b"node").await.unwrap() })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(client.list("/nodes/", true).await.unwrap().len(), 4);
+
+        // Closing a handle does not affect the others
+        let other = client.clone();
+        client.close().await.unwrap();
+        assert!(client.read("/nodes/0", true).await.is_err());
+        assert_eq!(other.read("/nodes/0", true).await.unwrap(), b"node".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_memory_client_watch() {
+        let client = InMemoryMetaClient::new();
+        client.update("/nodes/1", b"node1").await.unwrap();
+
+        let mut watcher = client.clone().watch("/nodes/").await.unwrap();
+        client.update("/slots", b"slots").await.unwrap();
+        client.update("/nodes/2", b"node2").await.unwrap();
+        client.delete("/nodes/1").await.unwrap();
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(watcher.next(timeout).await.unwrap(), Some(WatchEvent::put("/nodes/2".to_owned(), b"node2".to_vec(), 3)));
+        assert_eq!(watcher.next(timeout).await.unwrap(), Some(WatchEvent::delete("/nodes/1".to_owned(), 4)));
+        assert_eq!(watcher.next(timeout).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_client_txn() {
+        let client = InMemoryMetaClient::new();
+        let revision = client.compare_and_swap("/slots", 0, b"v1").await.unwrap();
+        assert_eq!(client.read_revision("/slots").await.unwrap(), Some((b"v1".to_vec(), revision)));
+        assert!(is_conflict(&client.compare_and_swap("/slots", 0, b"v2").await.unwrap_err()));
+
+        // All or nothing
+        let txn = Txn::new()
+            .when(Compare::ModRevision("/slots".to_owned(), revision))
+            .when(Compare::ModRevision("/ring_version".to_owned(), 1))
+            .put("/slots", b"v2")
+            .put("/ring_version", b"1");
+        assert!(is_conflict(&client.txn(txn).await.unwrap_err()));
+        assert_eq!(client.read("/slots", true).await.unwrap(), b"v1".to_vec());
+        assert!(client.read_revision("/ring_version").await.unwrap().is_none());
+
+        let txn = Txn::new()
+            .when(Compare::ModRevision("/slots".to_owned(), revision))
+            .when(Compare::ModRevision("/ring_version".to_owned(), 0))
+            .put("/slots", b"v2")
+            .put("/ring_version", b"1");
+        let committed = client.txn(txn).await.unwrap();
+        assert_eq!(committed, revision + 1);
+        assert_eq!(client.read_revision("/slots").await.unwrap(), Some((b"v2".to_vec(), committed)));
+        assert_eq!(client.read_revision("/ring_version").await.unwrap(), Some((b"1".to_vec(), committed)));
+    }
+
+    #[tokio::test]
+    async fn test_memory_client_lease() {
+        let client = InMemoryMetaClient::new();
+        let lease = client.grant_lease(Duration::from_millis(50)).await.unwrap();
+        client.txn(Txn::new().put_with_lease("/nodes/1", b"node1", lease)).await.unwrap();
+        assert!(client.txn(Txn::new().put_with_lease("/nodes/2", b"node2", lease + 1)).await.is_err());
+
+        let mut watcher = client.watch("/nodes/").await.unwrap();
+        for _ in 0..3 {
+            time::sleep(Duration::from_millis(20)).await;
+            client.keep_alive(lease).await.unwrap();
+        }
+        assert_eq!(client.list("/nodes/", true).await.unwrap(), vec!["/nodes/1"]);
+
+        // Expired without keepalive
+        time::sleep(Duration::from_millis(60)).await;
+        assert!(client.list("/nodes/", false).await.unwrap().is_empty());
+        assert!(client.keep_alive(lease).await.is_err());
+        assert_eq!(watcher.next(Duration::from_millis(10)).await.unwrap(), Some(WatchEvent::delete("/nodes/1".to_owned(), 2)));
+
+        let lease = client.grant_lease(Duration::from_secs(60)).await.unwrap();
+        client.txn(Txn::new().put_with_lease("/nodes/2", b"node2", lease)).await.unwrap();
+        client.revoke_lease(lease).await.unwrap();
+        assert!(client.read_revision("/nodes/2").await.unwrap().is_none());
+    }
+}
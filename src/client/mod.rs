@@ -0,0 +1,421 @@
+use std::error;
+use std::fmt::{self, Display};
+use std::io;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+
+use crate::config::{Config, MetaType};
+use any::AnyMetaClient;
+use blocking::Blocking;
+use etcd::ETCDClient;
+use file::FileMetaClient;
+use memory::InMemoryMetaClient;
+use namespace::Namespaced;
+use redis::RedisClient;
+use retry::RetryClient;
+
+/// ETCD client
+pub mod etcd;
+
+/// Redis client
+pub mod redis;
+
+/// In-memory client
+pub mod memory;
+
+/// File backed client
+pub mod file;
+
+/// Watcher implementations shared by clients
+pub mod watch;
+
+/// Blocking meta data clients and the async adapter
+pub mod blocking;
+
+/// Distributed lock and leader election
+pub mod lock;
+
+/// Client keeping the paths under a namespace
+pub mod namespace;
+
+/// Client of the backend chosen at runtime
+pub mod any;
+
+/// Client wrapper with retry, backoff and circuit breaking
+pub mod retry;
+
+/// In-memory key value state machine shared by clients
+mod store;
+
+/// Meta data client trait.
+/// 
+/// This trait is used to interact with meta data service.
+/// Probably it's a etcd server
+///
+/// All the calls are async, the futures are cancellation safe, i.e. dropping
+/// a pending call never leaves the client in a broken state.
+#[async_trait]
+pub trait MetaClient: Send + Sync {
+    /// The watcher returned by `watch`
+    type Watcher: Watcher + 'static;
+
+    /// Create a meta data client connecting to the endpoints
+    fn from_endpoints(endpoints: Vec<String>) -> Result<Self, Error>
+    where
+        Self: Sized;
+
+    /// Create a new meta data
+    async fn create(&self, path: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Update the meta data
+    async fn update(&self, path: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Delete the meta data
+    async fn delete(&self, path: &str) -> Result<(), Error>;
+
+    /// Read the meta data
+    async fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error>;
+
+    /// List the meta data
+    async fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error>;
+
+    /// Close the meta data client
+    async fn close(&self) -> Result<(), Error>;
+
+    /// Watch the meta data changes under the path prefix, from the current revision
+    async fn watch(&self, path: &str) -> Result<Self::Watcher, Error>;
+
+    /// Read the meta data with its mod revision, None if it does not exist
+    async fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error>;
+
+    /// Commit the ops atomically if all the compares hold, return the commit revision
+    ///
+    /// It fails with `ConflictError` if any compare does not hold, then none of the ops is applied.
+    async fn txn(&self, txn: Txn) -> Result<u64, Error>;
+
+    /// Update the meta data only if its mod revision is `revision`, 0 means it does not exist
+    async fn compare_and_swap(&self, path: &str, revision: u64, data: &[u8]) -> Result<u64, Error> {
+        self.txn(Txn::new().when(Compare::ModRevision(path.to_owned(), revision)).put(path, data)).await
+    }
+
+    /// Grant a lease which expires after `ttl` without keepalive, return the lease id
+    async fn grant_lease(&self, ttl: Duration) -> Result<u64, Error>;
+
+    /// Refresh the lease to its full ttl, fail if it's expired or revoked
+    async fn keep_alive(&self, lease: u64) -> Result<(), Error>;
+
+    /// Revoke the lease, the meta data attached to it are deleted
+    async fn revoke_lease(&self, lease: u64) -> Result<(), Error>;
+
+    /// Create a new meta data attached to the lease, it's deleted when the lease expires
+    async fn create_with_lease(&self, path: &str, data: &[u8], lease: u64) -> Result<(), Error> {
+        match self.compare_and_swap_with_lease(path, 0, data, lease).await {
+            Err(e) if is_conflict(&e) => Err(anyhow!("meta data {} already exists", path)),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Update the meta data attached to the lease, it's deleted when the lease expires
+    async fn update_with_lease(&self, path: &str, data: &[u8], lease: u64) -> Result<(), Error> {
+        self.txn(Txn::new().put_with_lease(path, data, lease)).await?;
+        Ok(())
+    }
+
+    /// Update the meta data attached to the lease only if its mod revision is `revision`
+    async fn compare_and_swap_with_lease(&self, path: &str, revision: u64, data: &[u8], lease: u64) -> Result<u64, Error> {
+        self.txn(Txn::new().when(Compare::ModRevision(path.to_owned(), revision)).put_with_lease(path, data, lease))
+            .await
+    }
+}
+
+/// Compare of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Compare {
+    /// The mod revision of the path equals the revision, 0 means the path does not exist
+    ModRevision(String, u64),
+}
+
+impl Compare {
+    /// Get the compared path
+    pub fn path(&self) -> &str {
+        match *self {
+            Compare::ModRevision(ref path, _) => path,
+        }
+    }
+}
+
+/// Operation of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxnOp {
+    /// Put the value of a path attached to the lease, 0 means no lease
+    Put(String, Vec<u8>, u64),
+    /// Delete a path
+    Delete(String),
+}
+
+/// Multi-key transaction
+///
+/// All the ops are committed at the same revision if all the compares hold,
+/// otherwise none of them is applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Txn {
+    /// The compares
+    pub compares: Vec<Compare>,
+    /// The ops applied in order
+    pub ops: Vec<TxnOp>,
+}
+
+impl Txn {
+    /// Create a new empty transaction
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a compare
+    pub fn when(mut self, compare: Compare) -> Self {
+        self.compares.push(compare);
+        self
+    }
+
+    /// Add a put op
+    pub fn put(mut self, path: &str, data: &[u8]) -> Self {
+        self.ops.push(TxnOp::Put(path.to_owned(), data.to_vec(), 0));
+        self
+    }
+
+    /// Add a put op attached to the lease, the path is deleted when the lease expires
+    pub fn put_with_lease(mut self, path: &str, data: &[u8], lease: u64) -> Self {
+        self.ops.push(TxnOp::Put(path.to_owned(), data.to_vec(), lease));
+        self
+    }
+
+    /// Add a delete op
+    pub fn delete(mut self, path: &str) -> Self {
+        self.ops.push(TxnOp::Delete(path.to_owned()));
+        self
+    }
+}
+
+/// The compares of a transaction do not hold
+///
+/// It's returned wrapped in `anyhow::Error`, check it with `downcast_ref::<ConflictError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictError {
+    /// The paths whose compare failed, or all the compared paths if the backend can't tell
+    paths: Vec<String>,
+}
+
+impl ConflictError {
+    /// Create a new conflict error
+    pub fn new(paths: Vec<String>) -> Self {
+        Self { paths }
+    }
+
+    /// Get the conflicted paths
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+}
+
+impl Display for ConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "meta data conflicts on {:?}", self.paths)
+    }
+}
+
+impl error::Error for ConflictError {}
+
+/// Check the error is a transaction conflict or not
+pub fn is_conflict(error: &Error) -> bool {
+    error.downcast_ref::<ConflictError>().is_some()
+}
+
+/// The meta data service is unavailable, e.g. unreachable or timed out
+///
+/// The call may succeed if it's retried later or on another endpoint. Io
+/// errors in the error chain are regarded as unavailable too. Unless it's
+/// unsent, the request may have reached the service and been applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnavailableError {
+    /// The error message
+    message: String,
+    /// The request is known not to be sent, e.g. it failed to connect
+    unsent: bool,
+}
+
+impl UnavailableError {
+    /// Create a new unavailable error
+    pub fn new(message: String) -> Self {
+        Self { message, unsent: false }
+    }
+
+    /// Create a new unavailable error of the request known not to be sent
+    pub fn unsent(message: String) -> Self {
+        Self { message, unsent: true }
+    }
+
+    /// The request is known not to be sent or not
+    pub fn is_unsent(&self) -> bool {
+        self.unsent
+    }
+}
+
+impl Display for UnavailableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "meta data service is unavailable: {}", self.message)
+    }
+}
+
+impl error::Error for UnavailableError {}
+
+/// Check the error is caused by the unavailable meta data service or not
+pub fn is_unavailable(error: &Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.is::<UnavailableError>() || cause.is::<io::Error>())
+}
+
+/// Check the request failed before it's sent or not, so even a write can be retried safely
+pub fn is_unsent(error: &Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.downcast_ref::<UnavailableError>().is_some_and(UnavailableError::is_unsent))
+}
+
+/// Metadata watcher trait
+/// 
+/// This trait is used to watch the meta data change.
+/// The watcher is cancelled when it's dropped.
+#[async_trait]
+pub trait Watcher: Send {
+    /// Wait for the next event up to `timeout`, return `Ok(None)` if there is no event in time.
+    /// An error means the watch stream is broken, and a new watcher should be created.
+    /// It's cancellation safe, no event is lost if the future is dropped.
+    async fn next(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, Error>;
+}
+
+/// Watch event type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    /// The meta data is created or updated
+    Put,
+    /// The meta data is deleted
+    Delete,
+}
+
+/// Watch event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// The event type
+    pub event_type: EventType,
+    /// The meta data path
+    pub key: String,
+    /// The new value, empty for delete event
+    pub value: Vec<u8>,
+    /// The store revision of the change
+    pub revision: u64,
+}
+
+impl WatchEvent {
+    /// Create a put event
+    pub fn put(key: String, value: Vec<u8>, revision: u64) -> Self {
+        Self {
+            event_type: EventType::Put,
+            key,
+            value,
+            revision,
+        }
+    }
+
+    /// Create a delete event
+    pub fn delete(key: String, revision: u64) -> Self {
+        Self {
+            event_type: EventType::Delete,
+            key,
+            value: Vec::new(),
+            revision,
+        }
+    }
+}
+
+/// Meta data client created from the config
+pub type ConfiguredMetaClient = Namespaced<RetryClient<AnyMetaClient>>;
+
+/// Create a new meta data client of the configured type, with the endpoints, auth and namespace
+pub fn new_meta_client(config: &Config) -> Result<ConfiguredMetaClient, Error> {
+    let meta_type = config.meta_type.get_meta_type();
+    let endpoints = config.meta_endpoints.clone();
+    let auth = config.meta_password.as_deref().map(|password| (config.meta_username.as_deref(), password));
+    if endpoints.is_empty() && !matches!(config.meta_type, MetaType::Memory) {
+        return Err(anyhow!("no meta endpoint is configured for {}", meta_type));
+    }
+
+    // A backend client per endpoint, so the retry client can rotate and break circuits per endpoint
+    let backend = |endpoint: &str| -> Result<AnyMetaClient, Error> {
+        Ok(match (&config.meta_type, auth) {
+            (&MetaType::ETCD, auth) => {
+                let mut client = ETCDClient::new(endpoint.to_owned());
+                if let Some((username, password)) = auth {
+                    let username = username.ok_or_else(|| anyhow!("etcd auth needs the user name"))?;
+                    client = client.with_auth(username, password);
+                }
+                AnyMetaClient::ETCD(Blocking::new(client))
+            }
+            (&MetaType::Redis, auth) => {
+                let mut client = RedisClient::new(vec![endpoint.to_owned()]);
+                if let Some((username, password)) = auth {
+                    client = client.with_auth(username, password);
+                }
+                AnyMetaClient::Redis(Blocking::new(client))
+            }
+            (_, Some(_)) => return Err(anyhow!("meta type {} does not support auth", meta_type)),
+            (&MetaType::Memory, None) => AnyMetaClient::Memory(InMemoryMetaClient::new()),
+            (&MetaType::File, None) => AnyMetaClient::File(Blocking::new(FileMetaClient::open(endpoint)?)),
+        })
+    };
+
+    let client = match config.meta_type {
+        MetaType::ETCD | MetaType::Redis => RetryClient::new(
+            endpoints
+                .iter()
+                .map(|endpoint| Ok((endpoint.clone(), backend(endpoint)?)))
+                .collect::<Result<Vec<_>, Error>>()?,
+        )?,
+        MetaType::Memory | MetaType::File => RetryClient::single(backend(endpoints.first().map_or("", String::as_str))?),
+    };
+
+    Namespaced::new(client, &config.meta_namespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    fn test_config(meta_type: &str, endpoints: Vec<String>) -> Config {
+        Config::new(1024, meta_type, endpoints, 1, "127.0.0.1".to_owned(), 8000).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_new_meta_client() {
+        assert!(Config::new(1024, "zookeeper", Vec::new(), 1, "127.0.0.1".to_owned(), 8000).is_err());
+        assert!(new_meta_client(&test_config("etcd", Vec::new())).is_err());
+        assert!(new_meta_client(&test_config("memory", Vec::new()).with_meta_auth(None, "secret".to_owned())).is_err());
+        assert!(new_meta_client(&test_config("etcd", vec!["127.0.0.1:2379".to_owned()]).with_meta_auth(None, "secret".to_owned())).is_err());
+
+        let client = new_meta_client(&test_config("memory", Vec::new()).with_meta_namespace("cluster1")).unwrap();
+        assert!(matches!(*client.inner().current(), AnyMetaClient::Memory(_)));
+        client.create("/nodes/1", b"node1").await.unwrap();
+        assert_eq!(client.list("/nodes/", true).await.unwrap(), vec!["/nodes/1"]);
+        assert_eq!(client.inner().list("/", true).await.unwrap(), vec!["/ns/cluster1/nodes/1"]);
+
+        let dir = env::temp_dir().join(format!("cache_proxy_new_meta_client_{}", std::process::id()));
+        let client = new_meta_client(&test_config("file", vec![dir.to_string_lossy().into_owned()])).unwrap();
+        client.update("/slots", b"slots").await.unwrap();
+        assert_eq!(client.read("/slots", true).await.unwrap(), b"slots".to_vec());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+
+use super::{Compare, ConflictError, MetaClient, Txn, TxnOp, WatchEvent, Watcher};
+use crate::schema::CLUSTER_PATH;
+
+/// The prefix of the named namespaces, kept apart from the paths of the root
+pub const NAMESPACE_PATH: &str = "/ns/";
+
+/// Meta data client keeping all paths under a namespace
+///
+/// The namespace is prefixed to the paths sent to the inner client, and
+/// stripped from the paths returned, so several clusters can share one meta
+/// data service without seeing each other. An empty namespace is the root,
+/// the named ones are kept under `NAMESPACE_PATH`.
+#[derive(Debug, Clone)]
+pub struct Namespaced<C> {
+    /// The inner client
+    inner: C,
+    /// The path prefix, e.g. `/ns/cluster1`, empty for the root
+    prefix: String,
+}
+
+impl<C: MetaClient> Namespaced<C> {
+    /// Create a new client under the namespace, e.g. `cluster1` or `/cluster1/`
+    pub fn new(inner: C, namespace: &str) -> Result<Self, Error> {
+        Ok(Self {
+            inner,
+            prefix: namespace_prefix(namespace)?,
+        })
+    }
+
+    /// Get the inner client
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Get the path prefix of the namespace
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The path in the inner client
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+
+    /// Strip the namespace from the errors of transactions
+    fn strip_conflict(&self, error: Error) -> Error {
+        match error.downcast::<ConflictError>() {
+            Ok(conflict) => Error::new(ConflictError::new(
+                conflict.paths().iter().map(|path| strip(&self.prefix, path)).collect(),
+            )),
+            Err(error) => error,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: MetaClient> MetaClient for Namespaced<C> {
+    type Watcher = NamespacedWatcher<C::Watcher>;
+
+    fn from_endpoints(endpoints: Vec<String>) -> Result<Self, Error> {
+        Self::new(C::from_endpoints(endpoints)?, "")
+    }
+
+    async fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        self.inner.create(&self.key(path), data).await
+    }
+
+    async fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        self.inner.update(&self.key(path), data).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), Error> {
+        self.inner.delete(&self.key(path)).await
+    }
+
+    async fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+        self.inner.read(&self.key(path), must).await
+    }
+
+    async fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+        let paths = self.inner.list(&self.key(path), must).await?;
+        Ok(paths.iter().map(|key| strip(&self.prefix, key)).collect())
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.inner.close().await
+    }
+
+    async fn watch(&self, path: &str) -> Result<Self::Watcher, Error> {
+        Ok(NamespacedWatcher {
+            inner: self.inner.watch(&self.key(path)).await?,
+            prefix: self.prefix.clone(),
+        })
+    }
+
+    async fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.inner.read_revision(&self.key(path)).await
+    }
+
+    async fn txn(&self, txn: Txn) -> Result<u64, Error> {
+        let txn = Txn {
+            compares: txn
+                .compares
+                .into_iter()
+                .map(|compare| match compare {
+                    Compare::ModRevision(path, revision) => Compare::ModRevision(self.key(&path), revision),
+                })
+                .collect(),
+            ops: txn
+                .ops
+                .into_iter()
+                .map(|op| match op {
+                    TxnOp::Put(path, data, lease) => TxnOp::Put(self.key(&path), data, lease),
+                    TxnOp::Delete(path) => TxnOp::Delete(self.key(&path)),
+                })
+                .collect(),
+        };
+
+        self.inner.txn(txn).await.map_err(|e| self.strip_conflict(e))
+    }
+
+    async fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+        self.inner.grant_lease(ttl).await
+    }
+
+    async fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+        self.inner.keep_alive(lease).await
+    }
+
+    async fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+        self.inner.revoke_lease(lease).await
+    }
+}
+
+/// Watcher stripping the namespace from the event paths
+#[derive(Debug)]
+pub struct NamespacedWatcher<W> {
+    /// The inner watcher
+    inner: W,
+    /// The path prefix of the namespace
+    prefix: String,
+}
+
+#[async_trait]
+impl<W: Watcher> Watcher for NamespacedWatcher<W> {
+    async fn next(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, Error> {
+        let event = self.inner.next(timeout).await?;
+        Ok(event.map(|event| WatchEvent {
+            key: strip(&self.prefix, &event.key),
+            ..event
+        }))
+    }
+}
+
+/// The path prefix of the namespace, e.g. `/ns/cluster1` for `cluster1` or `/cluster1/`
+///
+/// A namespace may be nested like `team/cluster1`, but no segment may be
+/// empty, and no nested segment may be the first segment of `CLUSTER_PATH`,
+/// otherwise its paths would be under the topology of the enclosing one.
+pub fn namespace_prefix(namespace: &str) -> Result<String, Error> {
+    let namespace = namespace.trim_matches('/');
+    if namespace.is_empty() {
+        return Ok(String::new());
+    }
+    let segments = namespace.split('/');
+    if segments.clone().any(str::is_empty) || segments.skip(1).any(|segment| segment == CLUSTER_PATH.trim_matches('/')) {
+        return Err(anyhow!("invalid meta namespace {:?}", namespace));
+    }
+    Ok(format!("{}{}", NAMESPACE_PATH, namespace))
+}
+
+/// Strip the prefix of the namespace from the path
+fn strip(prefix: &str, path: &str) -> String {
+    path.strip_prefix(prefix).unwrap_or(path).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::is_conflict;
+    use crate::client::memory::InMemoryMetaClient;
+
+    #[tokio::test]
+    async fn test_namespaced_client() {
+        let client = InMemoryMetaClient::new();
+        let cluster1 = Namespaced::new(client.clone(), "cluster1").unwrap();
+        let cluster2 = Namespaced::new(client.clone(), "/cluster2/").unwrap();
+        assert_eq!(cluster2.prefix(), "/ns/cluster2");
+
+        let mut watcher = cluster1.watch("/nodes/").await.unwrap();
+        cluster1.create("/nodes/1", b"node1").await.unwrap();
+        cluster2.create("/nodes/1", b"node2").await.unwrap();
+        assert_eq!(cluster1.list("/nodes/", true).await.unwrap(), vec!["/nodes/1"]);
+        assert_eq!(cluster2.read("/nodes/1", true).await.unwrap(), b"node2".to_vec());
+        assert_eq!(client.list("/", true).await.unwrap(), vec!["/ns/cluster1/nodes/1", "/ns/cluster2/nodes/1"]);
+
+        let event = watcher.next(Duration::from_millis(10)).await.unwrap().unwrap();
+        assert_eq!((event.key.as_str(), event.value.as_slice()), ("/nodes/1", b"node1".as_slice()));
+        assert!(watcher.next(Duration::from_millis(10)).await.unwrap().is_none());
+
+        let error = cluster1.compare_and_swap("/nodes/1", 0, b"node1").await.unwrap_err();
+        assert!(is_conflict(&error));
+        assert_eq!(error.downcast_ref::<ConflictError>().unwrap().paths(), &["/nodes/1".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn test_namespace_overlap() {
+        assert!(Namespaced::new(InMemoryMetaClient::new(), "team//cluster1").is_err());
+        assert!(Namespaced::new(InMemoryMetaClient::new(), "team/cluster").is_err());
+
+        // None of the namespaces sees the topology of another
+        let client = InMemoryMetaClient::new();
+        let root = Namespaced::new(client.clone(), "").unwrap();
+        let cluster = Namespaced::new(client.clone(), "cluster").unwrap();
+        let nodes = Namespaced::new(client.clone(), "cluster/nodes").unwrap();
+        root.create("/cluster/nodes/1", b"root").await.unwrap();
+        cluster.create("/cluster/nodes/1", b"cluster").await.unwrap();
+        nodes.create("/cluster/nodes/1", b"nodes").await.unwrap();
+
+        let mut watcher = root.watch(CLUSTER_PATH).await.unwrap();
+        cluster.update("/cluster/nodes/1", b"cluster").await.unwrap();
+        nodes.update("/cluster/nodes/1", b"nodes").await.unwrap();
+        assert!(watcher.next(Duration::from_millis(10)).await.unwrap().is_none());
+
+        for (client, value) in [(&root, b"root".as_slice()), (&cluster, b"cluster"), (&nodes, b"nodes")] {
+            assert_eq!(client.list(CLUSTER_PATH, true).await.unwrap(), vec!["/cluster/nodes/1"]);
+            assert_eq!(client.read("/cluster/nodes/1", true).await.unwrap(), value.to_vec());
+        }
+    }
+}
@@ -0,0 +1,1106 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Error};
+use tracing::warn;
+
+use super::blocking::BlockingMetaClient;
+use super::watch::{poll_watcher, ChannelWatcher, Snapshot};
+use super::{is_conflict, is_unavailable, is_unsent, Compare, ConflictError, Txn, TxnOp, UnavailableError};
+
+/// Default timeout for a single redis command
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+/// The hash field to store meta data value
+const VALUE_FIELD: &str = "value";
+/// The hash field to store the revision when the meta data is last modified
+const REVISION_FIELD: &str = "revision";
+/// The hash field to store the attached lease
+const LEASE_FIELD: &str = "lease";
+/// The prefix of the keys for internal use, which are not listed
+const INTERNAL_PREFIX: &str = "__";
+/// The key of the store revision counter, bumped by every mutation
+const REVISION_KEY: &str = "__revision__";
+/// The key of the lease id counter
+const LEASE_ID_KEY: &str = "__lease_id__";
+/// Interval to poll the store revision counter for watchers
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+/// Interval to scan for watchers even if the counter is not bumped, for the keys expired with their leases
+const WATCH_RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+/// Key count hint of a single scan
+const SCAN_COUNT: &str = "128";
+
+/// Redis client
+///
+/// This struct is used to interact with redis server over RESP.
+/// Every meta data path is stored as a redis hash whose key is the path,
+/// so `list` is a prefix scan on the key space. The hash also records the
+/// revision of the last modification, taken from a global counter.
+///
+/// A lease is a key expiring with the ttl, the keys attached to it share the
+/// expiration and are tracked in a set, which is refreshed by keepalive.
+#[derive(Debug)]
+pub struct RedisClient {
+    /// The redis endpoints, e.g. `127.0.0.1:6379` or `redis://127.0.0.1:6379`
+    endpoints: Vec<String>,
+    /// Index of the endpoint used by the cached connection
+    current: AtomicUsize,
+    /// Cached connection, reconnect when it's broken
+    connection: Mutex<Option<Connection>>,
+    /// Timeout for connecting, reading and writing a single command
+    timeout: Duration,
+    /// The client is closed or not
+    closed: AtomicBool,
+    /// The optional user name and the password, None if auth is disabled
+    auth: Option<(Option<String>, String)>,
+}
+
+impl RedisClient {
+    /// Create a new redis client
+    pub fn new(endpoints: Vec<String>) -> Self {
+        Self {
+            endpoints,
+            current: AtomicUsize::new(0),
+            connection: Mutex::new(None),
+            timeout: DEFAULT_TIMEOUT,
+            closed: AtomicBool::new(false),
+            auth: None,
+        }
+    }
+
+    /// Authenticate new connections with the password, and the user name for redis ACL
+    pub fn with_auth(mut self, username: Option<&str>, password: &str) -> Self {
+        self.auth = Some((username.map(str::to_owned), password.to_owned()));
+        self
+    }
+
+    /// Set the timeout of a single command
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Get the endpoints
+    pub fn endpoints(&self) -> &Vec<String> {
+        &self.endpoints
+    }
+
+    /// Create a client of the same endpoints, timeout and auth, with its own connection
+    fn clone_config(&self) -> Self {
+        Self {
+            auth: self.auth.clone(),
+            ..Self::new(self.endpoints.clone()).with_timeout(self.timeout)
+        }
+    }
+
+    /// Send a command
+    fn command(&self, args: &[&[u8]]) -> Result<Reply, Error> {
+        self.with_connection(|conn| conn.command(args)?.into_result())
+    }
+
+    /// Run `f` on the connection, reconnect to the next endpoint if the connection is broken
+    ///
+    /// `f` may send several commands, it's retried on a new connection only
+    /// when it fails with io error, so it should be idempotent. If it fails
+    /// with `UnavailableError` the commands may be applied, so it's returned
+    /// without retry, and the connection in unknown state is dropped.
+    fn with_connection<T>(&self, mut f: impl FnMut(&mut Connection) -> Result<T, Error>) -> Result<T, Error> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(anyhow!("redis client is closed"));
+        }
+        if self.endpoints.is_empty() {
+            return Err(anyhow!("no redis endpoint is configured"));
+        }
+
+        let mut connection = self.connection.lock().unwrap();
+        let mut last_error: Option<Error> = None;
+        // Try the cached connection first
+        if let Some(conn) = connection.as_mut() {
+            match f(conn) {
+                Err(e) if is_broken(&e) => {
+                    warn!("redis connection is broken: {:?}", e);
+                    *connection = None;
+                    last_error = Some(e);
+                }
+                Err(e) if is_unavailable(&e) => {
+                    *connection = None;
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+
+        let start = self.current.load(Ordering::Relaxed);
+        for offset in 0..self.endpoints.len() {
+            let index = (start + offset) % self.endpoints.len();
+            let endpoint = &self.endpoints[index];
+
+            let result = Connection::connect(endpoint, self.timeout).and_then(|mut conn| {
+                if let Some((ref username, ref password)) = self.auth {
+                    conn.auth(username.as_deref(), password)?;
+                }
+                let result = f(&mut conn);
+                match result {
+                    Err(e) if is_broken(&e) => Err(e),
+                    result => Ok((conn, result)),
+                }
+            });
+            match result {
+                Ok((conn, result)) => {
+                    self.current.store(index, Ordering::Relaxed);
+                    if !result.as_ref().is_err_and(is_unavailable) {
+                        *connection = Some(conn);
+                    }
+                    return result;
+                }
+                Err(e) => {
+                    warn!("redis endpoint {} request failed: {:?}", endpoint, e);
+                    // Keep the error of a request which may be sent, so the call is not taken as unsent
+                    if last_error.as_ref().is_none_or(is_unsent) {
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("no redis endpoint is available")))
+    }
+
+    /// Take a snapshot of the meta data under the path
+    fn snapshot(&self, path: &str) -> Result<Snapshot, Error> {
+        let mut kvs = BTreeMap::new();
+        for key in self.list(path, false)? {
+            let reply = self.command(&[b"HMGET", key.as_bytes(), REVISION_FIELD.as_bytes(), VALUE_FIELD.as_bytes()])?;
+            match reply {
+                Reply::Array(Some(fields)) => match fields.as_slice() {
+                    [revision, Reply::Bulk(Some(value))] => {
+                        kvs.insert(key, (parse_revision(revision)?, value.clone()));
+                    }
+                    // Deleted after listed
+                    [_, Reply::Bulk(None)] => {}
+                    _ => return Err(anyhow!("unexpected redis hmget reply: {:?}", fields)),
+                },
+                reply => return Err(anyhow!("unexpected redis hmget reply: {:?}", reply)),
+            }
+        }
+
+        // Read the revision at last, so the deleted keys are not newer than it
+        Ok((self.revision()?, kvs))
+    }
+
+    /// Get the store revision counter
+    fn revision(&self) -> Result<u64, Error> {
+        parse_revision(&self.command(&[b"GET", REVISION_KEY.as_bytes()])?)
+    }
+}
+
+impl BlockingMetaClient for RedisClient {
+    fn from_endpoints(endpoints: Vec<String>) -> Result<Self, Error> {
+        Ok(Self::new(endpoints))
+    }
+
+    fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        match self.compare_and_swap(path, 0, data) {
+            Err(e) if is_conflict(&e) => Err(anyhow!("meta data {} already exists", path)),
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        self.with_connection(|conn| {
+            let revision = conn.next_revision()?;
+            conn.put(path, data, revision, None)
+        })?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), Error> {
+        self.with_connection(|conn| {
+            conn.next_revision()?;
+            conn.command(&[b"DEL", path.as_bytes()])?.into_result()
+        })?;
+        Ok(())
+    }
+
+    fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+        match self.command(&[b"HGET", path.as_bytes(), VALUE_FIELD.as_bytes()])? {
+            Reply::Bulk(Some(data)) => Ok(data),
+            Reply::Bulk(None) if must => Err(anyhow!("meta data {} not found", path)),
+            Reply::Bulk(None) => Ok(Vec::new()),
+            reply => Err(anyhow!("unexpected redis reply: {:?}", reply)),
+        }
+    }
+
+    fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+        let pattern = format!("{}*", escape_pattern(path));
+        let mut cursor = b"0".to_vec();
+        let mut keys = Vec::new();
+        loop {
+            let reply = self.command(&[b"SCAN", &cursor, b"MATCH", pattern.as_bytes(), b"COUNT", SCAN_COUNT.as_bytes()])?;
+            let (next, batch) = match reply {
+                Reply::Array(Some(mut items)) if items.len() == 2 => {
+                    let batch = items.pop().unwrap_or(Reply::Array(None));
+                    let next = items.pop().unwrap_or(Reply::Bulk(None));
+                    (next, batch)
+                }
+                reply => return Err(anyhow!("unexpected redis scan reply: {:?}", reply)),
+            };
+
+            if let Reply::Array(Some(batch)) = batch {
+                for key in batch {
+                    if let Reply::Bulk(Some(key)) = key {
+                        if key.starts_with(INTERNAL_PREFIX.as_bytes()) {
+                            continue;
+                        }
+                        keys.push(String::from_utf8(key).context("redis key is not valid utf-8")?);
+                    }
+                }
+            }
+
+            cursor = match next {
+                Reply::Bulk(Some(next)) => next,
+                reply => return Err(anyhow!("unexpected redis scan cursor: {:?}", reply)),
+            };
+            if cursor == b"0" {
+                break;
+            }
+        }
+
+        // Scan may return duplicated keys in different order
+        keys.sort();
+        keys.dedup();
+        if keys.is_empty() && must {
+            return Err(anyhow!("meta data under {} not found", path));
+        }
+
+        Ok(keys)
+    }
+
+    fn close(&self) -> Result<(), Error> {
+        self.closed.store(true, Ordering::Release);
+        *self.connection.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn watch(&self, path: &str) -> Result<ChannelWatcher, Error> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(anyhow!("redis client is closed"));
+        }
+
+        // Redis has no revisioned change feed, poll with a dedicated connection
+        let client = self.clone_config();
+        let path = path.to_owned();
+        let mut scanned: Option<(u64, Instant)> = None;
+        poll_watcher(WATCH_INTERVAL, move || {
+            // Read before the scan, so the changes during the scan bump it again
+            let revision = client.revision()?;
+            if let Some((last, at)) = scanned {
+                if last == revision && at.elapsed() < WATCH_RESCAN_INTERVAL {
+                    return Ok(None);
+                }
+            }
+            let snapshot = client.snapshot(&path)?;
+            scanned = Some((revision, Instant::now()));
+            Ok(Some(snapshot))
+        })
+    }
+
+    fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        match self.command(&[b"HMGET", path.as_bytes(), VALUE_FIELD.as_bytes(), REVISION_FIELD.as_bytes()])? {
+            Reply::Array(Some(fields)) => match fields.as_slice() {
+                [Reply::Bulk(Some(value)), revision] => Ok(Some((value.clone(), parse_revision(revision)?))),
+                [Reply::Bulk(None), _] => Ok(None),
+                _ => Err(anyhow!("unexpected redis hmget reply: {:?}", fields)),
+            },
+            reply => Err(anyhow!("unexpected redis hmget reply: {:?}", reply)),
+        }
+    }
+
+    fn txn(&self, txn: Txn) -> Result<u64, Error> {
+        let committed = self.with_connection(|conn| {
+            // The transaction is aborted if any compared path is modified by others after `WATCH`
+            let mut conflicts = Vec::new();
+            for compare in &txn.compares {
+                let Compare::ModRevision(ref path, revision) = *compare;
+                conn.command(&[b"WATCH", path.as_bytes()])?.into_result()?;
+                let current = conn.command(&[b"HGET", path.as_bytes(), REVISION_FIELD.as_bytes()])?.into_result()?;
+                if parse_revision(&current)? != revision {
+                    conflicts.push(path.clone());
+                }
+            }
+            if !conflicts.is_empty() || txn.ops.is_empty() {
+                conn.command(&[b"UNWATCH"])?.into_result()?;
+                if !conflicts.is_empty() {
+                    return Ok(Err(ConflictError::new(conflicts)));
+                }
+                let revision = conn.command(&[b"GET", REVISION_KEY.as_bytes()])?.into_result()?;
+                return Ok(Ok(parse_revision(&revision)?));
+            }
+
+            let mut ttls = BTreeMap::new();
+            for op in &txn.ops {
+                if let TxnOp::Put(_, _, lease) = *op {
+                    if lease != 0 && !ttls.contains_key(&lease) {
+                        ttls.insert(lease, conn.lease_ttl(lease)?);
+                    }
+                }
+            }
+
+            let revision = conn.next_revision()?;
+            conn.command(&[b"MULTI"])?.into_result()?;
+            for op in &txn.ops {
+                match *op {
+                    TxnOp::Put(ref path, ref data, lease) => {
+                        conn.put(path, data, revision, ttls.get(&lease).map(|&ttl| (lease, ttl)))?;
+                    }
+                    TxnOp::Delete(ref path) => {
+                        conn.command(&[b"DEL", path.as_bytes()])?.into_result()?;
+                    }
+                }
+            }
+            // It may be applied once `EXEC` is sent, so it must not be retried on a new connection
+            let reply = conn.command(&[b"EXEC"]).map_err(|e| match e.downcast::<io::Error>() {
+                Ok(e) => UnavailableError::new(format!("redis exec failed: {}", e)).into(),
+                Err(e) => e,
+            })?;
+            if reply.into_result()? == Reply::Array(None) {
+                let paths = txn.compares.iter().map(|compare| compare.path().to_owned()).collect();
+                return Ok(Err(ConflictError::new(paths)));
+            }
+            Ok(Ok(revision))
+        })?;
+
+        Ok(committed?)
+    }
+
+    fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+        let ttl = u64::try_from(ttl.as_millis())?.max(1).to_string();
+        self.with_connection(|conn| {
+            let lease = match conn.command(&[b"INCR", LEASE_ID_KEY.as_bytes()])?.into_result()? {
+                Reply::Integer(lease) => u64::try_from(lease)?,
+                reply => return Err(anyhow!("unexpected redis incr reply: {:?}", reply)),
+            };
+            conn.command(&[b"SET", lease_key(lease).as_bytes(), ttl.as_bytes(), b"PX", ttl.as_bytes()])?
+                .into_result()?;
+            Ok(lease)
+        })
+    }
+
+    fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+        self.with_connection(|conn| {
+            let ttl = conn.lease_ttl(lease)?.to_string();
+            if conn.command(&[b"PEXPIRE", lease_key(lease).as_bytes(), ttl.as_bytes()])?.into_result()? != Reply::Integer(1) {
+                return Err(anyhow!("redis lease {} is expired", lease));
+            }
+
+            let keys = lease_keys(lease);
+            for key in conn.lease_members(lease)? {
+                if conn.lease_of(&key)? == lease {
+                    conn.command(&[b"PEXPIRE", &key, ttl.as_bytes()])?.into_result()?;
+                } else {
+                    conn.command(&[b"SREM", keys.as_bytes(), &key])?.into_result()?;
+                }
+            }
+            conn.command(&[b"PEXPIRE", keys.as_bytes(), ttl.as_bytes()])?.into_result()?;
+            Ok(())
+        })
+    }
+
+    fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+        self.with_connection(|conn| {
+            conn.command(&[b"DEL", lease_key(lease).as_bytes()])?.into_result()?;
+            let mut attached = Vec::new();
+            for key in conn.lease_members(lease)? {
+                if conn.lease_of(&key)? == lease {
+                    attached.push(key);
+                }
+            }
+            if !attached.is_empty() {
+                conn.next_revision()?;
+                for key in &attached {
+                    conn.command(&[b"DEL", key])?.into_result()?;
+                }
+            }
+            conn.command(&[b"DEL", lease_keys(lease).as_bytes()])?.into_result()?;
+            Ok(())
+        })
+    }
+}
+
+/// The key of a lease, the value is the ttl in milliseconds
+fn lease_key(lease: u64) -> String {
+    format!("{}lease__:{}", INTERNAL_PREFIX, lease)
+}
+
+/// The key of the set of the keys attached to a lease
+fn lease_keys(lease: u64) -> String {
+    format!("{}lease_keys__:{}", INTERNAL_PREFIX, lease)
+}
+
+/// The connection should be dropped or not after the error
+fn is_broken(error: &Error) -> bool {
+    error.downcast_ref::<io::Error>().is_some()
+}
+
+/// Parse a revision or other integer reply, nil means zero
+fn parse_revision(reply: &Reply) -> Result<u64, Error> {
+    match *reply {
+        Reply::Bulk(None) => Ok(0),
+        Reply::Bulk(Some(ref revision)) => std::str::from_utf8(revision)?.parse().context("invalid redis revision"),
+        Reply::Integer(revision) => Ok(u64::try_from(revision)?),
+        ref reply => Err(anyhow!("unexpected redis revision: {:?}", reply)),
+    }
+}
+
+/// Escape the glob special chars of a redis `MATCH` pattern
+fn escape_pattern(path: &str) -> String {
+    let mut pattern = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+/// RESP reply
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    /// Simple string, e.g. `+OK`
+    Status(String),
+    /// Error, e.g. `-ERR unknown command`
+    Error(String),
+    /// Integer, e.g. `:1`
+    Integer(i64),
+    /// Bulk string, None means nil
+    Bulk(Option<Vec<u8>>),
+    /// Array, None means nil
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    /// Convert the error reply to error
+    fn into_result(self) -> Result<Reply, Error> {
+        match self {
+            Reply::Error(e) => Err(anyhow!("redis error: {}", e)),
+            reply => Ok(reply),
+        }
+    }
+}
+
+/// A RESP connection to redis server
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    /// Connect to the endpoint
+    fn connect(endpoint: &str, timeout: Duration) -> Result<Self, Error> {
+        let host = endpoint.trim_start_matches("redis://").trim_end_matches('/');
+        let addr = host
+            .to_socket_addrs()
+            .with_context(|| format!("invalid redis endpoint {}", endpoint))?
+            .next()
+            .ok_or_else(|| anyhow!("redis endpoint {} is not resolved", endpoint))?;
+
+        let stream = TcpStream::connect_timeout(&addr, timeout)
+            .map_err(|e| UnavailableError::unsent(format!("failed to connect to redis endpoint {}: {}", endpoint, e)))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// Authenticate the connection
+    fn auth(&mut self, username: Option<&str>, password: &str) -> Result<(), Error> {
+        let mut args: Vec<&[u8]> = vec![b"AUTH"];
+        args.extend(username.map(str::as_bytes));
+        args.push(password.as_bytes());
+        self.command(&args)?.into_result()?;
+        Ok(())
+    }
+
+    /// Send a command and read its reply
+    fn command(&mut self, args: &[&[u8]]) -> Result<Reply, Error> {
+        self.writer.write_all(&encode_command(args))?;
+        self.writer.flush()?;
+        read_reply(&mut self.reader)
+    }
+
+    /// Bump the store revision counter, return the new revision
+    fn next_revision(&mut self) -> Result<u64, Error> {
+        match self.command(&[b"INCR", REVISION_KEY.as_bytes()])?.into_result()? {
+            Reply::Integer(revision) => Ok(u64::try_from(revision)?),
+            reply => Err(anyhow!("unexpected redis incr reply: {:?}", reply)),
+        }
+    }
+
+    /// Put the value of a path with its mod revision, and attach it to the lease with its ttl
+    fn put(&mut self, path: &str, data: &[u8], revision: u64, lease: Option<(u64, u64)>) -> Result<(), Error> {
+        let revision = revision.to_string();
+        let (id, ttl) = lease.unwrap_or((0, 0));
+        let (id, ttl) = (id.to_string(), ttl.to_string());
+        self.command(&[
+            b"HSET",
+            path.as_bytes(),
+            VALUE_FIELD.as_bytes(),
+            data,
+            REVISION_FIELD.as_bytes(),
+            revision.as_bytes(),
+            LEASE_FIELD.as_bytes(),
+            id.as_bytes(),
+        ])?
+        .into_result()?;
+
+        match lease {
+            Some((lease, _)) => {
+                let keys = lease_keys(lease);
+                self.command(&[b"PEXPIRE", path.as_bytes(), ttl.as_bytes()])?.into_result()?;
+                self.command(&[b"SADD", keys.as_bytes(), path.as_bytes()])?.into_result()?;
+                self.command(&[b"PEXPIRE", keys.as_bytes(), ttl.as_bytes()])?.into_result()?;
+            }
+            None => {
+                self.command(&[b"PERSIST", path.as_bytes()])?.into_result()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the ttl of an alive lease in milliseconds
+    fn lease_ttl(&mut self, lease: u64) -> Result<u64, Error> {
+        match self.command(&[b"GET", lease_key(lease).as_bytes()])?.into_result()? {
+            Reply::Bulk(None) => Err(anyhow!("redis lease {} not found", lease)),
+            reply => parse_revision(&reply),
+        }
+    }
+
+    /// Get the keys ever attached to the lease
+    fn lease_members(&mut self, lease: u64) -> Result<Vec<Vec<u8>>, Error> {
+        match self.command(&[b"SMEMBERS", lease_keys(lease).as_bytes()])?.into_result()? {
+            Reply::Array(Some(members)) => Ok(members
+                .into_iter()
+                .filter_map(|member| match member {
+                    Reply::Bulk(Some(member)) => Some(member),
+                    _ => None,
+                })
+                .collect()),
+            reply => Err(anyhow!("unexpected redis smembers reply: {:?}", reply)),
+        }
+    }
+
+    /// Get the lease the key is attached to now, 0 means no lease
+    fn lease_of(&mut self, key: &[u8]) -> Result<u64, Error> {
+        let reply = self.command(&[b"HGET", key, LEASE_FIELD.as_bytes()])?.into_result()?;
+        parse_revision(&reply)
+    }
+}
+
+/// Encode a command as RESP array of bulk strings
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+/// Read a RESP reply
+fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply, Error> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "redis connection closed").into());
+    }
+    let line = line.trim_end_matches("\r\n");
+    let (kind, content) = line.split_at(line.chars().next().map_or(0, char::len_utf8));
+
+    match kind {
+        "+" => Ok(Reply::Status(content.to_owned())),
+        "-" => Ok(Reply::Error(content.to_owned())),
+        ":" => Ok(Reply::Integer(content.parse().context("invalid redis integer")?)),
+        "$" => {
+            let len: i64 = content.parse().context("invalid redis bulk length")?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut data = vec![0; usize::try_from(len)? + 2];
+            reader.read_exact(&mut data)?;
+            data.truncate(data.len() - 2);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        "*" => {
+            let len: i64 = content.parse().context("invalid redis array length")?;
+            if len < 0 {
+                return Ok(Reply::Array(None));
+            }
+            let items = (0..len)
+                .map(|_| read_reply(reader))
+                .collect::<Result<Vec<Reply>, Error>>()?;
+            Ok(Reply::Array(Some(items)))
+        }
+        _ => Err(anyhow!("invalid redis reply: {:?}", line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+    use std::time::Instant;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::client::{EventType, WatchEvent, Watcher};
+
+    /// The data of the mock redis
+    #[derive(Default)]
+    struct MockData {
+        hashes: BTreeMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>,
+        strings: BTreeMap<Vec<u8>, Vec<u8>>,
+        sets: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+        expires: HashMap<Vec<u8>, Instant>,
+        /// Bumped when a key is modified, for `WATCH`
+        versions: HashMap<Vec<u8>, u64>,
+        version: u64,
+    }
+
+    impl MockData {
+        fn touch(&mut self, key: &[u8]) {
+            self.version += 1;
+            self.versions.insert(key.to_vec(), self.version);
+        }
+
+        fn exists(&self, key: &[u8]) -> bool {
+            self.hashes.contains_key(key) || self.strings.contains_key(key) || self.sets.contains_key(key)
+        }
+
+        fn remove(&mut self, key: &[u8]) -> bool {
+            self.expires.remove(key);
+            let existed = self.hashes.remove(key).is_some() | self.strings.remove(key).is_some() | self.sets.remove(key).is_some();
+            if existed {
+                self.touch(key);
+            }
+            existed
+        }
+
+        fn expire(&mut self, key: &[u8], millis: &[u8]) -> bool {
+            if !self.exists(key) {
+                return false;
+            }
+            let millis = String::from_utf8_lossy(millis).parse().unwrap();
+            self.expires.insert(key.to_vec(), Instant::now() + Duration::from_millis(millis));
+            true
+        }
+
+        /// Remove the expired keys
+        fn purge(&mut self) {
+            let now = Instant::now();
+            let expired: Vec<Vec<u8>> = self.expires.iter().filter(|(_, &at)| at <= now).map(|(key, _)| key.clone()).collect();
+            for key in expired {
+                self.remove(&key);
+            }
+        }
+    }
+
+    /// The transaction state of a mock connection
+    #[derive(Default)]
+    struct Session {
+        watched: Vec<(Vec<u8>, Option<u64>)>,
+        queued: Option<Vec<Vec<Vec<u8>>>>,
+        authenticated: bool,
+    }
+
+    /// A tiny in-process RESP stand-in of redis server
+    ///
+    /// It only supports the subset of commands used by `RedisClient`.
+    #[derive(Default)]
+    struct MockRedis {
+        data: Mutex<MockData>,
+        /// The password required by `AUTH`, None if auth is disabled
+        password: Option<String>,
+        /// The `SCAN` commands served
+        scans: AtomicUsize,
+        /// Close the connection after the next `EXEC` is applied, before replying
+        drop_exec: AtomicBool,
+    }
+
+    impl MockRedis {
+        /// Start the mock server, return its endpoint
+        fn start() -> String {
+            Self::serve_on(MockRedis::default()).0
+        }
+
+        /// Start the mock server, return its endpoint and itself
+        fn start_mock() -> (String, Arc<MockRedis>) {
+            Self::serve_on(MockRedis::default())
+        }
+
+        /// Start the mock server requiring the password, return its endpoint
+        fn start_with_password(password: &str) -> String {
+            Self::serve_on(MockRedis {
+                password: Some(password.to_owned()),
+                ..MockRedis::default()
+            })
+            .0
+        }
+
+        fn serve_on(mock: MockRedis) -> (String, Arc<MockRedis>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("redis://{}", listener.local_addr().unwrap());
+            let mock = Arc::new(mock);
+
+            let server = Arc::clone(&mock);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    let mock = Arc::clone(&server);
+                    thread::spawn(move || mock.serve(stream));
+                }
+            });
+
+            (endpoint, mock)
+        }
+
+        fn serve(&self, mut stream: TcpStream) {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut session = Session::default();
+            while let Ok(Reply::Array(Some(args))) = read_reply(&mut reader) {
+                let args: Vec<Vec<u8>> = args
+                    .into_iter()
+                    .map(|arg| match arg {
+                        Reply::Bulk(Some(arg)) => arg,
+                        _ => Vec::new(),
+                    })
+                    .collect();
+                let exec = args[0] == b"EXEC";
+                let reply = self.handle(&mut session, args);
+                if exec && self.drop_exec.swap(false, Ordering::Relaxed) {
+                    return;
+                }
+                if stream.write_all(&encode_reply(&reply)).is_err() {
+                    return;
+                }
+            }
+        }
+
+        fn handle(&self, session: &mut Session, args: Vec<Vec<u8>>) -> Reply {
+            if let Some(ref password) = self.password {
+                if args[0] == b"AUTH" {
+                    session.authenticated = args.last().map(Vec::as_slice) == Some(password.as_bytes());
+                    return if session.authenticated {
+                        Reply::Status("OK".to_owned())
+                    } else {
+                        Reply::Error("WRONGPASS invalid username-password pair".to_owned())
+                    };
+                }
+                if !session.authenticated {
+                    return Reply::Error("NOAUTH Authentication required.".to_owned());
+                }
+            }
+
+            let mut data = self.data.lock().unwrap();
+            data.purge();
+            match args[0].as_slice() {
+                b"WATCH" => {
+                    let version = data.versions.get(&args[1]).copied();
+                    session.watched.push((args[1].clone(), version));
+                    Reply::Status("OK".to_owned())
+                }
+                b"UNWATCH" => {
+                    session.watched.clear();
+                    Reply::Status("OK".to_owned())
+                }
+                b"MULTI" => {
+                    session.queued = Some(Vec::new());
+                    Reply::Status("OK".to_owned())
+                }
+                b"EXEC" => {
+                    let queued = session.queued.take().unwrap_or_default();
+                    let watched = std::mem::take(&mut session.watched);
+                    if watched.iter().any(|(key, version)| data.versions.get(key).copied() != *version) {
+                        return Reply::Array(None);
+                    }
+                    Reply::Array(Some(queued.iter().map(|args| self.execute(&mut data, args)).collect()))
+                }
+                _ => match session.queued {
+                    Some(ref mut queued) => {
+                        queued.push(args);
+                        Reply::Status("QUEUED".to_owned())
+                    }
+                    None => self.execute(&mut data, &args),
+                },
+            }
+        }
+
+        fn execute(&self, data: &mut MockData, args: &[Vec<u8>]) -> Reply {
+            match args[0].as_slice() {
+                b"PING" => Reply::Status("PONG".to_owned()),
+                b"HSET" => {
+                    let hash = data.hashes.entry(args[1].clone()).or_default();
+                    let added = args[2..].chunks(2).filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none()).count();
+                    data.touch(&args[1]);
+                    Reply::Integer(i64::try_from(added).unwrap())
+                }
+                b"HGET" => Reply::Bulk(data.hashes.get(&args[1]).and_then(|hash| hash.get(&args[2])).cloned()),
+                b"HMGET" => {
+                    let hash = data.hashes.get(&args[1]);
+                    let fields = args[2..].iter().map(|field| Reply::Bulk(hash.and_then(|hash| hash.get(field)).cloned()));
+                    Reply::Array(Some(fields.collect()))
+                }
+                b"INCR" => {
+                    let value = data.strings.get(&args[1]).map_or(0, |value| String::from_utf8_lossy(value).parse().unwrap()) + 1;
+                    data.strings.insert(args[1].clone(), value.to_string().into_bytes());
+                    data.touch(&args[1]);
+                    Reply::Integer(value)
+                }
+                b"GET" => Reply::Bulk(data.strings.get(&args[1]).cloned()),
+                b"SET" => {
+                    data.remove(&args[1]);
+                    data.strings.insert(args[1].clone(), args[2].clone());
+                    if args.get(3).map(Vec::as_slice) == Some(b"PX") {
+                        data.expire(&args[1], &args[4]);
+                    }
+                    Reply::Status("OK".to_owned())
+                }
+                b"PEXPIRE" => Reply::Integer(i64::from(data.expire(&args[1], &args[2]))),
+                b"PERSIST" => Reply::Integer(i64::from(data.expires.remove(&args[1]).is_some())),
+                b"SADD" => Reply::Integer(i64::from(data.sets.entry(args[1].clone()).or_default().insert(args[2].clone()))),
+                b"SREM" => Reply::Integer(i64::from(data.sets.get_mut(&args[1]).is_some_and(|set| set.remove(&args[2])))),
+                b"SMEMBERS" => {
+                    let members = data.sets.get(&args[1]).into_iter().flatten().map(|member| Reply::Bulk(Some(member.clone())));
+                    Reply::Array(Some(members.collect()))
+                }
+                b"EXISTS" => Reply::Integer(i64::from(data.exists(&args[1]))),
+                b"DEL" => Reply::Integer(i64::from(data.remove(&args[1]))),
+                b"SCAN" => {
+                    self.scans.fetch_add(1, Ordering::Relaxed);
+                    // Only prefix patterns are supported, return all keys at once
+                    let pattern = String::from_utf8(args[3].clone()).unwrap();
+                    let prefix = pattern.trim_end_matches('*').replace('\\', "");
+                    let keys = data
+                        .hashes
+                        .keys()
+                        .chain(data.strings.keys())
+                        .chain(data.sets.keys())
+                        .filter(|key| key.starts_with(prefix.as_bytes()))
+                        .map(|key| Reply::Bulk(Some(key.clone())))
+                        .collect();
+                    Reply::Array(Some(vec![Reply::Bulk(Some(b"0".to_vec())), Reply::Array(Some(keys))]))
+                }
+                _ => Reply::Error("ERR unknown command".to_owned()),
+            }
+        }
+    }
+
+    /// Encode a reply as RESP
+    fn encode_reply(reply: &Reply) -> Vec<u8> {
+        match reply {
+            Reply::Status(s) => format!("+{}\r\n", s).into_bytes(),
+            Reply::Error(e) => format!("-{}\r\n", e).into_bytes(),
+            Reply::Integer(i) => format!(":{}\r\n", i).into_bytes(),
+            Reply::Bulk(None) => b"$-1\r\n".to_vec(),
+            Reply::Bulk(Some(data)) => encode_command(&[data]).split_off(4),
+            Reply::Array(None) => b"*-1\r\n".to_vec(),
+            Reply::Array(Some(items)) => {
+                let mut buf = format!("*{}\r\n", items.len()).into_bytes();
+                for item in items {
+                    buf.extend(encode_reply(item));
+                }
+                buf
+            }
+        }
+    }
+
+    #[test]
+    fn test_escape_pattern() {
+        assert_eq!(escape_pattern("/nodes/"), "/nodes/");
+        assert_eq!(escape_pattern("/a*b?[c]\\"), "/a\\*b\\?\\[c\\]\\\\");
+    }
+
+    #[test]
+    fn test_resp() {
+        let mut reader = BufReader::new(&b"*3\r\n$3\r\nfoo\r\n$-1\r\n:42\r\n"[..]);
+        assert_eq!(
+            read_reply(&mut reader).unwrap(),
+            Reply::Array(Some(vec![Reply::Bulk(Some(b"foo".to_vec())), Reply::Bulk(None), Reply::Integer(42)]))
+        );
+        assert_eq!(encode_command(&[b"GET", b"k"]), b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_redis_client() {
+        let client = RedisClient::new(vec![MockRedis::start()]);
+
+        client.create("/nodes/1", b"node1").unwrap();
+        assert!(client.create("/nodes/1", b"node1").is_err());
+        client.create("/nodes/2", b"node2").unwrap();
+        client.update("/slots", b"slots").unwrap();
+
+        assert_eq!(client.read("/nodes/1", true).unwrap(), b"node1".to_vec());
+        assert!(client.read("/nodes/3", true).is_err());
+        assert!(client.read("/nodes/3", false).unwrap().is_empty());
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/1", "/nodes/2"]);
+
+        client.delete("/nodes/1").unwrap();
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/2"]);
+        assert!(client.list("/missing/", true).is_err());
+        assert!(client.list("/missing/", false).unwrap().is_empty());
+
+        client.close().unwrap();
+        assert!(client.read("/slots", false).is_err());
+    }
+
+    #[test]
+    fn test_redis_client_failover() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let client = RedisClient::from_endpoints(vec![dead, MockRedis::start()]).unwrap();
+        client.update("/slots", b"slots").unwrap();
+        assert_eq!(client.current.load(Ordering::Relaxed), 1);
+        assert_eq!(client.read("/slots", true).unwrap(), b"slots".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_redis_client_auth() {
+        let endpoint = MockRedis::start_with_password("secret");
+        assert!(RedisClient::new(vec![endpoint.clone()]).read("/slots", false).is_err());
+        assert!(RedisClient::new(vec![endpoint.clone()]).with_auth(None, "wrong").read("/slots", false).is_err());
+
+        let client = RedisClient::new(vec![endpoint]).with_auth(Some("default"), "secret");
+        client.update("/slots", b"slots").unwrap();
+        assert_eq!(client.read("/slots", true).unwrap(), b"slots".to_vec());
+
+        // The watcher polls with the same auth
+        let mut watcher = client.watch("/nodes/").unwrap();
+        client.update("/nodes/1", b"node1").unwrap();
+        let event = watcher.next(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(event, Some(WatchEvent::put("/nodes/1".to_owned(), b"node1".to_vec(), 2)));
+    }
+
+    #[test]
+    fn test_redis_client_revision() {
+        let client = RedisClient::new(vec![MockRedis::start()]);
+        client.create("/nodes/1", b"node1").unwrap();
+        client.update("/nodes/1", b"node1").unwrap();
+        client.delete("/nodes/2").unwrap();
+        client.update("/nodes/2", b"node2").unwrap();
+
+        let (revision, kvs) = client.snapshot("").unwrap();
+        assert_eq!(revision, 4);
+        assert_eq!(kvs.get("/nodes/1"), Some(&(2, b"node1".to_vec())));
+        assert_eq!(kvs.get("/nodes/2"), Some(&(4, b"node2".to_vec())));
+        assert!(!kvs.contains_key(REVISION_KEY));
+    }
+
+    #[tokio::test]
+    async fn test_redis_client_watch() {
+        let client = RedisClient::new(vec![MockRedis::start()]);
+        client.update("/nodes/1", b"node1").unwrap();
+
+        let mut watcher = client.watch("/nodes/").unwrap();
+        client.update("/slots", b"slots").unwrap();
+        client.update("/nodes/2", b"node2").unwrap();
+        client.delete("/nodes/1").unwrap();
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(watcher.next(timeout).await.unwrap(), Some(WatchEvent::put("/nodes/2".to_owned(), b"node2".to_vec(), 3)));
+        let event = watcher.next(timeout).await.unwrap().unwrap();
+        assert_eq!((event.event_type, event.key.as_str(), event.revision), (EventType::Delete, "/nodes/1", 4));
+    }
+
+    #[tokio::test]
+    async fn test_redis_client_watch_idle() {
+        let (endpoint, mock) = MockRedis::start_mock();
+        let client = RedisClient::new(vec![endpoint]);
+        let lease = client.grant_lease(Duration::from_millis(300)).unwrap();
+        client.txn(Txn::new().put_with_lease("/nodes/1", b"node1", lease)).unwrap();
+
+        // Only the revision counter is polled while nothing changes
+        let mut watcher = client.watch("/nodes/").unwrap();
+        let scans = mock.scans.load(Ordering::Relaxed);
+        assert!(watcher.next(Duration::from_millis(50)).await.unwrap().is_none());
+        client.update("/nodes/2", b"node2").unwrap();
+        let event = watcher.next(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(event.key, "/nodes/2");
+        assert_eq!(mock.scans.load(Ordering::Relaxed), scans + 1);
+
+        // The key expired with its lease does not bump the counter, but it's scanned now and then
+        let event = watcher.next(WATCH_RESCAN_INTERVAL * 2).await.unwrap().unwrap();
+        assert_eq!((event.event_type, event.key.as_str()), (EventType::Delete, "/nodes/1"));
+    }
+
+    #[test]
+    fn test_redis_client_txn() {
+        let client = RedisClient::new(vec![MockRedis::start()]);
+        let revision = client.compare_and_swap("/slots", 0, b"v1").unwrap();
+        assert_eq!(client.read_revision("/slots").unwrap(), Some((b"v1".to_vec(), revision)));
+        assert!(client.read_revision("/missing").unwrap().is_none());
+        client.update("/nodes/1", b"node1").unwrap();
+
+        let txn = Txn::new()
+            .when(Compare::ModRevision("/slots".to_owned(), revision))
+            .when(Compare::ModRevision("/ring_version".to_owned(), 0))
+            .put("/slots", b"v2")
+            .put("/ring_version", b"1")
+            .delete("/nodes/1");
+        let committed = client.txn(txn.clone()).unwrap();
+        assert_eq!(client.read_revision("/ring_version").unwrap(), Some((b"1".to_vec(), committed)));
+        assert!(client.list("/nodes/", false).unwrap().is_empty());
+
+        let error = client.txn(txn).unwrap_err();
+        assert_eq!(error.downcast_ref::<ConflictError>().unwrap().paths(), &["/slots".to_owned(), "/ring_version".to_owned()]);
+        assert_eq!(client.read_revision("/slots").unwrap(), Some((b"v2".to_vec(), committed)));
+    }
+
+    #[test]
+    fn test_redis_client_txn_exec_failure() {
+        let (endpoint, mock) = MockRedis::start_mock();
+        let client = RedisClient::new(vec![endpoint]);
+        let revision = client.compare_and_swap("/slots", 0, b"v1").unwrap();
+
+        // Applied but not replied, it's unknown to the client, rather than a conflict on a new connection
+        mock.drop_exec.store(true, Ordering::Relaxed);
+        let error = client.compare_and_swap("/slots", revision, b"v2").unwrap_err();
+        assert!(is_unavailable(&error) && !is_unsent(&error) && !is_conflict(&error));
+        assert_eq!(client.read("/slots", true).unwrap(), b"v2".to_vec());
+    }
+
+    #[test]
+    fn test_redis_client_lease() {
+        let client = RedisClient::new(vec![MockRedis::start()]);
+        let lease = client.grant_lease(Duration::from_millis(100)).unwrap();
+        client.txn(Txn::new().put_with_lease("/nodes/1", b"node1", lease).put_with_lease("/nodes/2", b"node2", lease)).unwrap();
+        assert!(client.txn(Txn::new().put_with_lease("/nodes/3", b"node3", lease + 1)).is_err());
+        // Put without lease detaches the key
+        client.update("/nodes/2", b"node2").unwrap();
+
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(50));
+            client.keep_alive(lease).unwrap();
+        }
+        assert_eq!(client.list("/nodes/", true).unwrap(), vec!["/nodes/1", "/nodes/2"]);
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(client.list("/", true).unwrap(), vec!["/nodes/2"]);
+        assert!(client.keep_alive(lease).is_err());
+
+        let lease = client.grant_lease(Duration::from_secs(60)).unwrap();
+        client.txn(Txn::new().put_with_lease("/nodes/3", b"node3", lease)).unwrap();
+        client.revoke_lease(lease).unwrap();
+        assert!(client.read_revision("/nodes/3").unwrap().is_none());
+    }
+}
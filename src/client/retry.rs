@@ -0,0 +1,526 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use rand::Rng;
+use tokio::time;
+use tracing::warn;
+
+use super::{is_unavailable, is_unsent, MetaClient, Txn, UnavailableError};
+
+/// Retry and circuit breaker policy of `RetryClient`
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Max retries of a call, so a call is tried at most `max_retries + 1` times
+    pub max_retries: u32,
+    /// The backoff before the first retry, doubled with every retry
+    pub base_delay: Duration,
+    /// The upper bound of the backoff
+    pub max_delay: Duration,
+    /// Consecutive failures to open the circuit of an endpoint
+    pub failure_threshold: u32,
+    /// The circuit is open for this long, then a trial call is let through
+    pub open_duration: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+            failure_threshold: 3,
+            open_duration: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The backoff before the retry, with equal jitter so the proxies do not retry in lockstep
+    fn backoff(&self, retry: u32) -> Duration {
+        let exp = self.base_delay.saturating_mul(1 << retry.saturating_sub(1).min(16));
+        let delay = exp.min(self.max_delay);
+        let half = delay / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+/// The counters of `RetryClient`
+#[derive(Debug, Default)]
+struct RetryCounters {
+    /// The calls
+    calls: AtomicU64,
+    /// The retries after unavailable errors
+    retries: AtomicU64,
+    /// The calls failed with unavailable errors, after all retries or not retryable
+    failures: AtomicU64,
+    /// The calls rejected because all the circuits are open
+    rejections: AtomicU64,
+    /// The times a circuit is opened
+    circuit_opens: AtomicU64,
+}
+
+/// Snapshot of the counters of `RetryClient`, for monitoring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryMetrics {
+    /// The calls
+    pub calls: u64,
+    /// The retries after unavailable errors
+    pub retries: u64,
+    /// The calls failed with unavailable errors, after all retries or not retryable
+    pub failures: u64,
+    /// The calls rejected because all the circuits are open
+    pub rejections: u64,
+    /// The times a circuit is opened
+    pub circuit_opens: u64,
+    /// The endpoints whose circuit is open now
+    pub open_circuits: u64,
+}
+
+/// The circuit breaker of an endpoint
+#[derive(Debug, Default)]
+struct Circuit {
+    /// Consecutive unavailable errors
+    failures: u32,
+    /// The circuit is open until, None if it's closed
+    open_until: Option<Instant>,
+}
+
+/// An endpoint with its client and circuit
+#[derive(Debug)]
+struct Endpoint<C> {
+    /// The endpoint
+    name: String,
+    /// The client connecting to the endpoint only
+    client: C,
+    /// The circuit breaker
+    circuit: Mutex<Circuit>,
+}
+
+/// Meta data client retrying the calls failed with unavailable errors
+///
+/// It holds a client per endpoint, and sends the calls to the current one.
+/// An unavailable error rotates to the next endpoint, and the call is
+/// retried with bounded exponential backoff. An endpoint failing
+/// `failure_threshold` times in a row has its circuit opened, it's skipped
+/// until `open_duration` passes and a trial call succeeds.
+///
+/// Other errors, e.g. `ConflictError`, are returned directly. The writes are
+/// retried only if they are known not to be sent, e.g. they failed to connect.
+/// A write timed out may still be applied, so it's not retried, or it could
+/// be applied twice.
+#[derive(Debug)]
+pub struct RetryClient<C> {
+    /// The endpoints
+    endpoints: Vec<Endpoint<C>>,
+    /// Index of the current endpoint
+    current: AtomicUsize,
+    /// The retry policy
+    policy: RetryPolicy,
+    /// The counters
+    counters: RetryCounters,
+}
+
+/// Call the method on the endpoints with retry, if the unavailable error is retryable
+macro_rules! retry {
+    ($self:ident, $method:ident($($arg:expr),*)) => {
+        retry!($self, $method($($arg),*), is_unavailable)
+    };
+    ($self:ident, $method:ident($($arg:expr),*), $retryable:path) => {{
+        $self.counters.calls.fetch_add(1, Ordering::Relaxed);
+        let mut retry = 0;
+        loop {
+            let index = $self.select()?;
+            let endpoint = &$self.endpoints[index];
+            match endpoint.client.$method($($arg),*).await {
+                Err(e) if is_unavailable(&e) => {
+                    $self.fail(index, &e);
+                    if retry >= $self.policy.max_retries || !$retryable(&e) {
+                        $self.counters.failures.fetch_add(1, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                    retry += 1;
+                    $self.counters.retries.fetch_add(1, Ordering::Relaxed);
+                    time::sleep($self.policy.backoff(retry)).await;
+                }
+                result => {
+                    $self.succeed(index);
+                    return result;
+                }
+            }
+        }
+    }};
+}
+
+impl<C: MetaClient> RetryClient<C> {
+    /// Create a new retry client with the clients of the endpoints
+    pub fn new(endpoints: Vec<(String, C)>) -> Result<Self, Error> {
+        if endpoints.is_empty() {
+            return Err(anyhow!("no meta endpoint is configured"));
+        }
+
+        Ok(Self {
+            endpoints: endpoints
+                .into_iter()
+                .map(|(name, client)| Endpoint {
+                    name,
+                    client,
+                    circuit: Mutex::new(Circuit::default()),
+                })
+                .collect(),
+            current: AtomicUsize::new(0),
+            policy: RetryPolicy::default(),
+            counters: RetryCounters::default(),
+        })
+    }
+
+    /// Create a new retry client with a single client, e.g. it does failover itself
+    pub fn single(client: C) -> Self {
+        Self {
+            endpoints: vec![Endpoint {
+                name: "default".to_owned(),
+                client,
+                circuit: Mutex::new(Circuit::default()),
+            }],
+            current: AtomicUsize::new(0),
+            policy: RetryPolicy::default(),
+            counters: RetryCounters::default(),
+        }
+    }
+
+    /// Set the retry policy
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Get the client of the current endpoint
+    pub fn current(&self) -> &C {
+        &self.endpoints[self.current.load(Ordering::Relaxed)].client
+    }
+
+    /// Get the snapshot of the counters
+    pub fn metrics(&self) -> RetryMetrics {
+        let now = Instant::now();
+        let open_circuits = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.circuit.lock().unwrap().open_until.map_or(false, |until| until > now))
+            .count();
+
+        RetryMetrics {
+            calls: self.counters.calls.load(Ordering::Relaxed),
+            retries: self.counters.retries.load(Ordering::Relaxed),
+            failures: self.counters.failures.load(Ordering::Relaxed),
+            rejections: self.counters.rejections.load(Ordering::Relaxed),
+            circuit_opens: self.counters.circuit_opens.load(Ordering::Relaxed),
+            open_circuits: open_circuits as u64,
+        }
+    }
+
+    /// Select the first endpoint from the current one whose circuit is closed or due to a trial
+    fn select(&self) -> Result<usize, Error> {
+        let now = Instant::now();
+        let start = self.current.load(Ordering::Relaxed);
+        for offset in 0..self.endpoints.len() {
+            let index = (start + offset) % self.endpoints.len();
+            let circuit = self.endpoints[index].circuit.lock().unwrap();
+            if circuit.open_until.map_or(true, |until| until <= now) {
+                self.current.store(index, Ordering::Relaxed);
+                return Ok(index);
+            }
+        }
+
+        self.counters.rejections.fetch_add(1, Ordering::Relaxed);
+        Err(UnavailableError::unsent("the circuits of all meta endpoints are open".to_owned()).into())
+    }
+
+    /// Record the unavailable error of the endpoint, and rotate to the next one
+    fn fail(&self, index: usize, error: &Error) {
+        let endpoint = &self.endpoints[index];
+        let mut circuit = endpoint.circuit.lock().unwrap();
+        circuit.failures += 1;
+        if circuit.failures >= self.policy.failure_threshold {
+            // A failed trial reopens the circuit
+            if circuit.open_until.is_none() {
+                self.counters.circuit_opens.fetch_add(1, Ordering::Relaxed);
+                warn!("Meta endpoint {} circuit is open: {:?}", endpoint.name, error);
+            }
+            circuit.open_until = Some(Instant::now() + self.policy.open_duration);
+        }
+
+        let _ = self
+            .current
+            .compare_exchange(index, (index + 1) % self.endpoints.len(), Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Record the endpoint is available, and close its circuit
+    fn succeed(&self, index: usize) {
+        let mut circuit = self.endpoints[index].circuit.lock().unwrap();
+        circuit.failures = 0;
+        circuit.open_until = None;
+    }
+}
+
+#[async_trait]
+impl<C: MetaClient> MetaClient for RetryClient<C> {
+    type Watcher = C::Watcher;
+
+    fn from_endpoints(endpoints: Vec<String>) -> Result<Self, Error> {
+        if endpoints.is_empty() {
+            return Ok(Self::single(C::from_endpoints(endpoints)?));
+        }
+
+        let clients = endpoints
+            .into_iter()
+            .map(|endpoint| Ok((endpoint.clone(), C::from_endpoints(vec![endpoint])?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Self::new(clients)
+    }
+
+    async fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        retry!(self, create(path, data), is_unsent)
+    }
+
+    async fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        retry!(self, update(path, data), is_unsent)
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), Error> {
+        retry!(self, delete(path), is_unsent)
+    }
+
+    async fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+        retry!(self, read(path, must))
+    }
+
+    async fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+        retry!(self, list(path, must))
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        for endpoint in &self.endpoints {
+            endpoint.client.close().await?;
+        }
+        Ok(())
+    }
+
+    async fn watch(&self, path: &str) -> Result<Self::Watcher, Error> {
+        retry!(self, watch(path))
+    }
+
+    async fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        retry!(self, read_revision(path))
+    }
+
+    async fn txn(&self, txn: Txn) -> Result<u64, Error> {
+        retry!(self, txn(txn.clone()), is_unsent)
+    }
+
+    async fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+        retry!(self, grant_lease(ttl), is_unsent)
+    }
+
+    async fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+        retry!(self, keep_alive(lease))
+    }
+
+    async fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+        retry!(self, revoke_lease(lease), is_unsent)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::client::memory::InMemoryMetaClient;
+    use crate::client::{is_conflict, Compare};
+
+    /// In-memory client which can be made unavailable
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct FlakyClient {
+        inner: InMemoryMetaClient,
+        /// The calls fail to connect
+        pub(crate) down: Arc<AtomicBool>,
+        /// The writes time out after they are applied
+        slow: Arc<AtomicBool>,
+    }
+
+    impl FlakyClient {
+        pub(crate) fn new(inner: InMemoryMetaClient) -> Self {
+            Self { inner, ..Self::default() }
+        }
+
+        fn check(&self) -> Result<(), Error> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err(UnavailableError::unsent("down".to_owned()).into());
+            }
+            Ok(())
+        }
+
+        fn check_applied<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+            if self.slow.load(Ordering::Relaxed) {
+                result?;
+                return Err(UnavailableError::new("timed out".to_owned()).into());
+            }
+            result
+        }
+    }
+
+    #[async_trait]
+    impl MetaClient for FlakyClient {
+        type Watcher = <InMemoryMetaClient as MetaClient>::Watcher;
+
+        fn from_endpoints(_endpoints: Vec<String>) -> Result<Self, Error> {
+            Ok(Self::default())
+        }
+
+        async fn create(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+            self.check()?;
+            self.check_applied(self.inner.create(path, data).await)
+        }
+
+        async fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+            self.check()?;
+            self.check_applied(self.inner.update(path, data).await)
+        }
+
+        async fn delete(&self, path: &str) -> Result<(), Error> {
+            self.check()?;
+            self.check_applied(self.inner.delete(path).await)
+        }
+
+        async fn read(&self, path: &str, must: bool) -> Result<Vec<u8>, Error> {
+            self.check()?;
+            self.inner.read(path, must).await
+        }
+
+        async fn list(&self, path: &str, must: bool) -> Result<Vec<String>, Error> {
+            self.check()?;
+            self.inner.list(path, must).await
+        }
+
+        async fn close(&self) -> Result<(), Error> {
+            self.inner.close().await
+        }
+
+        async fn watch(&self, path: &str) -> Result<Self::Watcher, Error> {
+            self.check()?;
+            self.inner.watch(path).await
+        }
+
+        async fn read_revision(&self, path: &str) -> Result<Option<(Vec<u8>, u64)>, Error> {
+            self.check()?;
+            self.inner.read_revision(path).await
+        }
+
+        async fn txn(&self, txn: Txn) -> Result<u64, Error> {
+            self.check()?;
+            self.check_applied(self.inner.txn(txn).await)
+        }
+
+        async fn grant_lease(&self, ttl: Duration) -> Result<u64, Error> {
+            self.check()?;
+            self.inner.grant_lease(ttl).await
+        }
+
+        async fn keep_alive(&self, lease: u64) -> Result<(), Error> {
+            self.check()?;
+            self.inner.keep_alive(lease).await
+        }
+
+        async fn revoke_lease(&self, lease: u64) -> Result<(), Error> {
+            self.check()?;
+            self.inner.revoke_lease(lease).await
+        }
+    }
+
+    fn test_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+            failure_threshold: 2,
+            open_duration: Duration::from_millis(50),
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        for retry in 1..10 {
+            let delay = policy.backoff(retry);
+            let exp = (policy.base_delay * (1 << (retry - 1))).min(policy.max_delay);
+            assert!(delay >= exp / 2 && delay <= exp);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_client_failover() {
+        // Two endpoints of the same store
+        let store = InMemoryMetaClient::new();
+        let first = FlakyClient::new(store.clone());
+        let second = FlakyClient::new(store.clone());
+        let client = RetryClient::new(vec![("first".to_owned(), first.clone()), ("second".to_owned(), second.clone())])
+            .unwrap()
+            .with_policy(test_policy());
+
+        first.down.store(true, Ordering::Relaxed);
+        client.update("/slots", b"v1").await.unwrap();
+        client.update("/slots", b"v2").await.unwrap();
+        assert_eq!(client.metrics().retries, 1);
+
+        // Other errors are not retried
+        assert!(client.create("/slots", b"v3").await.is_err());
+        assert_eq!(client.metrics().retries, 1);
+
+        // Both are down, the circuits are opened and the calls are rejected
+        second.down.store(true, Ordering::Relaxed);
+        assert!(client.read("/slots", true).await.is_err());
+        assert!(client.read("/slots", true).await.is_err());
+        let metrics = client.metrics();
+        assert_eq!((metrics.calls, metrics.failures, metrics.open_circuits), (5, 1, 2));
+        assert!(metrics.rejections >= 1);
+        assert!(metrics.circuit_opens >= 2);
+
+        // The trial call closes the circuit after it's recovered
+        second.down.store(false, Ordering::Relaxed);
+        time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(client.read("/slots", true).await.unwrap(), b"v2".to_vec());
+        assert_eq!(client.metrics().open_circuits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_client_writes_not_sent_twice() {
+        let store = InMemoryMetaClient::new();
+        let first = FlakyClient::new(store.clone());
+        let second = FlakyClient { slow: Arc::clone(&first.slow), ..FlakyClient::new(store.clone()) };
+        let client = RetryClient::new(vec![("first".to_owned(), first.clone()), ("second".to_owned(), second)])
+            .unwrap()
+            .with_policy(test_policy());
+
+        // The writes may be applied before they time out, they are not retried
+        first.slow.store(true, Ordering::Relaxed);
+        let error = client.create("/nodes/1", b"node1").await.unwrap_err();
+        assert!(is_unavailable(&error) && !is_unsent(&error));
+        assert_eq!(store.read("/nodes/1", true).await.unwrap(), b"node1".to_vec());
+        let txn = Txn::new().when(Compare::ModRevision("/slots".to_owned(), 0)).put("/slots", b"v1");
+        assert!(!is_conflict(&client.txn(txn).await.unwrap_err()));
+        let metrics = client.metrics();
+        assert_eq!((metrics.retries, metrics.failures), (0, 2));
+
+        assert_eq!(client.read("/slots", true).await.unwrap(), b"v1".to_vec());
+
+        // The writes failed to connect are retried on the other endpoint
+        first.slow.store(false, Ordering::Relaxed);
+        first.down.store(true, Ordering::Relaxed);
+        client.update("/slots", b"v2").await.unwrap();
+        client.update("/slots", b"v3").await.unwrap();
+        assert_eq!(client.metrics().retries, 1);
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use super::watch::ChannelWatcher;
+use super::{Compare, ConflictError, TxnOp, WatchEvent};
+
+/// A key value entry in the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    /// The value
+    pub(crate) value: Vec<u8>,
+    /// The revision when the key is created
+    pub(crate) create_revision: u64,
+    /// The revision when the key is last modified
+    pub(crate) mod_revision: u64,
+    /// The attached lease, 0 means no lease
+    pub(crate) lease: u64,
+}
+
+/// A lease in the store
+#[derive(Debug, Clone)]
+struct Lease {
+    /// The ttl
+    ttl: Duration,
+    /// The lease expires at
+    deadline: Instant,
+    /// The attached keys
+    keys: BTreeSet<String>,
+}
+
+/// In-memory key value state machine
+///
+/// It's shared by the in-memory and file backed clients. Like etcd, every
+/// batch of ops bumps the store revision by one, and the changes are sent to
+/// the watchers of the matching prefixes. Leases are checked lazily, the owner
+/// should commit the ops of `expire` before using the store.
+#[derive(Debug, Default)]
+pub(crate) struct KvStore {
+    /// The key values, ordered by key
+    kvs: BTreeMap<String, Entry>,
+    /// The current store revision
+    revision: u64,
+    /// The watchers, with the watched prefix
+    watchers: Vec<(String, UnboundedSender<Result<WatchEvent, Error>>)>,
+    /// The alive leases
+    leases: BTreeMap<u64, Lease>,
+    /// The last granted lease id
+    last_lease: u64,
+}
+
+impl KvStore {
+    /// Create a store with loaded key values
+    pub(crate) fn with_data(kvs: BTreeMap<String, Entry>, revision: u64) -> Self {
+        Self {
+            kvs,
+            revision,
+            ..Self::default()
+        }
+    }
+
+    /// Get the current store revision
+    pub(crate) fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Get the entry of a key
+    pub(crate) fn get(&self, key: &str) -> Option<&Entry> {
+        self.kvs.get(key)
+    }
+
+    /// List the keys with the prefix
+    pub(crate) fn keys(&self, prefix: &str) -> Vec<String> {
+        self.kvs
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Check the compares of a transaction
+    pub(crate) fn check(&self, compares: &[Compare]) -> Result<(), ConflictError> {
+        let conflicts: Vec<String> = compares
+            .iter()
+            .filter(|compare| match **compare {
+                Compare::ModRevision(ref key, revision) => self.kvs.get(key).map_or(0, |entry| entry.mod_revision) != revision,
+            })
+            .map(|compare| compare.path().to_owned())
+            .collect();
+
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(ConflictError::new(conflicts))
+        }
+    }
+
+    /// Check the leases of the ops are alive
+    pub(crate) fn check_leases(&self, ops: &[TxnOp]) -> Result<(), Error> {
+        for op in ops {
+            if let TxnOp::Put(_, _, lease) = *op {
+                if lease != 0 && !self.leases.contains_key(&lease) {
+                    return Err(anyhow!("lease {} not found", lease));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Grant a lease with the ttl
+    pub(crate) fn grant(&mut self, ttl: Duration) -> u64 {
+        self.last_lease += 1;
+        self.leases.insert(self.last_lease, Lease {
+            ttl,
+            deadline: Instant::now() + ttl,
+            keys: BTreeSet::new(),
+        });
+        self.last_lease
+    }
+
+    /// Refresh the lease to its full ttl
+    pub(crate) fn keep_alive(&mut self, id: u64) -> Result<(), Error> {
+        let lease = self.leases.get_mut(&id).ok_or_else(|| anyhow!("lease {} not found", id))?;
+        lease.deadline = Instant::now() + lease.ttl;
+        Ok(())
+    }
+
+    /// Remove the lease, return the ops to delete its keys
+    pub(crate) fn revoke(&mut self, id: u64) -> Vec<TxnOp> {
+        self.leases
+            .remove(&id)
+            .map(|lease| lease.keys.into_iter().map(TxnOp::Delete).collect())
+            .unwrap_or_default()
+    }
+
+    /// Remove the expired leases, return the ops to delete their keys
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<TxnOp> {
+        let expired: Vec<u64> = self
+            .leases
+            .iter()
+            .filter(|&(_, lease)| lease.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        expired.into_iter().flat_map(|id| self.revoke(id)).collect()
+    }
+
+    /// Apply a batch of ops at `revision`, which must be larger than the current one
+    pub(crate) fn apply(&mut self, ops: &[TxnOp], revision: u64) {
+        debug_assert!(revision > self.revision);
+        self.revision = revision;
+
+        let mut events = Vec::with_capacity(ops.len());
+        for op in ops {
+            match *op {
+                TxnOp::Put(ref key, ref value, lease) => {
+                    let create_revision = self.kvs.get(key).map_or(revision, |entry| entry.create_revision);
+                    let old = self.kvs.insert(key.clone(), Entry {
+                        value: value.clone(),
+                        create_revision,
+                        mod_revision: revision,
+                        lease,
+                    });
+                    self.detach(old.as_ref(), key);
+                    if let Some(lease) = self.leases.get_mut(&lease) {
+                        lease.keys.insert(key.clone());
+                    }
+                    events.push(WatchEvent::put(key.clone(), value.clone(), revision));
+                }
+                TxnOp::Delete(ref key) => {
+                    if let Some(old) = self.kvs.remove(key) {
+                        self.detach(Some(&old), key);
+                        events.push(WatchEvent::delete(key.clone(), revision));
+                    }
+                }
+            }
+        }
+
+        self.notify(&events);
+    }
+
+    /// Detach the key from the lease of its old entry
+    fn detach(&mut self, old: Option<&Entry>, key: &str) {
+        if let Some(lease) = old.and_then(|entry| self.leases.get_mut(&entry.lease)) {
+            lease.keys.remove(key);
+        }
+    }
+
+    /// Watch the changes under the prefix
+    pub(crate) fn watch(&mut self, prefix: &str) -> ChannelWatcher {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.watchers.push((prefix.to_owned(), sender));
+        ChannelWatcher::new(receiver)
+    }
+
+    /// Send the events to the watchers, and remove the dropped watchers
+    fn notify(&mut self, events: &[WatchEvent]) {
+        self.watchers.retain(|&(ref prefix, ref sender)| {
+            events
+                .iter()
+                .filter(|event| event.key.starts_with(prefix.as_str()))
+                .all(|event| sender.send(Ok(event.clone())).is_ok())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::client::Watcher;
+
+    #[test]
+    fn test_store_revision() {
+        let mut store = KvStore::default();
+        store.apply(&[TxnOp::Put("/a".to_owned(), b"1".to_vec(), 0), TxnOp::Put("/b".to_owned(), b"1".to_vec(), 0)], 1);
+        store.apply(&[TxnOp::Put("/a".to_owned(), b"2".to_vec(), 0)], 2);
+
+        let entry = store.get("/a").unwrap();
+        assert_eq!((entry.create_revision, entry.mod_revision), (1, 2));
+        assert_eq!(store.get("/b").unwrap().mod_revision, 1);
+        assert_eq!(store.keys("/"), vec!["/a", "/b"]);
+        assert_eq!(store.revision(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_store_watch() {
+        let mut store = KvStore::default();
+        let mut watcher = store.watch("/nodes/");
+        let dropped = store.watch("/nodes/");
+        drop(dropped);
+
+        store.apply(&[TxnOp::Put("/slots".to_owned(), b"1".to_vec(), 0), TxnOp::Put("/nodes/1".to_owned(), b"1".to_vec(), 0)], 1);
+        store.apply(&[TxnOp::Delete("/nodes/1".to_owned()), TxnOp::Delete("/nodes/2".to_owned())], 2);
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(watcher.next(timeout).await.unwrap(), Some(WatchEvent::put("/nodes/1".to_owned(), b"1".to_vec(), 1)));
+        assert_eq!(watcher.next(timeout).await.unwrap(), Some(WatchEvent::delete("/nodes/1".to_owned(), 2)));
+        assert_eq!(watcher.next(timeout).await.unwrap(), None);
+        assert_eq!(store.watchers.len(), 1);
+    }
+
+    #[test]
+    fn test_store_check() {
+        let mut store = KvStore::default();
+        store.apply(&[TxnOp::Put("/a".to_owned(), b"1".to_vec(), 0)], 1);
+
+        assert!(store.check(&[Compare::ModRevision("/a".to_owned(), 1), Compare::ModRevision("/b".to_owned(), 0)]).is_ok());
+        let conflict = store.check(&[Compare::ModRevision("/a".to_owned(), 0), Compare::ModRevision("/b".to_owned(), 1)]).unwrap_err();
+        assert_eq!(conflict.paths(), &["/a".to_owned(), "/b".to_owned()]);
+    }
+
+    #[test]
+    fn test_store_lease() {
+        let mut store = KvStore::default();
+        let lease = store.grant(Duration::from_secs(60));
+        assert!(store.check_leases(&[TxnOp::Put("/a".to_owned(), b"1".to_vec(), lease + 1)]).is_err());
+        store.apply(&[TxnOp::Put("/a".to_owned(), b"1".to_vec(), lease), TxnOp::Put("/b".to_owned(), b"1".to_vec(), lease)], 1);
+        // Put without lease detaches the key
+        store.apply(&[TxnOp::Put("/b".to_owned(), b"2".to_vec(), 0)], 2);
+
+        store.keep_alive(lease).unwrap();
+        assert!(store.expire(Instant::now()).is_empty());
+        assert_eq!(store.expire(Instant::now() + Duration::from_secs(61)), vec![TxnOp::Delete("/a".to_owned())]);
+        assert!(store.keep_alive(lease).is_err());
+        assert!(store.revoke(lease).is_empty());
+    }
+}
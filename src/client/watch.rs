@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time;
+
+use super::{WatchEvent, Watcher};
+
+/// Snapshot of the meta data under a prefix for `poll_watcher`
+///
+/// It's the store revision, and the mod revision and value of each path.
+pub type Snapshot = (u64, BTreeMap<String, (u64, Vec<u8>)>);
+
+/// Watcher receiving events from a channel
+///
+/// The sender side is the event source, e.g. a store or a background thread.
+/// The `on_drop` hook is called when the watcher is dropped, to stop the source.
+pub struct ChannelWatcher {
+    /// The event receiver
+    receiver: UnboundedReceiver<Result<WatchEvent, Error>>,
+    /// Hook to stop the event source
+    on_drop: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl ChannelWatcher {
+    /// Create a new channel watcher
+    pub fn new(receiver: UnboundedReceiver<Result<WatchEvent, Error>>) -> Self {
+        Self {
+            receiver,
+            on_drop: None,
+        }
+    }
+
+    /// Create a new channel watcher, which calls `on_drop` when it's dropped
+    pub fn with_drop(receiver: UnboundedReceiver<Result<WatchEvent, Error>>, on_drop: impl FnOnce() + Send + 'static) -> Self {
+        Self {
+            receiver,
+            on_drop: Some(Box::new(on_drop)),
+        }
+    }
+}
+
+#[async_trait]
+impl Watcher for ChannelWatcher {
+    async fn next(&mut self, timeout: Duration) -> Result<Option<WatchEvent>, Error> {
+        match time::timeout(timeout, self.receiver.recv()).await {
+            Ok(Some(event)) => event.map(Some),
+            Ok(None) => Err(anyhow!("watch stream is closed")),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl Debug for ChannelWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelWatcher").finish_non_exhaustive()
+    }
+}
+
+impl Drop for ChannelWatcher {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.on_drop.take() {
+            on_drop();
+        }
+    }
+}
+
+/// Watch by polling snapshots in a background thread
+///
+/// It's used by the store without native watch support. The first snapshot is
+/// taken before return, then the changes between snapshots are sent as events,
+/// so the events are delayed by at most one `interval`. `snapshot` returns
+/// None if nothing is changed since the last one, so it can skip the scan.
+pub fn poll_watcher<F>(interval: Duration, mut snapshot: F) -> Result<ChannelWatcher, Error>
+where
+    F: FnMut() -> Result<Option<Snapshot>, Error> + Send + 'static,
+{
+    let (_, mut last) = snapshot()?.unwrap_or_default();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    let stop = Arc::clone(&stopped);
+    thread::spawn(move || {
+        while !stopped.load(Ordering::Acquire) {
+            thread::sleep(interval);
+            match snapshot() {
+                Ok(None) => {}
+                Ok(Some((revision, current))) => {
+                    for event in diff(&last, &current, revision) {
+                        if sender.send(Ok(event)).is_err() {
+                            return;
+                        }
+                    }
+                    last = current;
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(ChannelWatcher::with_drop(receiver, move || stop.store(true, Ordering::Release)))
+}
+
+/// Diff two snapshots into events, deleted paths take the store revision
+fn diff(last: &BTreeMap<String, (u64, Vec<u8>)>, current: &BTreeMap<String, (u64, Vec<u8>)>, revision: u64) -> Vec<WatchEvent> {
+    let mut events: Vec<WatchEvent> = current
+        .iter()
+        .filter(|&(key, &(mod_revision, _))| last.get(key).map(|&(rev, _)| rev) != Some(mod_revision))
+        .map(|(key, &(mod_revision, ref value))| WatchEvent::put(key.clone(), value.clone(), mod_revision))
+        .collect();
+    events.sort_by_key(|event| event.revision);
+
+    events.extend(
+        last.keys()
+            .filter(|key| !current.contains_key(*key))
+            .map(|key| WatchEvent::delete(key.clone(), revision)),
+    );
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::client::EventType;
+
+    #[test]
+    fn test_diff() {
+        let last = BTreeMap::from([
+            ("/a".to_owned(), (1, b"a".to_vec())),
+            ("/b".to_owned(), (2, b"b".to_vec())),
+        ]);
+        let current = BTreeMap::from([
+            ("/a".to_owned(), (4, b"a2".to_vec())),
+            ("/c".to_owned(), (3, b"c".to_vec())),
+        ]);
+
+        let events = diff(&last, &current, 5);
+        assert_eq!(events, vec![
+            WatchEvent::put("/c".to_owned(), b"c".to_vec(), 3),
+            WatchEvent::put("/a".to_owned(), b"a2".to_vec(), 4),
+            WatchEvent::delete("/b".to_owned(), 5),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_poll_watcher() {
+        let data: Arc<Mutex<Snapshot>> = Arc::new(Mutex::new((0, BTreeMap::new())));
+        let source = Arc::clone(&data);
+        let mut watcher = poll_watcher(Duration::from_millis(10), move || Ok(Some(source.lock().unwrap().clone()))).unwrap();
+        assert!(watcher.next(Duration::from_millis(30)).await.unwrap().is_none());
+
+        *data.lock().unwrap() = (1, BTreeMap::from([("/a".to_owned(), (1, b"a".to_vec()))]));
+        let event = watcher.next(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(event, WatchEvent::put("/a".to_owned(), b"a".to_vec(), 1));
+
+        *data.lock().unwrap() = (2, BTreeMap::new());
+        let event = watcher.next(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(event.event_type, EventType::Delete);
+        assert_eq!(event.revision, 2);
+    }
+}
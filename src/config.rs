@@ -1,3 +1,9 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+
 /// Cache proxy config
 /// 
 /// This struct is used to manage the cache proxy configuration.
@@ -10,6 +16,14 @@ pub struct Config {
     pub meta_type: MetaType,
     /// Meta data endpoint
     pub meta_endpoints: Vec<String>,
+    /// Meta data service user name, None if the service does not need it
+    pub meta_username: Option<String>,
+    /// Meta data service password, None if auth is disabled
+    pub meta_password: Option<String>,
+    /// Namespace of the cluster, prefixed to all meta data paths, empty for the root
+    ///
+    /// Several clusters can share one meta data service in different namespaces.
+    pub meta_namespace: String,
     /// Time period to fetch meta data
     pub time_period: usize,
 
@@ -17,6 +31,24 @@ pub struct Config {
     pub rpc_ip: String,
     /// RPC server port
     pub rpc_port: u16,
+    /// RPC TLS with mutual authentication, None for plain TCP
+    pub rpc_tls: Option<TlsConfig>,
+    /// Interval of the RPC heartbeats, None to disable the heartbeats and idle timeouts
+    pub rpc_heartbeat: Option<Duration>,
+}
+
+/// TLS config of the RPC traffic
+///
+/// The proxies and their clients are authenticated mutually, with the
+/// certificates signed by the same CA. The files are in PEM format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// The certificate chain of this proxy, used as both server and client
+    pub cert_path: PathBuf,
+    /// The private key of the certificate
+    pub key_path: PathBuf,
+    /// The CA certificates to verify the peers
+    pub ca_path: PathBuf,
 }
 
 /// Meta type
@@ -27,6 +59,10 @@ pub enum MetaType {
     ETCD,
     /// Redis
     Redis,
+    /// In-memory, for tests and standalone proxy
+    Memory,
+    /// Local directory, the meta endpoint is the directory path
+    File,
 }
 
 impl MetaType {
@@ -35,15 +71,27 @@ impl MetaType {
         match self {
             MetaType::ETCD => "etcd".to_string(),
             MetaType::Redis => "redis".to_string(),
+            MetaType::Memory => "memory".to_string(),
+            MetaType::File => "file".to_string(),
         }
     }
 
     /// Get the meta type from string
-    pub fn from_string(meta_type: &str) -> Self {
+    pub fn from_string(meta_type: &str) -> Result<Self, Error> {
+        meta_type.parse()
+    }
+}
+
+impl FromStr for MetaType {
+    type Err = Error;
+
+    fn from_str(meta_type: &str) -> Result<Self, Self::Err> {
         match meta_type {
-            "etcd" => MetaType::ETCD,
-            "redis" => MetaType::Redis,
-            _ => panic!("Invalid meta type"),
+            "etcd" => Ok(MetaType::ETCD),
+            "redis" => Ok(MetaType::Redis),
+            "memory" => Ok(MetaType::Memory),
+            "file" => Ok(MetaType::File),
+            _ => Err(anyhow!("invalid meta type {:?}, expect one of etcd, redis, memory and file", meta_type)),
         }
     }
 }
@@ -51,17 +99,50 @@ impl MetaType {
 impl Config {
     /// Create a new config
     pub fn new(slot_size: usize, meta_type_string: &str, meta_endpoints: Vec<String>, time_period: usize,
-            rpc_ip: String, rpc_port: u16) -> Self {
-        let meta_type = MetaType::from_string(meta_type_string);
+            rpc_ip: String, rpc_port: u16) -> Result<Self, Error> {
+        let meta_type = MetaType::from_string(meta_type_string)?;
 
-        Self {
+        Ok(Self {
             slot_size,
             meta_type,
             meta_endpoints,
+            meta_username: None,
+            meta_password: None,
+            meta_namespace: String::new(),
             time_period,
             rpc_ip,
             rpc_port,
-        }
+            rpc_tls: None,
+            rpc_heartbeat: None,
+        })
+    }
+
+    /// Set the meta data service credentials, the user name is optional for redis
+    pub fn with_meta_auth(mut self, username: Option<String>, password: String) -> Self {
+        self.meta_username = username;
+        self.meta_password = Some(password);
+        self
+    }
+
+    /// Set the namespace of the cluster, e.g. `cluster1` or `team/cluster1`
+    pub fn with_meta_namespace(mut self, namespace: &str) -> Self {
+        self.meta_namespace = namespace.to_owned();
+        self
+    }
+
+    /// Enable TLS with mutual authentication for the RPC traffic
+    pub fn with_rpc_tls(mut self, tls: TlsConfig) -> Self {
+        self.rpc_tls = Some(tls);
+        self
+    }
+
+    /// Enable the RPC heartbeats at the interval
+    ///
+    /// A peer missing several heartbeats is suspected, and an idle connection
+    /// without heartbeats for as long is closed by the server.
+    pub fn with_rpc_heartbeat(mut self, interval: Duration) -> Self {
+        self.rpc_heartbeat = Some(interval);
+        self
     }
 
     /// Get the slot size
@@ -74,6 +155,11 @@ impl Config {
         &self.meta_endpoints
     }
 
+    /// Get the meta data namespace
+    pub fn meta_namespace(&self) -> &str {
+        &self.meta_namespace
+    }
+
     /// Get the time period
     pub fn time_period(&self) -> usize {
         self.time_period
@@ -486,9 +486,6 @@ where T: NodeType,
             last_slot.end = self.capacity;
         }
 
-        // update the capacity
-        self.capacity = self.capacity;
-
         true
     }
 }
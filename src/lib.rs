@@ -73,7 +73,6 @@
 )]
 
 use anyhow::Ok;
-use client::ETCDClient;
 use config::Config;
 use manager::CacheProxyManager;
 
@@ -95,6 +94,9 @@ pub mod client;
 /// Slot hashring node
 pub mod node;
 
+/// Versioned topology records in the meta data
+pub mod schema;
+
 /// The file cache
 pub mod file_cache;
 
@@ -114,33 +116,30 @@ pub async fn start_cache_proxy(slot_size: usize, meta_type_string: &str, meta_en
         time_period,
         rpc_ip,
         rpc_port,
-    );
-    
-    match config.meta_type {
-        config::MetaType::ETCD => {
-            // start topology manager
-            let manager = CacheProxyManager::<ETCDClient>::new(config);
-
-            // Start timer worker to fetch metadata
-            let manager_worker = tokio::task::spawn(
-                async move {
-                    manager.start().await.unwrap_or_else(|e| {
-                        panic!("Manager error: {:?}", e);
-                    })
-                }
-            );
-
-            manager_worker
-                .await
-                .unwrap_or_else(|e| {
-                    panic!("Manager worker error: {:?}", e);
-                });
-        }
-        config::MetaType::Redis => {
-            // start redis client
-            unimplemented!()
+    )?;
+
+    start_cache_proxy_with_config(config).await
+}
+
+/// Proxy cache server with the full config, e.g. with meta data auth and namespace
+pub async fn start_cache_proxy_with_config(config: Config) -> anyhow::Result<()> {
+    // start topology manager
+    let manager = CacheProxyManager::new(config)?;
+
+    // Start timer worker to fetch metadata
+    let manager_worker = tokio::task::spawn(
+        async move {
+            manager.start().await.unwrap_or_else(|e| {
+                panic!("Manager error: {:?}", e);
+            })
         }
-    }
+    );
+
+    manager_worker
+        .await
+        .unwrap_or_else(|e| {
+            panic!("Manager worker error: {:?}", e);
+        });
 
     Ok(())
 }
\ No newline at end of file
@@ -1,11 +1,23 @@
-use std::{fmt::Debug, usize};
+use std::{collections::BTreeMap, fmt::Debug, future, hash::{Hash, Hasher}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex, RwLock}, usize};
 
-use anyhow::Ok;
-use tokio::{select, time};
+use anyhow::{anyhow, Ok};
+use tokio::{select, sync, time};
 
-use crate::{client::{self, MetaClient}, config::Config, node::NodeList, ring::HashRing, rpc::server::RPCServer, slot::SlotMapping};
+use crate::{client::{self, lock::Election, ConfiguredMetaClient, MetaClient, WatchEvent, Watcher}, config::Config, node::{Node, NodeList}, ring::HashRing, rpc::{heartbeat::{ConnectionEvent, EventHook, HEARTBEAT_MISSES}, server::RPCServer, tls::TlsContext}, schema, slot::{Slot, SlotMapping}};
 
-use tracing::warn;
+use siphasher::sip::SipHasher;
+use tracing::{info, warn};
+
+/// The meta data prefix watched by the manager
+const METADATA_PREFIX: &str = schema::CLUSTER_PATH;
+/// Timeout of a single wait on the watcher
+const WATCH_WAIT_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+/// The election path of the proxy computing and publishing slot assignments
+const ELECTION_PATH: &str = "/election/rebalancing";
+/// The leases last for this many time periods without keepalive
+const LEASE_PERIODS: u32 = 3;
+/// Max attempts to publish the slot assignments while other proxies keep publishing first
+const PUBLISH_ATTEMPTS: u32 = 5;
 
 /// Cache proxy manager
 ///
@@ -24,24 +36,82 @@ where
     client: C,
     /// RPC Server
     rpc_server: RPCServer,
+    /// The RPC TLS context, None if the RPC traffic is plain
+    tls: Option<Arc<TlsContext>>,
+    /// The latest meta data revision seen from the watcher
+    revision: AtomicU64,
+    /// The rebalancing leader election, the candidate is the rpc address
+    election: sync::Mutex<Election>,
+    /// This proxy is the rebalancing leader or not, as of the last campaign
+    leader: AtomicBool,
+    /// The meta data service is unreachable, and the last known topology is served
+    degraded: AtomicBool,
+    /// The rebalancing failures, they are retried on the next tick
+    rebalancing_failures: AtomicU64,
+    /// The hook of the RPC connection events, sending them to `peer_events`
+    event_hook: EventHook,
+    /// The RPC connection events, to mark the peer nodes suspect
+    peer_events: sync::Mutex<sync::mpsc::UnboundedReceiver<ConnectionEvent>>,
+    /// The current node
+    node: Node,
+    /// The lease the current node is registered under
+    node_lease: Mutex<Option<u64>>,
+    /// Tell the `start` loop to exit, once the node is unregistered
+    stopped: sync::watch::Sender<bool>,
+}
+
+impl CacheProxyManager<ConfiguredMetaClient> {
+    /// Create a new cache proxy manager with the meta data client of the config
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let client = client::new_meta_client(&config)?;
+
+        Self::with_client(config, client)
+    }
 }
 
 impl <C> CacheProxyManager<C>
 where
     C: MetaClient,
 {
-    /// Create a new cache proxy manager
-    pub fn new(config: Config) -> Self {
+    /// Create a new cache proxy manager with the given meta data client
+    pub fn with_client(config: Config, client: C) -> anyhow::Result<Self> {
         let inner = ProxyTopology::new(config.clone());
-        let rpc_server = RPCServer::new(config.clone().rpc_ip, config.clone().rpc_port);
-        let client = client::new_meta_client(config.clone().meta_endpoints);
+        let tls = config.rpc_tls.clone().map(TlsContext::new).transpose()?.map(Arc::new);
+        let mut rpc_server = RPCServer::new(config.clone().rpc_ip, config.clone().rpc_port);
+        if let Some(ref tls) = tls {
+            rpc_server = rpc_server.with_tls(Arc::clone(tls));
+        }
+        let (events_tx, peer_events) = sync::mpsc::unbounded_channel();
+        let event_hook = EventHook::new(move |event| {
+            let _ = events_tx.send(event);
+        });
+        if let Some(interval) = config.rpc_heartbeat {
+            rpc_server = rpc_server
+                .with_idle_timeout(interval * HEARTBEAT_MISSES)
+                .with_event_hook(event_hook.clone());
+        }
+        let candidate = format!("{}:{}", config.rpc_ip, config.rpc_port);
+        let ttl = time::Duration::from_secs(config.time_period() as u64) * LEASE_PERIODS;
+        let election = sync::Mutex::new(Election::new(ELECTION_PATH, &candidate, ttl));
+        let node = Node::new(node_id(&candidate), config.rpc_ip.clone(), config.rpc_port, 1);
 
-        Self {
+        Ok(Self {
             inner,
             config,
             client,
             rpc_server,
-        }
+            tls,
+            revision: AtomicU64::new(0),
+            election,
+            leader: AtomicBool::new(false),
+            degraded: AtomicBool::new(false),
+            rebalancing_failures: AtomicU64::new(0),
+            event_hook,
+            peer_events: sync::Mutex::new(peer_events),
+            node,
+            node_lease: Mutex::new(None),
+            stopped: sync::watch::Sender::new(false),
+        })
     }
 
     /// Get the cache proxy topology
@@ -59,25 +129,92 @@ where
         &self.client
     }
 
-    /// Get free slot
-    #[allow(dead_code)]
-    pub fn allocate_free_slot(&self) -> anyhow::Result<()> {
-        // Get available slot mapping and node list
-        let slot_mapping = self.inner.slot_mapping();
+    /// Get the latest meta data revision seen from the watcher
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::Acquire)
+    }
 
-        // Get available slot
-        let _available_slot = slot_mapping.available_slot();
+    /// This proxy is the rebalancing leader or not, as of the last campaign
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Acquire)
+    }
 
-        // Update slot mapping
-        // slot_mapping.update_slot(available_slot);
-        // self.inner.update_slot_mapping();
+    /// The last meta data update failed, and the last known topology is served
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Acquire)
+    }
 
-        Ok(())
+    /// Get the number of the rebalancing failures
+    pub fn rebalancing_failures(&self) -> u64 {
+        self.rebalancing_failures.load(Ordering::Relaxed)
+    }
+
+    /// Get the rebalancing leader of the cluster, None if there is no leader
+    pub async fn leader(&self) -> anyhow::Result<Option<String>> {
+        self.election.lock().await.leader(&self.client).await
+    }
+
+    /// Get the hook of the RPC connection events, for the clients to the peer nodes
+    pub fn event_hook(&self) -> EventHook {
+        self.event_hook.clone()
+    }
+
+    /// Mark the peer node suspect if it's unreachable, and clear the mark once it's connected
+    pub fn on_connection_event(&self, event: &ConnectionEvent) {
+        let nodes = self.inner.nodes();
+        let Some(node) = nodes.find_by_addr(event.peer()) else {
+            return;
+        };
+
+        match *event {
+            ConnectionEvent::Suspect(_) => {
+                if nodes.suspect(node.id()) {
+                    warn!("Node {} at {} is suspect", node.id(), event.peer());
+                }
+            }
+            ConnectionEvent::Connected(_) => {
+                if nodes.unsuspect(node.id()) {
+                    info!("Node {} at {} is reachable again", node.id(), event.peer());
+                }
+            }
+            // The peer may just have nothing to send
+            ConnectionEvent::Idle(_) => {}
+        }
+    }
+
+    /// Campaign for the rebalancing leadership, or keep it, return this proxy is the leader or not
+    async fn campaign(&self) -> bool {
+        let mut election = self.election.lock().await;
+        let was_leader = election.is_leader();
+        let leader = election.campaign(&self.client).await.unwrap_or_else(|e| {
+            warn!("Campaign for rebalancing leader failed: {:?}", e);
+            false
+        });
+        self.leader.store(leader, Ordering::Release);
+
+        if leader != was_leader {
+            info!("Proxy {} leadership changed, leader: {}", election.candidate(), leader);
+        }
+        leader
+    }
+
+    /// Assign the free slots to the registered nodes, return the new ring version if any is assigned
+    ///
+    /// A slot is free if its node is not registered, e.g. it's never assigned
+    /// or the node is gone.
+    pub async fn allocate_free_slot(&self) -> anyhow::Result<Option<u64>> {
+        self.update_slots(assign_free_slots).await
     }
 
     /// Start
+    ///
+    /// It runs until the node is unregistered. The meta data failures are
+    /// logged and retried on the next tick, they never stop the manager.
     pub async fn start(&self) -> anyhow::Result<()> {
-        // self.rpc_server.start().await?;
+        // Restarted after unregistered
+        self.stopped.send_replace(false);
+        let mut stopped = self.stopped.subscribe();
+        self.rpc_server.start().await?;
 
         // Fetch metadata from meta client
         // match self.client.read("/", true) {
@@ -104,13 +241,67 @@ where
         //     }
         // }
 
+        // Watch metadata changes, the timer worker is kept as fallback
+        let mut events = self.watch_metadata().await;
+        let mut peer_events = self.peer_events.lock().await;
+
         // Start timer worker to fetch metadata
         let mut metadata_interval = time::interval(time::Duration::from_secs(self.inner.time_period as u64));
         loop {
             select! {
+                // The borrowed value is not held across the other branches
+                () = async { let _ = stopped.wait_for(|&stopped| stopped).await; } => {
+                    info!("Proxy manager of node {} stopped", self.node.id());
+                    return Ok(());
+                },
                 _ = metadata_interval.tick() => {
+                    // Unregistered while the previous tick is running
+                    if *self.stopped.borrow() {
+                        continue;
+                    }
+
+                    // Keep the current node online
+                    if let Err(e) = self.keep_node_alive().await {
+                        warn!("Register node to meta client failed: {:?}", e);
+                    }
+
+                    // Pick up the renewed certificates
+                    self.reload_tls();
+
+                    // Recreate the broken watcher
+                    if events.is_none() {
+                        events = self.watch_metadata().await;
+                    }
+
                     // Update metadata from meta client
-                    self.update_metadata().await?;
+                    if let Err(e) = self.update_metadata().await {
+                        warn!("Update metadata from meta client failed: {:?}", e);
+                    }
+
+                    // Only the leader computes and publishes slot assignments
+                    if self.campaign().await {
+                        if let Err(e) = self.rebalancing().await {
+                            self.rebalancing_failures.fetch_add(1, Ordering::Relaxed);
+                            warn!("Rebalancing failed, retry on the next tick: {:?}", e);
+                        }
+                    }
+                },
+                event = next_event(&mut events) => {
+                    match event {
+                        Result::Ok(event) => {
+                            self.revision.fetch_max(event.revision, Ordering::AcqRel);
+                            if let Err(e) = self.update_metadata().await {
+                                warn!("Update metadata from meta client failed: {:?}", e);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Watch metadata from meta client failed: {:?}", e);
+                            events = None;
+                        }
+                    }
+                },
+                Some(event) = peer_events.recv() => {
+                    self.on_connection_event(&event);
                 },
                 _ = self.normal_worker() => {
 
@@ -119,107 +310,251 @@ where
         }
     }
 
-    /// Rebalancing
-    #[allow(dead_code)]
+    /// Rebalancing, it should only run on the leader
     pub async fn rebalancing(&self) -> anyhow::Result<()> {
-        // Request balancing lock
-        // let lock = self.client.lock("/");
-
-        // Get available slot mapping and node list
-        let slot_mapping = self.inner.slot_mapping();
-        let _node_list = self.inner.nodes();
-
-        // Get available slot
-        let _available_slot = slot_mapping.available_slot();
+        // TODO: move the slots from the overloaded nodes once the slots can be migrated
+        if let Some(ring_version) = self.allocate_free_slot().await? {
+            info!("Slot assignments are published at ring version {}", ring_version);
+        }
 
-        // Update slot mapping
-        // slot_mapping.update_slot(available_slot);
-        // self.inner.update_slot_mapping();
+        Ok(())
+    }
 
-        // TODO: rebalancing slot mapping
+    /// Compute the slot assignments from the published ones and publish them, return the new ring version
+    ///
+    /// The slots are published conditioned on the revision they are read at,
+    /// so the concurrent managers never overwrite each other. If another one
+    /// publishes first, they are read and computed again. None is returned if
+    /// the computation changes nothing.
+    async fn update_slots<F>(&self, mut compute: F) -> anyhow::Result<Option<u64>>
+    where
+        F: FnMut(&mut [Slot], &[Node]) -> bool + Send,
+    {
+        for _ in 0..PUBLISH_ATTEMPTS {
+            let (mut slots, revision) = match schema::load_slots(&self.client).await? {
+                Some(published) => published,
+                None => ((0..self.inner.slot_size as u64).map(|id| Slot::new(id, 0)).collect(), 0),
+            };
+            let (nodes, _) = schema::load_nodes(&self.client).await?;
+            if !compute(&mut slots, &nodes) {
+                return Ok(None);
+            }
 
-        // Update to slotmapping to meta client
-        // self.client.update("/", data);
+            match schema::publish_slots(&self.client, &slots, revision).await {
+                Result::Ok(ring_version) => {
+                    self.inner.replace_slots(slots, ring_version);
+                    return Ok(Some(ring_version));
+                }
+                Err(e) if client::is_conflict(&e) => {
+                    info!("Slot assignments are published by another proxy, computing again");
+                }
+                Err(e) => return Err(e),
+            }
+        }
 
-        Ok(())
+        Err(anyhow!("slot assignments conflict after {} attempts", PUBLISH_ATTEMPTS))
     }
 
     /// Current node online
-    #[allow(dead_code)]
-    fn register_node(&self) -> anyhow::Result<()> {
+    ///
+    /// The node is registered under a lease, so it disappears from the node
+    /// list of the cluster if this proxy stops keeping the lease alive.
+    async fn register_node(&self) -> anyhow::Result<()> {
         // update current node info to meta client
-        let _data = b"127.0.0.1"; // Mock data
+        let path = schema::node_path(self.node.id());
+        let data = schema::encode_node(&self.node);
+        let lease = self.client.grant_lease(self.lease_ttl()).await?;
 
         // Register node to meta client
-        // match self.client.create("/", data) {
-        //     Ok(_) => {
-        //         // Update metadata client
-        //         info!("Update metadata from meta client success");
-                
-        //         // self.inner.init(slot_mapping, node_list);
-        //     }
-        //     Err(e) => {
-        //         warn!("Update metadata from meta client failed: {:?}", e);
-        //     }
-        // }
+        if let Err(e) = self.client.create_with_lease(&path, &data, lease).await {
+            // Take over the registration left by this node before restart
+            let registered = self.client.read(&path, false).await;
+            if registered.as_deref().ok() != Some(data.as_slice()) {
+                let _ = self.client.revoke_lease(lease).await;
+                return Err(e);
+            }
+            self.client.update_with_lease(&path, &data, lease).await?;
+        }
 
-        Ok(())
+        {
+            let mut node_lease = self.node_lease.lock().unwrap();
+            // Checked under the lock, so the lease is either revoked here or by `unregister_node`
+            if !*self.stopped.borrow() {
+                *node_lease = Some(lease);
+                info!("Register node {} to meta client success", path);
+                return Ok(());
+            }
+        }
+
+        // Unregistered while registering
+        self.client.revoke_lease(lease).await
     }
 
-    /// Current node offline
-    #[allow(dead_code)]
-    fn unregister_node(&self) -> anyhow::Result<()> {
-        // update current node info to meta client
-        // match self.client.delete("/") {
-        //     Ok(_) => {
-        //         // Update metadata client
-        //         info!("Update metadata from meta client success");
-                
-        //         // self.inner.init(slot_mapping, node_list);
-        //     }
-        //     Err(e) => {
-        //         warn!("Update metadata from meta client failed: {:?}", e);
-        //     }
-        // }
+    /// Keep the node lease alive, register the node again if the lease is lost
+    async fn keep_node_alive(&self) -> anyhow::Result<()> {
+        let lease = *self.node_lease.lock().unwrap();
+        if let Some(lease) = lease {
+            match self.client.keep_alive(lease).await {
+                Result::Ok(()) => return Ok(()),
+                Err(e) => warn!("Keep node lease alive failed: {:?}", e),
+            }
+        }
+
+        self.register_node().await
+    }
 
-        Err(anyhow::anyhow!("Unregister node failed"))
+    /// Current node offline, and drain the rpc server
+    ///
+    /// The node is deleted first so the peers stop sending new requests, then
+    /// the in-flight requests are finished before the connections are closed.
+    /// The `start` loop exits, so the node is not registered again.
+    pub async fn unregister_node(&self) -> anyhow::Result<()> {
+        self.stopped.send_replace(true);
+
+        // The node is deleted with the lease
+        let lease = self.node_lease.lock().unwrap().take();
+        let revoked = match lease {
+            Some(lease) => self.client.revoke_lease(lease).await,
+            None => Ok(()),
+        };
+
+        self.rpc_server.stop().await?;
+        revoked
+    }
+
+    /// The ttl of the leases of this proxy
+    fn lease_ttl(&self) -> time::Duration {
+        time::Duration::from_secs(self.inner.time_period as u64) * LEASE_PERIODS
+    }
+
+    /// Watch the metadata changes
+    async fn watch_metadata(&self) -> Option<C::Watcher> {
+        match self.client.watch(METADATA_PREFIX).await {
+            Result::Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("Watch metadata from meta client failed: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Reload the RPC TLS certificates if they are renewed, the current ones are kept on failure
+    fn reload_tls(&self) {
+        if let Some(ref tls) = self.tls {
+            if let Err(e) = tls.reload_if_changed() {
+                warn!("Reload RPC TLS certificates failed: {:?}", e);
+            }
+        }
     }
 
     async fn update_metadata(&self) -> anyhow::Result<()> {
-        // Fetch metadata from meta client
-        // match self.client.read("/", true) {
-        //     Ok(_data) => {
-        //         // Update metadata client
-        //         info!("Update metadata from meta client success");
-                
-        //         // TODO: convert data to slot mapping and node list
-        //         // self.inner.update_slot_mapping();
-        //         // self.inner.update_node_list();
-        //     }
-        //     Err(e) => {
-        //         warn!("Update metadata from meta client failed: {:?}", e);
-        //     }
-        // }
+        // Fetch metadata from meta client, keep the last known topology on failure
+        let mut degraded = false;
+        match schema::load_nodes(&self.client).await {
+            // The unreadable nodes are skipped with warnings, the others are still served
+            Result::Ok((nodes, _)) => {
+                // Only the alive nodes are registered
+                self.inner.nodes().replace(nodes);
+            }
+            Err(e) => {
+                warn!("Update metadata from meta client failed: {:?}", e);
+                degraded = true;
+            }
+        }
+
+        match self.load_published_slots().await {
+            Result::Ok(Some((slots, ring_version))) => self.inner.replace_slots(slots, ring_version),
+            // Not published by the leader yet
+            Result::Ok(None) => {}
+            Err(e) => {
+                warn!("Update metadata from meta client failed: {:?}", e);
+                degraded = true;
+            }
+        }
+
+        if self.degraded.swap(degraded, Ordering::AcqRel) != degraded {
+            if degraded {
+                warn!("Meta data service is degraded, serving the last known topology");
+            } else {
+                info!("Meta data service is recovered");
+            }
+        }
 
-        warn!("Update metadata from meta client failed");
         Ok(())
     }
 
+    /// Load the published slots with the ring version, None if they are not published yet
+    async fn load_published_slots(&self) -> anyhow::Result<Option<(Vec<Slot>, u64)>> {
+        match schema::load_slots(&self.client).await? {
+            Some((slots, _)) => Ok(Some((slots, schema::load_ring_version(&self.client).await?))),
+            None => Ok(None),
+        }
+    }
+
     async fn normal_worker(&self) -> anyhow::Result<()> {
-        // serve rpc request
+        // The rpc requests are served by the rpc server in background
+        // TODO: nothing else to do yet, wait forever instead of spinning the select loop
+        future::pending::<()>().await;
 
         Ok(())
     }
 }
 
+/// The node id derived from its address, so it's stable across restarts
+fn node_id(address: &str) -> u64 {
+    let mut hasher = SipHasher::new();
+    address.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Assign the slots of the unregistered nodes to the registered ones, the least loaded first
+///
+/// Return any slot is assigned or not. The migrating slots are left to their migration.
+fn assign_free_slots(slots: &mut [Slot], nodes: &[Node]) -> bool {
+    let mut loads: BTreeMap<u64, usize> = nodes.iter().map(|node| (node.id(), 0)).collect();
+    for slot in slots.iter() {
+        if let Some(load) = loads.get_mut(&slot.backend_node_id()) {
+            *load += 1;
+        }
+    }
+
+    let mut assigned = false;
+    for slot in slots.iter_mut() {
+        if slot.is_migrating() || loads.contains_key(&slot.backend_node_id()) {
+            continue;
+        }
+        let Some((&id, load)) = loads.iter_mut().min_by_key(|(_, load)| **load) else {
+            return false;
+        };
+        slot.set_backend_node_id(id);
+        *load += 1;
+        assigned = true;
+    }
+    assigned
+}
+
+/// Wait for the next watch event, pending forever if there is no watcher
+async fn next_event<W: Watcher>(events: &mut Option<W>) -> anyhow::Result<WatchEvent> {
+    let watcher = match events.as_mut() {
+        Some(watcher) => watcher,
+        None => return future::pending().await,
+    };
+
+    loop {
+        if let Some(event) = watcher.next(WATCH_WAIT_TIMEOUT).await? {
+            return Ok(event);
+        }
+    }
+}
+
 /// Proxy topology
 /// 
 /// This struct is used to manage the inner topology in memory cache.
 #[allow(dead_code)]
 pub struct ProxyTopology {
-    /// Proxy topology for hashring
+    /// Proxy topology for hashring, rebuilt whenever the slots change
     /// TODO: update to node list?
-    hash_ring: HashRing,
+    hash_ring: RwLock<Arc<HashRing>>,
     /// Mapping from slot to physical node
     slot_mapping: SlotMapping,
     /// Node list
@@ -234,7 +569,7 @@ impl ProxyTopology {
     /// Create a new proxy topology
     pub fn new(config: Config) -> Self {
         let slot_mapping = SlotMapping::default();
-        let hash_ring = HashRing::new(slot_mapping.inner());
+        let hash_ring = RwLock::new(Arc::new(HashRing::new(slot_mapping.inner())));
         let node_list = NodeList::new();
         let slot_size = config.slot_size();
         let time_period = config.time_period();
@@ -248,9 +583,19 @@ impl ProxyTopology {
         }
     }
 
-    /// Get the hash ring
-    pub fn hash_ring(&self) -> &HashRing {
-        &self.hash_ring
+    /// Get the hash ring of the current slots
+    pub fn hash_ring(&self) -> Arc<HashRing> {
+        Arc::clone(&self.hash_ring.read().unwrap())
+    }
+
+    /// Replace the slots, and rebuild the hash ring at the ring version if they are changed
+    pub fn replace_slots(&self, slots: Vec<Slot>, ring_version: u64) {
+        let mut hash_ring = self.hash_ring.write().unwrap();
+        if hash_ring.version() == ring_version && self.slot_mapping.inner() == slots {
+            return;
+        }
+        *hash_ring = Arc::new(HashRing::new(slots.clone()).with_version(ring_version));
+        self.slot_mapping.replace(slots);
     }
 
     /// Get the slot mapping
@@ -271,7 +616,7 @@ impl ProxyTopology {
     /// Update slotmapping
     pub fn update_slot_mapping(&mut self, slot_mapping: SlotMapping) {
         self.slot_mapping = slot_mapping;
-        self.hash_ring = HashRing::new(self.slot_mapping.inner());
+        *self.hash_ring.get_mut().unwrap() = Arc::new(HashRing::new(self.slot_mapping.inner()));
     }
 
     /// Update online node list
@@ -298,4 +643,275 @@ impl Debug for ProxyTopology {
             .field("slot_size", &self.slot_size)
             .finish()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::memory::InMemoryMetaClient;
+
+    fn test_config(rpc_port: u16) -> Config {
+        Config::new(1024, "memory", Vec::new(), 1, "127.0.0.1".to_owned(), rpc_port).unwrap()
+    }
+
+    /// Distinct ports nothing listens on, they are bound together then released
+    fn unused_ports<const N: usize>() -> [u16; N] {
+        let listeners: Vec<_> = (0..N).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        std::array::from_fn(|index| listeners[index].local_addr().unwrap().port())
+    }
+
+    #[tokio::test]
+    async fn test_managers_share_memory_client() {
+        let client = InMemoryMetaClient::new();
+        let [port1, port2] = unused_ports();
+        let manager1 = CacheProxyManager::with_client(test_config(port1), client.clone()).unwrap();
+        let manager2 = CacheProxyManager::with_client(test_config(port2), client.clone()).unwrap();
+
+        manager1.client().create("/nodes/1", b"127.0.0.1:8001").await.unwrap();
+        manager2.client().create("/nodes/2", b"127.0.0.1:8002").await.unwrap();
+
+        assert_eq!(manager1.client().list("/nodes/", true).await.unwrap(), vec!["/nodes/1", "/nodes/2"]);
+        assert_eq!(manager2.client().read("/nodes/1", true).await.unwrap(), b"127.0.0.1:8001".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_manager_react_to_watch_event() {
+        let client = InMemoryMetaClient::new();
+        // The poll is too slow to be noticed by the test, and the rpc server binds any free port
+        let config = Config::new(1024, "memory", Vec::new(), 3600, "127.0.0.1".to_owned(), 0).unwrap();
+        let manager = CacheProxyManager::with_client(config, client.clone()).unwrap();
+
+        let changed = async {
+            for id in 0.. {
+                let node = Node::new(id, "127.0.0.1".to_owned(), 9000, 1);
+                client.update(&schema::node_path(id), &schema::encode_node(&node)).await.unwrap();
+                time::sleep(time::Duration::from_millis(10)).await;
+                if manager.revision() > 0 {
+                    break;
+                }
+            }
+        };
+
+        select! {
+            result = manager.start() => panic!("manager stopped: {:?}", result),
+            result = time::timeout(time::Duration::from_secs(5), changed) => result.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_manager_leader_election() {
+        let client = InMemoryMetaClient::new();
+        let [port1, port2] = unused_ports();
+        let manager1 = CacheProxyManager::with_client(test_config(port1), client.clone()).unwrap();
+        let manager2 = CacheProxyManager::with_client(test_config(port2), client.clone()).unwrap();
+
+        assert!(manager1.campaign().await);
+        assert!(!manager2.campaign().await);
+        assert!(manager1.is_leader() && !manager2.is_leader());
+        assert_eq!(manager2.leader().await.unwrap().as_deref(), Some(format!("127.0.0.1:{}", port1).as_str()));
+
+        manager1.election.lock().await.resign(manager1.client()).await.unwrap();
+        assert!(manager2.campaign().await);
+        assert_eq!(manager1.leader().await.unwrap().as_deref(), Some(format!("127.0.0.1:{}", port2).as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_managers_race_to_publish_slots() {
+        let client = InMemoryMetaClient::new();
+        let [port1, port2] = unused_ports();
+        let manager1 = CacheProxyManager::with_client(test_config(port1), client.clone()).unwrap();
+        let manager2 = CacheProxyManager::with_client(test_config(port2), client.clone()).unwrap();
+        manager1.keep_node_alive().await.unwrap();
+        manager2.keep_node_alive().await.unwrap();
+
+        // Manager2 publishes after manager1 reads the slots, so manager1 computes again
+        let mut computed = 0;
+        let published = manager1
+            .update_slots(|slots, nodes| {
+                computed += 1;
+                if computed == 1 {
+                    assert_eq!(futures::executor::block_on(manager2.allocate_free_slot()).unwrap(), Some(1));
+                    slots.iter_mut().for_each(|slot| slot.set_backend_node_id(manager1.node.id()));
+                    return true;
+                }
+                assign_free_slots(slots, nodes)
+            })
+            .await
+            .unwrap();
+        assert_eq!((computed, published), (2, None));
+
+        // The slots of manager2 are kept, and spread over both nodes
+        let (slots, _) = schema::load_slots(&client).await.unwrap().unwrap();
+        assert_eq!(slots, manager2.inner().slot_mapping().inner());
+        let owned = slots.iter().filter(|slot| slot.backend_node_id() == manager1.node.id()).count();
+        assert_eq!((slots.len(), owned), (1024, 512));
+        assert_eq!(schema::load_ring_version(&client).await.unwrap(), 1);
+
+        // A node is gone, its slots are assigned to the other
+        manager2.unregister_node().await.unwrap();
+        assert_eq!(manager1.allocate_free_slot().await.unwrap(), Some(2));
+        let (slots, _) = schema::load_slots(&client).await.unwrap().unwrap();
+        assert!(slots.iter().all(|slot| slot.backend_node_id() == manager1.node.id()));
+        assert_eq!(manager1.allocate_free_slot().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_manager_routing_follows_slots() {
+        let client = InMemoryMetaClient::new();
+        let [port1, port2] = unused_ports();
+        let leader = CacheProxyManager::with_client(test_config(port1), client.clone()).unwrap();
+        let follower = CacheProxyManager::with_client(test_config(port2), client.clone()).unwrap();
+        leader.keep_node_alive().await.unwrap();
+        follower.keep_node_alive().await.unwrap();
+        assert_eq!(leader.inner().hash_ring().version(), 0);
+
+        // The leader routes by the published slots at once, and the follower after the update
+        assert_eq!(leader.allocate_free_slot().await.unwrap(), Some(1));
+        follower.update_metadata().await.unwrap();
+        let ids = [leader.node.id(), follower.node.id()];
+        let (leader_ring, follower_ring) = (leader.inner().hash_ring(), follower.inner().hash_ring());
+        assert_eq!((leader_ring.version(), follower_ring.version()), (1, 1));
+        for key in ["a", "b", "c", "d"] {
+            let slot = leader_ring.get_slot(key).unwrap();
+            assert!(ids.contains(&slot.backend_node_id()));
+            assert_eq!(follower_ring.get_slot(key), Some(slot));
+        }
+
+        // The slots of the gone node are routed to the other one
+        follower.unregister_node().await.unwrap();
+        assert_eq!(leader.allocate_free_slot().await.unwrap(), Some(2));
+        follower.update_metadata().await.unwrap();
+        let ring = follower.inner().hash_ring();
+        assert_eq!(ring.version(), 2);
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(ring.get_slot(key).unwrap().backend_node_id(), leader.node.id());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_registration() {
+        let client = InMemoryMetaClient::new();
+        let [port1, port2] = unused_ports();
+        let manager1 = CacheProxyManager::with_client(test_config(port1), client.clone()).unwrap();
+        let manager2 = CacheProxyManager::with_client(test_config(port2), client.clone()).unwrap();
+        manager1.keep_node_alive().await.unwrap();
+        manager2.keep_node_alive().await.unwrap();
+
+        manager1.update_metadata().await.unwrap();
+        let mut ports: Vec<u16> = manager1.inner().nodes().list().iter().map(Node::port).collect();
+        ports.sort_unstable();
+        let mut expected = vec![port1, port2];
+        expected.sort_unstable();
+        assert_eq!(ports, expected);
+
+        // The node is gone with its lease, and registered again by keepalive
+        let lease = manager2.node_lease.lock().unwrap().unwrap();
+        client.revoke_lease(lease).await.unwrap();
+        manager1.update_metadata().await.unwrap();
+        assert_eq!(manager1.inner().nodes().list().len(), 1);
+        manager2.keep_node_alive().await.unwrap();
+        manager1.update_metadata().await.unwrap();
+        assert_eq!(manager1.inner().nodes().list().len(), 2);
+
+        manager2.unregister_node().await.unwrap();
+        manager1.update_metadata().await.unwrap();
+        let nodes = manager1.inner().nodes().list();
+        assert_eq!((nodes.len(), nodes[0].id()), (1, manager1.node.id()));
+    }
+
+    #[tokio::test]
+    async fn test_manager_stop_on_unregister() {
+        let client = InMemoryMetaClient::new();
+        let manager = CacheProxyManager::with_client(test_config(0), client.clone()).unwrap();
+
+        let unregistered = async {
+            while client.list(schema::NODES_PATH, false).await.unwrap().is_empty() {
+                time::sleep(time::Duration::from_millis(10)).await;
+            }
+            manager.unregister_node().await.unwrap();
+
+            // The node is not registered again by the following ticks
+            for _ in 0..5 {
+                time::sleep(time::Duration::from_millis(500)).await;
+                assert!(client.list(schema::NODES_PATH, false).await.unwrap().is_empty());
+            }
+        };
+
+        let (started, ()) = time::timeout(time::Duration::from_secs(10), async { tokio::join!(manager.start(), unregistered) })
+            .await
+            .unwrap();
+        started.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_manager_survive_rebalancing_failure() {
+        let client = InMemoryMetaClient::new();
+        // Written by a newer proxy with an incompatible schema
+        client.create(schema::SLOTS_PATH, br#"{"version":3,"min_reader_version":3,"slots":[]}"#).await.unwrap();
+        let manager = CacheProxyManager::with_client(test_config(0), client.clone()).unwrap();
+
+        let failed = async {
+            while manager.rebalancing_failures() < 2 {
+                time::sleep(time::Duration::from_millis(10)).await;
+            }
+            assert!(manager.is_leader());
+            manager.unregister_node().await.unwrap();
+        };
+
+        // The manager keeps running and retries on the next tick, until it's stopped
+        let (started, ()) = time::timeout(time::Duration::from_secs(10), async { tokio::join!(manager.start(), failed) })
+            .await
+            .unwrap();
+        started.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_manager_degraded() {
+        let [port] = unused_ports();
+        let manager = CacheProxyManager::with_client(test_config(port), InMemoryMetaClient::new()).unwrap();
+        manager.keep_node_alive().await.unwrap();
+        // A node of a newer proxy with an incompatible schema is skipped
+        let newer = br#"{"version":3,"min_reader_version":3,"id":1,"ip":"127.0.0.1","port":9000}"#;
+        manager.client().create(&schema::node_path(1), newer).await.unwrap();
+        manager.update_metadata().await.unwrap();
+        assert!(!manager.is_degraded());
+        assert_eq!(manager.inner().nodes().list().len(), 1);
+
+        // The last known topology is kept while the meta data service is down
+        manager.client().close().await.unwrap();
+        manager.update_metadata().await.unwrap();
+        assert!(manager.is_degraded());
+        assert_eq!(manager.inner().nodes().list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_manager_suspect_peer() {
+        let client = InMemoryMetaClient::new();
+        // Nothing listens on the port of manager2
+        let [port1, port2] = unused_ports();
+        let peer = format!("127.0.0.1:{}", port2);
+        let manager1 = CacheProxyManager::with_client(test_config(port1), client.clone()).unwrap();
+        let manager2 = CacheProxyManager::with_client(test_config(port2), client.clone()).unwrap();
+        manager1.keep_node_alive().await.unwrap();
+        manager2.keep_node_alive().await.unwrap();
+        manager1.update_metadata().await.unwrap();
+        let nodes = manager1.inner().nodes();
+
+        // The rpc server of manager2 is not started, so it's unreachable
+        let rpc_client = crate::rpc::client::RPCClient::new("127.0.0.1".to_owned(), port2, 1000)
+            .with_event_hook(manager1.event_hook());
+        assert!(rpc_client.send_request(crate::rpc::RPCRequest::new(1, 1, None)).await.is_err());
+        let event = manager1.peer_events.lock().await.try_recv().unwrap();
+        assert_eq!(event, ConnectionEvent::Suspect(peer.clone()));
+        manager1.on_connection_event(&event);
+        assert!(nodes.is_suspect(manager2.node.id()));
+        assert!(!nodes.is_suspect(manager1.node.id()));
+
+        // The mark is kept across the updates, and cleared once it's connected
+        manager1.update_metadata().await.unwrap();
+        assert!(nodes.is_suspect(manager2.node.id()));
+        manager1.on_connection_event(&ConnectionEvent::Idle(peer.clone()));
+        assert!(nodes.is_suspect(manager2.node.id()));
+        manager1.on_connection_event(&ConnectionEvent::Connected(peer));
+        assert!(!nodes.is_suspect(manager2.node.id()));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
 /// Physical node struct
@@ -45,6 +46,11 @@ impl Node {
     pub fn weight(&self) -> u32 {
         self.weight
     }
+
+    /// Get the RPC address of the node
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
 }
 
 /// Node list
@@ -53,6 +59,8 @@ impl Node {
 #[derive(Debug)]
 pub struct NodeList {
     inner: Arc<Mutex<Vec<Node>>>,
+    /// The ids of the nodes suspected to be down, e.g. they missed the RPC heartbeats
+    suspects: Mutex<HashSet<u64>>,
 }
 
 impl NodeList {
@@ -60,6 +68,7 @@ impl NodeList {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(Mutex::new(Vec::new())),
+            suspects: Mutex::new(HashSet::new()),
         }
     }
 
@@ -77,6 +86,13 @@ impl NodeList {
     pub fn remove(&self, id: u64) {
         let mut list = self.inner.lock().unwrap();
         list.retain(|node| node.id() != id);
+        self.suspects.lock().unwrap().remove(&id);
+    }
+
+    /// Replace all the nodes in the list, the suspects still in the list are kept
+    pub fn replace(&self, nodes: Vec<Node>) {
+        self.suspects.lock().unwrap().retain(|&id| nodes.iter().any(|node| node.id() == id));
+        *self.inner.lock().unwrap() = nodes;
     }
 
     /// Get the node by id
@@ -84,4 +100,25 @@ impl NodeList {
         let list = self.inner.lock().unwrap();
         list.iter().find(|node| node.id() == id).cloned()
     }
+
+    /// Get the node by its RPC address
+    pub fn find_by_addr(&self, addr: &str) -> Option<Node> {
+        let list = self.inner.lock().unwrap();
+        list.iter().find(|node| node.addr() == addr).cloned()
+    }
+
+    /// Mark the node as suspect, return it's newly marked or not
+    pub fn suspect(&self, id: u64) -> bool {
+        self.suspects.lock().unwrap().insert(id)
+    }
+
+    /// Clear the suspect mark of the node, return it was marked or not
+    pub fn unsuspect(&self, id: u64) -> bool {
+        self.suspects.lock().unwrap().remove(&id)
+    }
+
+    /// The node is suspected or not
+    pub fn is_suspect(&self, id: u64) -> bool {
+        self.suspects.lock().unwrap().contains(&id)
+    }
 }
\ No newline at end of file
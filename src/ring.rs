@@ -34,6 +34,12 @@ impl HashRing {
         }
     }
 
+    /// Set the version of the hashring, e.g. the ring version of the slots it's built from
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
+    }
+
     /// Get the version of the hashring
     pub fn version(&self) -> u64 {
         self.version
@@ -0,0 +1,352 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::stream::{FuturesOrdered, StreamExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::client::RPCClient;
+use super::protocol::ProtocolError;
+use super::server::Handler;
+use super::RPCRequest;
+
+/// Default size of a chunk
+pub const DEFAULT_CHUNK_SIZE: u32 = 256 * 1024;
+
+/// Max size of a chunk, a larger request is cut to it
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Default number of in-flight chunks of a transfer
+pub const DEFAULT_WINDOW: usize = 4;
+
+/// Size of the fixed fields of a chunk request: offset and length
+const REQUEST_FIXED_SIZE: usize = 8 + 4;
+
+/// Size of the fixed fields of a chunk: total size, offset and checksum
+const CHUNK_FIXED_SIZE: usize = 8 + 8 + 4;
+
+/// Request of a chunk of an object
+///
+/// The request body is laid out in big endian as `offset (u64) | length (u32) | key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRequest {
+    /// The object key
+    pub key: String,
+    /// The offset of the chunk in the object
+    pub offset: u64,
+    /// The max length of the chunk
+    pub length: u32,
+}
+
+impl ChunkRequest {
+    /// Encode the request body
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(REQUEST_FIXED_SIZE + self.key.len());
+        buf.put_u64(self.offset);
+        buf.put_u32(self.length);
+        buf.put_slice(self.key.as_bytes());
+        buf.freeze()
+    }
+
+    /// Decode the request body
+    pub fn decode(mut body: &[u8]) -> anyhow::Result<Self> {
+        if body.len() < REQUEST_FIXED_SIZE {
+            return Err(ProtocolError::Truncated { needed: REQUEST_FIXED_SIZE, remaining: body.len() }.into());
+        }
+        let offset = body.get_u64();
+        let length = body.get_u32();
+        let key = String::from_utf8(body.to_vec())?;
+
+        Ok(Self { key, offset, length })
+    }
+}
+
+/// A chunk of an object
+///
+/// The response body is laid out in big endian as
+/// `total size (u64) | offset (u64) | crc32 of data (u32) | data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// The object size
+    pub total_size: u64,
+    /// The offset of the chunk in the object
+    pub offset: u64,
+    /// The chunk data, empty at the end of the object
+    pub data: Bytes,
+}
+
+impl Chunk {
+    /// Encode the response body with the checksum
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(CHUNK_FIXED_SIZE + self.data.len());
+        buf.put_u64(self.total_size);
+        buf.put_u64(self.offset);
+        buf.put_u32(crc32fast::hash(&self.data));
+        buf.put_slice(&self.data);
+        buf.freeze()
+    }
+
+    /// Decode the response body, fail if the checksum mismatches
+    ///
+    /// The data shares the body rather than copies it.
+    pub fn decode(mut body: Bytes) -> anyhow::Result<Self> {
+        if body.len() < CHUNK_FIXED_SIZE {
+            return Err(ProtocolError::Truncated { needed: CHUNK_FIXED_SIZE, remaining: body.len() }.into());
+        }
+        let total_size = body.get_u64();
+        let offset = body.get_u64();
+        let checksum = body.get_u32();
+        if crc32fast::hash(&body) != checksum {
+            return Err(anyhow!("checksum mismatch of the chunk at offset {}", offset));
+        }
+
+        Ok(Self {
+            total_size,
+            offset,
+            data: body,
+        })
+    }
+}
+
+/// The objects served in chunks
+#[async_trait]
+pub trait ChunkSource: Send + Sync + 'static {
+    /// Read up to `length` bytes of the object from `offset`, return the object size and the data
+    ///
+    /// The data can be shared with the cache, it's not copied until it's framed.
+    async fn read_chunk(&self, key: &str, offset: u64, length: usize) -> anyhow::Result<(u64, Bytes)>;
+}
+
+/// Handler serving the chunk requests from the source
+#[derive(Debug)]
+pub struct ChunkHandler<S> {
+    /// The objects
+    source: S,
+}
+
+impl<S: ChunkSource> ChunkHandler<S> {
+    /// Create a new handler of the source
+    pub fn new(source: S) -> Self {
+        Self { source }
+    }
+}
+
+#[async_trait]
+impl<S: ChunkSource> Handler for ChunkHandler<S> {
+    async fn handle(&self, request: RPCRequest) -> anyhow::Result<Option<Bytes>> {
+        let request = ChunkRequest::decode(request.body.as_deref().unwrap_or_default())?;
+        let length = request.length.min(MAX_CHUNK_SIZE) as usize;
+        let (total_size, data) = self.source.read_chunk(&request.key, request.offset, length).await?;
+
+        Ok(Some(Chunk { total_size, offset: request.offset, data }.encode()))
+    }
+}
+
+/// Download of an object in chunks
+///
+/// The chunks are requested as separate calls, so the transfer takes at most
+/// `window` chunks of a connection at a time, and the small requests are
+/// interleaved between the chunks. The chunks are written in order, and the
+/// transfer is resumed from the written offset if it's run again after a
+/// failure, e.g. connection loss or checksum mismatch.
+#[derive(Debug, Clone)]
+pub struct ChunkTransfer {
+    /// The message type of the chunk requests
+    message_type: u16,
+    /// The object key
+    key: String,
+    /// The chunk size
+    chunk_size: u32,
+    /// The max in-flight chunks
+    window: usize,
+    /// The bytes written
+    offset: u64,
+    /// The object size, None before the first chunk
+    total_size: Option<u64>,
+    /// The id of the next request
+    next_id: u64,
+}
+
+impl ChunkTransfer {
+    /// Create a new transfer of the object
+    pub fn new(message_type: u16, key: String) -> Self {
+        Self {
+            message_type,
+            key,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            window: DEFAULT_WINDOW,
+            offset: 0,
+            total_size: None,
+            next_id: 0,
+        }
+    }
+
+    /// Set the chunk size, up to `MAX_CHUNK_SIZE`
+    pub fn with_chunk_size(mut self, chunk_size: u32) -> Self {
+        self.chunk_size = chunk_size.clamp(1, MAX_CHUNK_SIZE);
+        self
+    }
+
+    /// Set the max in-flight chunks
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Get the bytes written
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Get the object size, None before the first chunk
+    pub fn total_size(&self) -> Option<u64> {
+        self.total_size
+    }
+
+    /// The object is fully written or not
+    pub fn is_done(&self) -> bool {
+        self.total_size.map_or(false, |total_size| self.offset >= total_size)
+    }
+
+    /// Transfer the rest of the object to the writer
+    pub async fn run<W: AsyncWrite + Unpin>(&mut self, client: &RPCClient, writer: &mut W) -> anyhow::Result<()> {
+        let mut in_flight = FuturesOrdered::new();
+        // The next offset to request
+        let mut requested = self.offset;
+
+        loop {
+            // Only one chunk is requested until the object size is known
+            let window = if self.total_size.is_some() { self.window } else { 1 };
+            while in_flight.len() < window && self.total_size.map_or(in_flight.is_empty(), |total_size| requested < total_size) {
+                let request = ChunkRequest {
+                    key: self.key.clone(),
+                    offset: requested,
+                    length: self.chunk_size,
+                };
+                let request = RPCRequest::new(self.next_id, self.message_type, Some(request.encode()));
+                self.next_id += 1;
+                requested += u64::from(self.chunk_size);
+                in_flight.push_back(client.send_request(request));
+            }
+
+            let Some(response) = in_flight.next().await else {
+                break;
+            };
+            let response = response?;
+            if response.is_error() {
+                let msg = String::from_utf8_lossy(response.msg.as_deref().unwrap_or_default()).into_owned();
+                return Err(anyhow!("chunk of {} at offset {} failed: {}", self.key, self.offset, msg));
+            }
+            let chunk = Chunk::decode(response.body.unwrap_or_default())?;
+            if chunk.offset != self.offset {
+                return Err(anyhow!("chunk of {} at offset {} is expected, got {}", self.key, self.offset, chunk.offset));
+            }
+            if self.total_size.map_or(false, |total_size| total_size != chunk.total_size) {
+                return Err(anyhow!("object {} is changed during the transfer", self.key));
+            }
+            self.total_size = Some(chunk.total_size);
+            if chunk.data.is_empty() && !self.is_done() {
+                return Err(anyhow!("chunk of {} at offset {} is empty", self.key, self.offset));
+            }
+
+            writer.write_all(&chunk.data).await?;
+            self.offset += chunk.data.len() as u64;
+            if chunk.data.len() < self.chunk_size as usize && !self.is_done() {
+                // A short chunk, request the rest from the written offset
+                in_flight = FuturesOrdered::new();
+                requested = self.offset;
+            }
+        }
+
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::rpc::server::RPCServer;
+
+    /// In-memory objects, failing once after the first chunks if `fail` is set
+    #[derive(Debug, Default)]
+    struct MemorySource {
+        objects: HashMap<String, Bytes>,
+        fail: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl ChunkSource for MemorySource {
+        async fn read_chunk(&self, key: &str, offset: u64, length: usize) -> anyhow::Result<(u64, Bytes)> {
+            let object = self.objects.get(key).ok_or_else(|| anyhow!("object {} not found", key))?;
+            if offset > 0 && self.fail.swap(false, Ordering::AcqRel) {
+                return Err(anyhow!("disk error"));
+            }
+            let start = (offset as usize).min(object.len());
+            let end = (start + length).min(object.len());
+            Ok((object.len() as u64, object.slice(start..end)))
+        }
+    }
+
+    #[test]
+    fn test_chunk_codec() {
+        let request = ChunkRequest { key: "object".to_owned(), offset: 7, length: 1024 };
+        assert_eq!(ChunkRequest::decode(&request.encode()).unwrap(), request);
+        assert!(ChunkRequest::decode(&[0; 3]).is_err());
+
+        let chunk = Chunk { total_size: 10, offset: 4, data: Bytes::from_static(b"data") };
+        let body = chunk.encode();
+        assert_eq!(Chunk::decode(body.clone()).unwrap(), chunk);
+        let mut body = BytesMut::from(&body[..]);
+        body[CHUNK_FIXED_SIZE] ^= 1;
+        assert!(Chunk::decode(body.freeze()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunk_transfer() {
+        let object: Vec<u8> = (0..100_000_u32).map(|i| i as u8).collect();
+        let fail = Arc::new(AtomicBool::new(false));
+        let source = MemorySource {
+            objects: HashMap::from([("object".to_owned(), Bytes::from(object.clone())), ("empty".to_owned(), Bytes::new())]),
+            fail: Arc::clone(&fail),
+        };
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0);
+        server.register(1, ChunkHandler::new(source));
+        server.register(2, |request: RPCRequest| async move { Ok(request.body) });
+        server.start().await.unwrap();
+        let client = RPCClient::new("127.0.0.1".to_owned(), server.local_addr().unwrap().port(), 1000).with_pool_size(1);
+
+        // The small requests are served during the transfer on the same connection
+        let mut transfer = ChunkTransfer::new(1, "object".to_owned()).with_chunk_size(4096).with_window(2);
+        let mut written = Vec::new();
+        let (result, small) = tokio::join!(transfer.run(&client, &mut written), async {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            client.send_request(RPCRequest::new(0, 2, Some(Bytes::from_static(b"small")))).await
+        });
+        result.unwrap();
+        assert_eq!(small.unwrap().body.as_deref(), Some(b"small".as_slice()));
+        assert!(transfer.is_done());
+        assert_eq!(written, object);
+
+        // Resumed from the written offset after a failure
+        fail.store(true, Ordering::Release);
+        let mut transfer = ChunkTransfer::new(1, "object".to_owned()).with_chunk_size(30_000);
+        let mut written = Vec::new();
+        assert!(transfer.run(&client, &mut written).await.is_err());
+        assert_eq!((transfer.offset(), transfer.total_size()), (30_000, Some(100_000)));
+        transfer.run(&client, &mut written).await.unwrap();
+        assert_eq!(written, object);
+
+        let mut transfer = ChunkTransfer::new(1, "empty".to_owned());
+        let mut written = Vec::new();
+        transfer.run(&client, &mut written).await.unwrap();
+        assert!(transfer.is_done() && written.is_empty());
+
+        let mut transfer = ChunkTransfer::new(1, "missing".to_owned());
+        assert!(transfer.run(&client, &mut Vec::new()).await.is_err());
+    }
+}
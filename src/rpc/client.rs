@@ -1,28 +1,729 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::codec::FramedRead;
+use tracing::{debug, warn};
+
+use super::codec::{FrameWriter, RPCCodec, RPCFrame};
+use super::compress::{Compression, CompressionMetrics, Compressor};
+use super::message::{BincodeCodec, MessageCodec};
+use super::heartbeat::{ConnectionEvent, EventHook, Heartbeat};
+use super::protocol::{MSG_CANCEL, MSG_GO_AWAY, MSG_HELLO, MSG_PING};
+use super::tls::TlsContext;
 use super::{RPCRequest, RPCResponse};
 
+/// Default number of connections to a server
+const DEFAULT_POOL_SIZE: usize = 2;
+
+/// Default number of retries of a request rejected as busy
+const DEFAULT_BUSY_RETRIES: usize = 2;
+
+/// The pending calls of a connection by request id
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<RPCResponse>>>>;
+
+/// A connection multiplexing the calls by request id
+#[derive(Debug)]
+struct ClientConnection {
+    /// The frames to send
+    sender: mpsc::UnboundedSender<RPCFrame>,
+    /// The pending calls
+    pending: Pending,
+    /// The id of the next request
+    next_id: Arc<AtomicU64>,
+    /// The connection is broken, or the server is going away
+    closed: Arc<AtomicBool>,
+    /// The reader, writer and heartbeat tasks
+    tasks: Vec<JoinHandle<()>>,
+    /// The compression of the requests, shared by the connections
+    compressor: Arc<Compressor>,
+    /// The negotiated compression, None if the bodies are raw
+    compression: Option<Compression>,
+}
+
+impl ClientConnection {
+    /// Connect to the server over TLS if it's given, and negotiate the compression
+    async fn connect(client: &RPCClient, deadline: time::Instant) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect((client.server_ip.as_str(), client.server_port)).await?;
+        stream.set_nodelay(true)?;
+        let mut connection = match client.tls {
+            Some(ref tls) => Self::open(tls.connect(&client.server_ip, stream).await?, client),
+            None => Self::open(stream, client),
+        };
+
+        if !client.compressor.algorithms().is_empty() {
+            let hello = RPCRequest::new(0, MSG_HELLO, Some(client.compressor.offer().into()));
+            let response = connection.call(hello, deadline, client.timeout()).await?;
+            // A server not knowing the hello responds an error, then the bodies are raw
+            if !response.is_error() {
+                connection.compression = response.body.and_then(|body| body.first().copied()).and_then(Compression::from_id);
+            }
+            debug!("RPC client negotiated compression {:?}", connection.compression);
+        }
+        Ok(connection)
+    }
+
+    /// Run the reader and writer tasks on the stream, and the heartbeat task if it's enabled
+    ///
+    /// The peer is suspected if nothing is received for the heartbeat timeout,
+    /// then the connection is closed as it may be half open.
+    fn open<S>(stream: S, client: &RPCClient) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = io::split(stream);
+        let mut reader = FramedRead::new(reader, RPCCodec::default());
+        let mut writer = FrameWriter::new(writer, RPCCodec::default());
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let next_id = Arc::new(AtomicU64::new(0));
+        let closed = Arc::new(AtomicBool::new(false));
+        let last_read = Arc::new(Mutex::new(time::Instant::now()));
+
+        let write = tokio::spawn({
+            let closed = Arc::clone(&closed);
+            async move {
+                if let Err(e) = writer.forward(&mut receiver).await {
+                    warn!("RPC client write failed: {:?}", e);
+                }
+                closed.store(true, Ordering::Release);
+            }
+        });
+
+        let read = tokio::spawn({
+            let pending = Arc::clone(&pending);
+            let closed = Arc::clone(&closed);
+            let last_read = Arc::clone(&last_read);
+            async move {
+                while let Some(frame) = reader.next().await {
+                    *last_read.lock().unwrap() = time::Instant::now();
+                    match frame {
+                        Ok(RPCFrame::Response(response)) => {
+                            // The caller may have timed out, or it's a pong
+                            if let Some(call) = pending.lock().unwrap().remove(&response.id) {
+                                let _ = call.send(response);
+                            }
+                        }
+                        Ok(RPCFrame::Request(request)) if request.message_type().ok() == Some(MSG_GO_AWAY) => {
+                            // The in-flight calls are still responded
+                            debug!("RPC server is going away");
+                            closed.store(true, Ordering::Release);
+                        }
+                        Ok(RPCFrame::Request(request)) => {
+                            warn!("RPC client got unexpected request {}", request.id);
+                        }
+                        Err(e) => {
+                            warn!("RPC client read failed: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+
+                // Fail the pending calls by dropping their senders
+                closed.store(true, Ordering::Release);
+                pending.lock().unwrap().clear();
+            }
+        });
+
+        let mut tasks = vec![read, write];
+        if let Some(heartbeat) = client.heartbeat {
+            let aborts = [tasks[0].abort_handle(), tasks[1].abort_handle()];
+            tasks.push(tokio::spawn({
+                let sender = sender.clone();
+                let pending = Arc::clone(&pending);
+                let next_id = Arc::clone(&next_id);
+                let closed = Arc::clone(&closed);
+                let peer = client.peer();
+                let events = client.events.clone();
+                async move {
+                    let mut ticks = time::interval(heartbeat.interval);
+                    ticks.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+                    loop {
+                        ticks.tick().await;
+                        if closed.load(Ordering::Acquire) {
+                            break;
+                        }
+                        if last_read.lock().unwrap().elapsed() >= heartbeat.timeout {
+                            warn!("RPC server {} missed the heartbeats for {:?}, closing the connection", peer, heartbeat.timeout);
+                            closed.store(true, Ordering::Release);
+                            for abort in &aborts {
+                                abort.abort();
+                            }
+                            pending.lock().unwrap().clear();
+                            if let Some(events) = events {
+                                events.emit(ConnectionEvent::Suspect(peer));
+                            }
+                            break;
+                        }
+                        let id = next_id.fetch_add(1, Ordering::Relaxed);
+                        if sender.send(RPCRequest::new(id, MSG_PING, None).into()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }));
+        }
+
+        Self {
+            sender,
+            pending,
+            next_id,
+            closed,
+            tasks,
+            compressor: Arc::clone(&client.compressor),
+            compression: None,
+        }
+    }
+
+    /// The connection can take new calls or not
+    fn is_open(&self) -> bool {
+        !self.closed.load(Ordering::Acquire)
+    }
+
+    /// Send the request and wait for its response
+    ///
+    /// The request carries the time left to the deadline. It's cancelled on
+    /// the server if it's timed out or the future is dropped before the response.
+    async fn call(&self, mut request: RPCRequest, deadline: time::Instant, timeout: Duration) -> anyhow::Result<RPCResponse> {
+        // The wire id is unique in the connection, the caller's id is restored in the response
+        let caller_id = request.id;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        request.id = id;
+        let left = deadline.saturating_duration_since(time::Instant::now());
+        request.timeout = Some(request.timeout.map_or(left, |timeout| timeout.min(left)));
+        self.compressor.compress(self.compression, &mut request.header, &mut request.body);
+
+        let (call, response) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, call);
+        let mut guard = CancelGuard { connection: self, id, done: false };
+        if self.sender.send(request.into()).is_err() {
+            guard.done = true;
+            self.pending.lock().unwrap().remove(&id);
+            return Err(anyhow!("rpc connection is closed"));
+        }
+
+        let result = time::timeout_at(deadline, response).await;
+        guard.done = result.is_ok();
+        match result {
+            Ok(Ok(mut response)) => {
+                response.id = caller_id;
+                self.compressor.decompress(&mut response.header, &mut response.body)?;
+                Ok(response)
+            }
+            Ok(Err(_)) => Err(anyhow!("rpc connection is closed before the response of request {}", caller_id)),
+            Err(_) => Err(anyhow!("rpc request {} timed out after {:?}", caller_id, timeout)),
+        }
+    }
+
+    /// Close the connection, and fail the pending calls
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::Release);
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Cancel the pending call on drop unless it's done
+#[derive(Debug)]
+struct CancelGuard<'a> {
+    /// The connection of the call
+    connection: &'a ClientConnection,
+    /// The wire id of the call
+    id: u64,
+    /// The call is responded, or failed without being sent
+    done: bool,
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.connection.pending.lock().unwrap().remove(&self.id);
+            // The connection is closed if it fails, then the request is aborted with it
+            let _ = self.connection.sender.send(RPCRequest::new(self.id, MSG_CANCEL, None).into());
+        }
+    }
+}
+
 /// RPC client module
+///
+/// It keeps a pool of persistent connections to the server, and the
+/// concurrent requests are multiplexed over them. A broken connection is
+/// replaced on the next request, and its pending requests fail at once.
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct RPCClient {
     server_ip: String,
     server_port: u16,
+    /// The timeout of a request in milliseconds
     timeout: u64,
-    close: bool,
+    /// The client is closed or not
+    close: AtomicBool,
+    /// The connection slots, connected lazily
+    pool: Vec<tokio::sync::Mutex<Option<Arc<ClientConnection>>>>,
+    /// The slot of the next request
+    next: AtomicUsize,
+    /// The TLS context, the connections are plain if it's None
+    tls: Option<Arc<TlsContext>>,
+    /// The compression of the requests, disabled by default
+    compressor: Arc<Compressor>,
+    /// The id of the next typed request
+    next_id: AtomicU64,
+    /// The heartbeats of the connections, None if they are disabled
+    heartbeat: Option<Heartbeat>,
+    /// Called on the connection events
+    events: Option<EventHook>,
+    /// No request is sent until it's passed, as the server is busy
+    busy_until: Mutex<Option<time::Instant>>,
+    /// The retries of a request rejected as busy
+    busy_retries: usize,
 }
 
 impl RPCClient {
-    /// Create a new RPC client
+    /// Create a new RPC client, the timeout is in milliseconds
     pub fn new(server_ip: String, server_port: u16, timeout: u64) -> Self {
         Self {
             server_ip,
             server_port,
             timeout,
-            close: false,
+            close: AtomicBool::new(false),
+            pool: (0..DEFAULT_POOL_SIZE).map(|_| tokio::sync::Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            tls: None,
+            compressor: Arc::new(Compressor::disabled()),
+            next_id: AtomicU64::new(0),
+            heartbeat: None,
+            events: None,
+            busy_until: Mutex::new(None),
+            busy_retries: DEFAULT_BUSY_RETRIES,
+        }
+    }
+
+    /// Connect to the server over TLS with the client certificate
+    pub fn with_tls(mut self, tls: Arc<TlsContext>) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Set the number of connections to the server
+    pub fn with_pool_size(mut self, pool_size: usize) -> Self {
+        self.pool = (0..pool_size.max(1)).map(|_| tokio::sync::Mutex::new(None)).collect();
+        self
+    }
+
+    /// Offer the compression algorithms to the server, in preference order
+    ///
+    /// The request bodies smaller than the threshold are sent raw. The
+    /// responses are decompressed anyway.
+    pub fn with_compression(mut self, algorithms: Vec<Compression>, threshold: usize) -> Self {
+        self.compressor = Arc::new(Compressor::new(algorithms, threshold));
+        self
+    }
+
+    /// Ping the server at the interval on every connection
+    ///
+    /// If nothing is received for `HEARTBEAT_MISSES` intervals, the server is
+    /// suspected and the connection is closed, its pending requests fail.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(Heartbeat::new(interval));
+        self
+    }
+
+    /// Set the hook called on the connection events, e.g. the server is suspected
+    pub fn with_event_hook(mut self, events: EventHook) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Set the retries of a request rejected as busy, 0 to return the busy response at once
+    pub fn with_busy_retries(mut self, retries: usize) -> Self {
+        self.busy_retries = retries;
+        self
+    }
+
+    /// Get the compression metrics of the requests
+    pub fn compression_metrics(&self) -> CompressionMetrics {
+        self.compressor.metrics()
+    }
+
+    /// The timeout of a request
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout)
+    }
+
+    /// The address of the server
+    fn peer(&self) -> String {
+        format!("{}:{}", self.server_ip, self.server_port)
+    }
+
+    /// The time until which the server is busy, None if it's not
+    fn busy_until(&self) -> Option<time::Instant> {
+        let mut busy_until = self.busy_until.lock().unwrap();
+        if busy_until.map_or(false, |until| until <= time::Instant::now()) {
+            *busy_until = None;
+        }
+        *busy_until
+    }
+
+    /// Hold the requests off for the retry-after hint of the server
+    fn back_off(&self, retry_after: Duration) {
+        let until = time::Instant::now() + retry_after;
+        let mut busy_until = self.busy_until.lock().unwrap();
+        *busy_until = Some(busy_until.map_or(until, |busy_until| busy_until.max(until)));
+    }
+
+    /// Call the event hook if it's set
+    fn emit(&self, event: ConnectionEvent) {
+        if let Some(ref events) = self.events {
+            events.emit(event);
         }
     }
 
     /// Send a request to the server
-    pub async fn send_request(&self, _request: RPCRequest) -> anyhow::Result<RPCResponse> {
-        todo!()
+    ///
+    /// The response has the id of the request. The request fails if it's not
+    /// responded in the timeout, or the connection is broken in the meantime.
+    ///
+    /// If the server is busy, no request is sent until its retry-after hint is
+    /// passed, and the rejected request is retried then. The busy response is
+    /// returned if the retries run out, or the hint is beyond the timeout.
+    pub async fn send_request(&self, request: RPCRequest) -> anyhow::Result<RPCResponse> {
+        let timeout = self.timeout();
+        let deadline = time::Instant::now() + timeout;
+        let mut retries = 0;
+        loop {
+            if let Some(until) = self.busy_until() {
+                if until > deadline {
+                    let message_type = request.message_type().unwrap_or_default();
+                    return Ok(RPCResponse::busy(request.id, message_type, until - time::Instant::now()));
+                }
+                time::sleep_until(until).await;
+            }
+
+            let connection = match time::timeout_at(deadline, self.connection(deadline)).await {
+                Ok(connection) => connection?,
+                Err(_) => {
+                    self.emit(ConnectionEvent::Suspect(self.peer()));
+                    return Err(anyhow!("rpc connect to {} timed out after {:?}", self.peer(), timeout));
+                }
+            };
+            let response = connection.call(request.clone(), deadline, timeout).await?;
+            let Some(retry_after) = response.retry_after() else {
+                return Ok(response);
+            };
+            debug!("RPC server {} is busy, retry after {:?}", self.peer(), retry_after);
+            self.back_off(retry_after);
+            if retries >= self.busy_retries {
+                return Ok(response);
+            }
+            retries += 1;
+        }
+    }
+
+    /// Send a typed message and wait for the typed response, encoded by `BincodeCodec`
+    pub async fn call<Req, Resp>(&self, message_type: u16, message: &Req) -> anyhow::Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        self.call_with(&BincodeCodec, message_type, message).await
+    }
+
+    /// Send a typed message and wait for the typed response, encoded by the codec
+    ///
+    /// An error response is returned as an error with its message.
+    pub async fn call_with<C, Req, Resp>(&self, codec: &C, message_type: u16, message: &Req) -> anyhow::Result<Resp>
+    where
+        C: MessageCodec,
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let response = self.send_request(RPCRequest::new(id, message_type, Some(codec.encode(message)?.into()))).await?;
+        if response.is_error() {
+            let msg = response.msg.unwrap_or_default();
+            return Err(anyhow!("rpc request {} failed: {}", id, String::from_utf8_lossy(&msg)));
+        }
+        codec.decode(response.body.as_deref().unwrap_or_default())
+    }
+
+    /// Close the connections, the pending requests fail
+    pub async fn close(&self) {
+        self.close.store(true, Ordering::Release);
+        for slot in &self.pool {
+            if let Some(connection) = slot.lock().await.take() {
+                connection.shutdown();
+            }
+        }
+    }
+
+    /// Get an open connection of the next slot, connect if it's not
+    async fn connection(&self, deadline: time::Instant) -> anyhow::Result<Arc<ClientConnection>> {
+        if self.close.load(Ordering::Acquire) {
+            return Err(anyhow!("rpc client is closed"));
+        }
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.pool.len();
+        let mut slot = self.pool[index].lock().await;
+        if let Some(ref connection) = *slot {
+            if connection.is_open() {
+                return Ok(Arc::clone(connection));
+            }
+        }
+
+        debug!("RPC client connecting to {}", self.peer());
+        let connection = match ClientConnection::connect(self, deadline).await {
+            Ok(connection) => Arc::new(connection),
+            Err(e) => {
+                self.emit(ConnectionEvent::Suspect(self.peer()));
+                return Err(e);
+            }
+        };
+        self.emit(ConnectionEvent::Connected(self.peer()));
+        *slot = Some(Arc::clone(&connection));
+        Ok(connection)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::rpc::message::JsonCodec;
+    use crate::rpc::server::RPCServer;
+
+    async fn echo_server(port: u16, drain_timeout: Duration) -> RPCServer {
+        let server = RPCServer::new("127.0.0.1".to_owned(), port).with_drain_timeout(drain_timeout);
+        server.register(1, |request: RPCRequest| async move { Ok(request.body) });
+        server.register(2, |request: RPCRequest| async move {
+            time::sleep(Duration::from_millis(200)).await;
+            Ok(request.body)
+        });
+        server.start().await.unwrap();
+        server
+    }
+
+    #[tokio::test]
+    async fn test_client_multiplexing() {
+        let server = echo_server(0, Duration::from_secs(1)).await;
+        let client = RPCClient::new("127.0.0.1".to_owned(), server.local_addr().unwrap().port(), 1000).with_pool_size(2);
+
+        // The caller's ids are kept even if they are duplicated
+        let responses = future::join_all((0..50_u64).map(|i| {
+            client.send_request(RPCRequest::new(i % 10, 1, Some(Bytes::copy_from_slice(&i.to_be_bytes()))))
+        }))
+        .await;
+        for (i, response) in (0..50_u64).zip(responses) {
+            let response = response.unwrap();
+            assert_eq!(response.id, i % 10);
+            assert_eq!(response.body.as_deref(), Some(i.to_be_bytes().as_slice()));
+        }
+        assert_eq!(server.connection_count(), 2);
+
+        // Timed out, and the late response is ignored
+        let client = RPCClient::new("127.0.0.1".to_owned(), server.local_addr().unwrap().port(), 50);
+        // The server may respond the deadline error before the client times out
+        let result = client.send_request(RPCRequest::new(1, 2, None)).await;
+        assert!(result.map_or(true, |response| response.is_error()));
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(client.send_request(RPCRequest::new(2, 1, None)).await.is_ok());
+
+        client.close().await;
+        assert!(client.send_request(RPCRequest::new(3, 1, None)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnect() {
+        let server = echo_server(0, Duration::from_millis(50)).await;
+        let port = server.local_addr().unwrap().port();
+        let client = RPCClient::new("127.0.0.1".to_owned(), port, 5000).with_pool_size(1);
+        assert!(client.send_request(RPCRequest::new(1, 1, None)).await.is_ok());
+
+        // The pending request fails when the connection is closed, long before the timeout
+        let start = time::Instant::now();
+        let (result, stopped) = tokio::join!(client.send_request(RPCRequest::new(2, 2, None)), async {
+            time::sleep(Duration::from_millis(20)).await;
+            server.stop().await
+        });
+        stopped.unwrap();
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Reconnect to the restarted server
+        let server = echo_server(port, Duration::from_millis(50)).await;
+        assert_eq!(client.send_request(RPCRequest::new(3, 1, None)).await.unwrap().id, 3);
+        assert_eq!(server.connection_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_compression() {
+        let server = echo_server(0, Duration::from_secs(1)).await;
+        let port = server.local_addr().unwrap().port();
+        let body = Bytes::from(b"cached object ".repeat(1000));
+
+        let client = RPCClient::new("127.0.0.1".to_owned(), port, 1000).with_compression(vec![Compression::Zstd], 1024);
+        for (id, body) in [(1, body.clone()), (2, Bytes::from(vec![0; 100]))] {
+            let response = client.send_request(RPCRequest::new(id, 1, Some(body.clone()))).await.unwrap();
+            assert_eq!(response.header, RPCResponse::new(id, 1, None, None).header);
+            assert_eq!(response.body, Some(body));
+        }
+        let metrics = client.compression_metrics();
+        assert_eq!((metrics.compressed, metrics.raw), (1, 1));
+        assert!(metrics.bytes_saved > 10000);
+        assert_eq!(server.compression_metrics(), metrics);
+
+        // Nothing in common, the bodies are raw
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0).with_compression(vec![Compression::Lz4], 0);
+        server.register(1, |request: RPCRequest| async move { Ok(request.body) });
+        server.start().await.unwrap();
+        let client = RPCClient::new("127.0.0.1".to_owned(), server.local_addr().unwrap().port(), 1000)
+            .with_compression(vec![Compression::Zstd], 0);
+        let response = client.send_request(RPCRequest::new(3, 1, Some(body.clone()))).await.unwrap();
+        assert_eq!(response.body, Some(body));
+        assert_eq!(client.compression_metrics(), CompressionMetrics::default());
+        assert_eq!(server.compression_metrics(), CompressionMetrics::default());
+    }
+
+    #[tokio::test]
+    async fn test_client_typed_call() {
+        #[derive(Debug, PartialEq, Serialize, serde::Deserialize)]
+        struct Lookup {
+            key: String,
+        }
+
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0);
+        server.register_typed(1, |lookup: Lookup| async move {
+            match lookup.key.as_str() {
+                "missing" => Err(anyhow!("{} is not found", lookup.key)),
+                _ => Ok(Some(lookup.key.len() as u64)),
+            }
+        });
+        server.register_with(JsonCodec, 2, |lookup: Lookup| async move { Ok(lookup) });
+        server.start().await.unwrap();
+        let client = RPCClient::new("127.0.0.1".to_owned(), server.local_addr().unwrap().port(), 1000);
+
+        let size: Option<u64> = client.call(1, &Lookup { key: "object".to_owned() }).await.unwrap();
+        assert_eq!(size, Some(6));
+        let error = client.call::<_, Option<u64>>(1, &Lookup { key: "missing".to_owned() }).await.unwrap_err();
+        assert!(error.to_string().contains("missing is not found"));
+        let lookup = Lookup { key: "json".to_owned() };
+        assert_eq!(client.call_with::<_, _, Lookup>(&JsonCodec, 2, &lookup).await.unwrap(), lookup);
+
+        // Decoded by another codec
+        assert!(client.call::<_, Lookup>(2, &lookup).await.is_err());
+        assert!(client.call::<_, Option<u64>>(1, &42_u8).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_cancel() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0);
+        let finished = Arc::new(AtomicBool::new(false));
+        let handled = Arc::clone(&finished);
+        let count = Arc::new(AtomicUsize::new(0));
+        let started = Arc::clone(&count);
+        server.register(1, move |_request: RPCRequest| {
+            started.fetch_add(1, Ordering::AcqRel);
+            let handled = Arc::clone(&handled);
+            async move {
+                time::sleep(Duration::from_millis(200)).await;
+                handled.store(true, Ordering::Release);
+                Ok(None)
+            }
+        });
+        server.start().await.unwrap();
+        let client = RPCClient::new("127.0.0.1".to_owned(), server.local_addr().unwrap().port(), 50);
+
+        // The handler is aborted on the client timeout, or when the caller gives up
+        let result = client.send_request(RPCRequest::new(1, 1, None)).await;
+        assert!(result.map_or(true, |response| response.is_error()));
+        let client = RPCClient::new("127.0.0.1".to_owned(), server.local_addr().unwrap().port(), 5000);
+        assert!(time::timeout(Duration::from_millis(50), client.send_request(RPCRequest::new(2, 1, None))).await.is_err());
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(count.load(Ordering::Acquire), 2);
+        assert!(!finished.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn test_client_heartbeat() {
+        // The peer accepts the connection but never responds, like a half open connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        let client = RPCClient::new("127.0.0.1".to_owned(), port, 5000)
+            .with_heartbeat(Duration::from_millis(20))
+            .with_event_hook(EventHook::new(move |event| recorded.lock().unwrap().push(event)));
+
+        // The pending request fails once the heartbeats are missed, long before the timeout
+        let start = time::Instant::now();
+        assert!(client.send_request(RPCRequest::new(1, 1, None)).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let peer = format!("127.0.0.1:{}", port);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![ConnectionEvent::Connected(peer.clone()), ConnectionEvent::Suspect(peer)]
+        );
+        drop(accepted);
+    }
+
+    #[tokio::test]
+    async fn test_client_busy() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0).with_max_in_flight(1, 1, Duration::from_millis(100));
+        server.register(1, |request: RPCRequest| async move {
+            time::sleep(Duration::from_millis(50)).await;
+            Ok(request.body)
+        });
+        server.start().await.unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        // The rejected request is retried after the hint
+        let client = RPCClient::new("127.0.0.1".to_owned(), port, 1000).with_pool_size(1);
+        let start = time::Instant::now();
+        let responses = future::join_all((0..2).map(|id| client.send_request(RPCRequest::new(id, 1, None)))).await;
+        assert!(responses.into_iter().all(|response| !response.unwrap().is_error()));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(server.rejected_count(), 1);
+
+        // Without retries the busy response is returned, and the next request waits for the hint
+        let client = RPCClient::new("127.0.0.1".to_owned(), port, 1000).with_pool_size(1).with_busy_retries(0);
+        let responses = future::join_all((0..2).map(|id| client.send_request(RPCRequest::new(id, 1, None)))).await;
+        let busy: Vec<_> = responses.into_iter().filter_map(|response| response.unwrap().retry_after()).collect();
+        assert_eq!(busy, vec![Duration::from_millis(100)]);
+        let start = time::Instant::now();
+        assert!(!client.send_request(RPCRequest::new(2, 1, None)).await.unwrap().is_error());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(server.rejected_count(), 2);
+
+        // The hint is beyond the timeout, the busy response is returned without sending
+        let client = RPCClient::new("127.0.0.1".to_owned(), port, 20).with_pool_size(1);
+        let responses = future::join_all((0..2).map(|id| client.send_request(RPCRequest::new(id, 1, None)))).await;
+        // The admitted one may time out
+        assert!(responses.iter().any(|response| response.as_ref().map_or(false, |response| response.retry_after().is_some())));
+        let response = client.send_request(RPCRequest::new(3, 1, None)).await.unwrap();
+        assert_eq!(response.id, 3);
+        assert!(response.retry_after().is_some());
+        assert_eq!(server.rejected_count(), 3);
+    }
+}
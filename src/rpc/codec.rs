@@ -0,0 +1,509 @@
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::protocol::{Header, ProtocolError, FLAG_RESPONSE};
+use super::{RPCRequest, RPCResponse};
+
+/// Default max size of a frame, excluding the length prefix
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Size of the length prefix
+const LENGTH_SIZE: usize = 4;
+
+/// Size of the fixed fields after the length prefix: id, header and presence
+const FIXED_SIZE: usize = 8 + 8 + 1;
+
+/// Size of the timeout field
+const TIMEOUT_SIZE: usize = 4;
+
+/// The `msg` field is present
+const PRESENT_MSG: u8 = 1 << 0;
+
+/// The `body` field is present
+const PRESENT_BODY: u8 = 1 << 1;
+
+/// The `timeout` field is present
+const PRESENT_TIMEOUT: u8 = 1 << 2;
+
+/// Max number of queued frames written together
+const MAX_BATCH: usize = 64;
+
+/// A frame on the wire
+#[derive(Debug, Clone)]
+pub enum RPCFrame {
+    /// A request
+    Request(RPCRequest),
+    /// A response
+    Response(RPCResponse),
+}
+
+impl From<RPCRequest> for RPCFrame {
+    fn from(request: RPCRequest) -> Self {
+        Self::Request(request)
+    }
+}
+
+impl From<RPCResponse> for RPCFrame {
+    fn from(response: RPCResponse) -> Self {
+        Self::Response(response)
+    }
+}
+
+/// Length prefixed codec of `RPCFrame`
+///
+/// A frame is laid out in big endian as:
+///
+/// ```text
+/// +--------------+----------+--------------+---------------+-----------------+-----------------------+--------+
+/// | length (u32) | id (u64) | header (u64) | presence (u8) | [timeout (u32)] | [msg len (u32) | msg] | [body] |
+/// +--------------+----------+--------------+---------------+-----------------+-----------------------+--------+
+/// ```
+///
+/// - length: the size of the frame after the length field, up to the max frame size.
+/// - header: see `Header`, `FLAG_RESPONSE` tells a response from a request.
+/// - presence: bit 0 is set if `msg` is present, bit 1 if `body` is present, bit 2 if `timeout` is present.
+/// - timeout: only in requests, the milliseconds left to the deadline of the request, rounded up.
+/// - msg: only in responses, prefixed with its length.
+/// - body: the rest of the frame.
+///
+/// The decoded `msg` and `body` share the read buffer rather than copy it.
+#[derive(Debug, Clone)]
+pub struct RPCCodec {
+    /// The max frame size, excluding the length prefix
+    max_frame_size: usize,
+}
+
+impl Default for RPCCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl RPCCodec {
+    /// Create a new codec with the max frame size
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    /// Get the max frame size
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Write the head of a frame, the fields but the body of `body_len` bytes following it
+    fn encode_fields(
+        &self,
+        id: u64,
+        header: u64,
+        timeout: Option<Duration>,
+        msg: Option<&[u8]>,
+        body_len: Option<usize>,
+        dst: &mut BytesMut,
+    ) -> Result<(), ProtocolError> {
+        let size = FIXED_SIZE
+            + timeout.map_or(0, |_| TIMEOUT_SIZE)
+            + msg.map_or(0, |msg| LENGTH_SIZE + msg.len())
+            + body_len.unwrap_or(0);
+        if size > self.max_frame_size {
+            return Err(ProtocolError::FrameTooLarge { size, max: self.max_frame_size });
+        }
+        let msg_len = msg.map(|msg| u32::try_from(msg.len())).transpose().map_err(|_| ProtocolError::FrameTooLarge {
+            size,
+            max: self.max_frame_size,
+        })?;
+        let length = u32::try_from(size).map_err(|_| ProtocolError::FrameTooLarge { size, max: self.max_frame_size })?;
+
+        let mut presence = 0;
+        if msg.is_some() {
+            presence |= PRESENT_MSG;
+        }
+        if body_len.is_some() {
+            presence |= PRESENT_BODY;
+        }
+        if timeout.is_some() {
+            presence |= PRESENT_TIMEOUT;
+        }
+
+        dst.reserve(LENGTH_SIZE + size - body_len.unwrap_or(0));
+        dst.put_u32(length);
+        dst.put_u64(id);
+        dst.put_u64(header);
+        dst.put_u8(presence);
+        if let Some(timeout) = timeout {
+            // Rounded up, so the time left under 1 millisecond is not taken as expired
+            dst.put_u32(u32::try_from(timeout.as_micros().div_ceil(1000)).unwrap_or(u32::MAX));
+        }
+        if let (Some(msg), Some(msg_len)) = (msg, msg_len) {
+            dst.put_u32(msg_len);
+            dst.put_slice(msg);
+        }
+
+        Ok(())
+    }
+
+    /// Write the head of the frame, and return the body to write after it
+    fn encode_head(&self, frame: &RPCFrame, dst: &mut BytesMut) -> Result<Option<Bytes>, ProtocolError> {
+        match *frame {
+            RPCFrame::Request(ref request) => {
+                if Header::decode(request.header)?.has_flag(FLAG_RESPONSE) {
+                    return Err(ProtocolError::Malformed("request with the response flag"));
+                }
+                let body_len = request.body.as_ref().map(Bytes::len);
+                self.encode_fields(request.id, request.header, request.timeout, None, body_len, dst)?;
+                Ok(request.body.clone())
+            }
+            RPCFrame::Response(ref response) => {
+                if !Header::decode(response.header)?.has_flag(FLAG_RESPONSE) {
+                    return Err(ProtocolError::Malformed("response without the response flag"));
+                }
+                let body_len = response.body.as_ref().map(Bytes::len);
+                self.encode_fields(response.id, response.header, None, response.msg.as_deref(), body_len, dst)?;
+                Ok(response.body.clone())
+            }
+        }
+    }
+}
+
+/// Check the frame has `needed` bytes left
+fn ensure(frame: &Bytes, needed: usize) -> Result<(), ProtocolError> {
+    if frame.len() < needed {
+        return Err(ProtocolError::Truncated { needed, remaining: frame.len() });
+    }
+    Ok(())
+}
+
+impl Decoder for RPCCodec {
+    type Item = RPCFrame;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+
+        let mut length = [0; LENGTH_SIZE];
+        length.copy_from_slice(&src[..LENGTH_SIZE]);
+        let size = u32::from_be_bytes(length) as usize;
+        if size > self.max_frame_size {
+            return Err(ProtocolError::FrameTooLarge { size, max: self.max_frame_size });
+        }
+        if size < FIXED_SIZE {
+            return Err(ProtocolError::Truncated { needed: FIXED_SIZE, remaining: size });
+        }
+        if src.len() < LENGTH_SIZE + size {
+            // Wait for the rest of the frame
+            src.reserve(LENGTH_SIZE + size - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_SIZE);
+        let mut frame = src.split_to(size).freeze();
+        let id = frame.get_u64();
+        let header = frame.get_u64();
+        let decoded = Header::decode(header)?;
+        let presence = frame.get_u8();
+
+        let timeout = if presence & PRESENT_TIMEOUT == 0 {
+            None
+        } else {
+            ensure(&frame, TIMEOUT_SIZE)?;
+            Some(Duration::from_millis(u64::from(frame.get_u32())))
+        };
+        let msg = if presence & PRESENT_MSG == 0 {
+            None
+        } else {
+            ensure(&frame, LENGTH_SIZE)?;
+            let msg_len = frame.get_u32() as usize;
+            ensure(&frame, msg_len)?;
+            Some(frame.split_to(msg_len))
+        };
+        let body = if presence & PRESENT_BODY == 0 {
+            if !frame.is_empty() {
+                return Err(ProtocolError::Malformed("trailing bytes without body"));
+            }
+            None
+        } else {
+            Some(frame)
+        };
+
+        if decoded.has_flag(FLAG_RESPONSE) {
+            if timeout.is_some() {
+                return Err(ProtocolError::Malformed("response with timeout"));
+            }
+            Ok(Some(RPCFrame::Response(RPCResponse { id, header, msg, body })))
+        } else if msg.is_some() {
+            Err(ProtocolError::Malformed("request with msg"))
+        } else {
+            Ok(Some(RPCFrame::Request(RPCRequest { id, header, timeout, body })))
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            // The stream is closed in the middle of a frame
+            None => {
+                let needed = if src.len() < LENGTH_SIZE {
+                    LENGTH_SIZE
+                } else {
+                    let mut length = [0; LENGTH_SIZE];
+                    length.copy_from_slice(&src[..LENGTH_SIZE]);
+                    LENGTH_SIZE + u32::from_be_bytes(length) as usize
+                };
+                Err(ProtocolError::Truncated { needed, remaining: src.len() })
+            }
+        }
+    }
+}
+
+impl Encoder<RPCFrame> for RPCCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, item: RPCFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if let Some(body) = self.encode_head(&item, dst)? {
+            dst.put_slice(&body);
+        }
+        Ok(())
+    }
+}
+
+/// The heads and bodies of the frames to write, in order
+#[derive(Debug, Default)]
+struct Segments {
+    /// The non empty segments
+    segments: VecDeque<Bytes>,
+    /// The bytes left
+    remaining: usize,
+}
+
+impl Segments {
+    /// Append a segment
+    fn push(&mut self, segment: Bytes) {
+        if !segment.is_empty() {
+            self.remaining += segment.len();
+            self.segments.push_back(segment);
+        }
+    }
+}
+
+impl Buf for Segments {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.segments.front().map_or(&[], |segment| segment.as_ref())
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut count = 0;
+        for (slice, segment) in dst.iter_mut().zip(&self.segments) {
+            *slice = IoSlice::new(segment);
+            count += 1;
+        }
+        count
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        self.remaining -= cnt;
+        while let Some(segment) = self.segments.front_mut() {
+            if cnt < segment.len() {
+                segment.advance(cnt);
+                return;
+            }
+            cnt -= segment.len();
+            self.segments.pop_front();
+        }
+    }
+}
+
+/// Writer of `RPCFrame` with vectored writes
+///
+/// Unlike `FramedWrite`, only the heads are encoded into a buffer, the bodies
+/// are written from where they are, e.g. the cache, without being copied.
+/// The queued frames are written together in as few system calls as possible.
+#[derive(Debug)]
+pub struct FrameWriter<W> {
+    /// The underlying writer
+    writer: W,
+    /// The codec encoding the heads
+    codec: RPCCodec,
+    /// The buffer of the heads
+    heads: BytesMut,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Create a new frame writer
+    pub fn new(writer: W, codec: RPCCodec) -> Self {
+        Self {
+            writer,
+            codec,
+            heads: BytesMut::new(),
+        }
+    }
+
+    /// Write the frames and flush, fail if any of them is invalid
+    pub async fn write_frames<I>(&mut self, frames: I) -> Result<(), ProtocolError>
+    where
+        I: IntoIterator<Item = RPCFrame>,
+    {
+        let mut segments = Segments::default();
+        for frame in frames {
+            let body = self.codec.encode_head(&frame, &mut self.heads)?;
+            segments.push(self.heads.split().freeze());
+            if let Some(body) = body {
+                segments.push(body);
+            }
+        }
+
+        self.writer.write_all_buf(&mut segments).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Write the frames from the receiver until all the senders are dropped
+    ///
+    /// The frames queued meanwhile are written in a batch.
+    pub async fn forward(&mut self, receiver: &mut mpsc::UnboundedReceiver<RPCFrame>) -> Result<(), ProtocolError> {
+        let mut frames = Vec::with_capacity(MAX_BATCH);
+        while let Some(frame) = receiver.recv().await {
+            frames.push(frame);
+            while frames.len() < MAX_BATCH {
+                match receiver.try_recv() {
+                    Ok(frame) => frames.push(frame),
+                    Err(_) => break,
+                }
+            }
+            self.write_frames(frames.drain(..)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(codec: &mut RPCCodec, frame: RPCFrame) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec.encode(frame, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let mut codec = RPCCodec::default();
+        let request = RPCRequest::new(1, 7, Some(Bytes::from_static(b"key"))).with_timeout(Duration::from_millis(1500));
+        let mut buf = encode(&mut codec, request.into());
+        buf.extend_from_slice(&encode(&mut codec, RPCRequest::new(2, 7, None).into()));
+        buf.extend_from_slice(&encode(&mut codec, RPCResponse::new(1, 7, Some(Bytes::from_static(b"ok")), Some(Bytes::new())).into()));
+
+        // Frames arrive byte by byte
+        let mut src = BytesMut::new();
+        let mut frames = Vec::new();
+        for byte in buf {
+            src.put_u8(byte);
+            if let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert!(src.is_empty());
+        assert_eq!(frames.len(), 3);
+
+        match (&frames[0], &frames[1], &frames[2]) {
+            (RPCFrame::Request(first), RPCFrame::Request(second), RPCFrame::Response(response)) => {
+                assert_eq!((first.id, first.message_type().unwrap(), first.body.as_deref()), (1, 7, Some(b"key".as_slice())));
+                assert_eq!(first.timeout, Some(Duration::from_millis(1500)));
+                assert_eq!((second.id, second.timeout, second.body.as_deref()), (2, None, None));
+                assert_eq!(response.id, 1);
+                assert_eq!(response.msg.as_deref(), Some(b"ok".as_slice()));
+                assert_eq!(response.body.as_deref(), Some([].as_slice()));
+            }
+            frames => panic!("unexpected frames {:?}", frames),
+        }
+    }
+
+    #[test]
+    fn test_codec_timeout_round_up() {
+        let mut codec = RPCCodec::default();
+        for (timeout, encoded) in [(Duration::from_micros(500), 1), (Duration::from_micros(1001), 2), (Duration::ZERO, 0)] {
+            let mut buf = encode(&mut codec, RPCRequest::new(1, 7, None).with_timeout(timeout).into());
+            match codec.decode(&mut buf).unwrap() {
+                Some(RPCFrame::Request(request)) => assert_eq!(request.timeout, Some(Duration::from_millis(encoded))),
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_writer() {
+        let frames: Vec<RPCFrame> = vec![
+            RPCRequest::new(1, 7, Some(Bytes::from(vec![1; 1000]))).with_timeout(Duration::from_millis(10)).into(),
+            RPCRequest::new(2, 7, Some(Bytes::new())).into(),
+            RPCResponse::new(1, 7, Some(Bytes::from_static(b"ok")), Some(Bytes::from(vec![2; 5000]))).into(),
+            RPCResponse::new(2, 7, None, None).into(),
+        ];
+        let mut expected = BytesMut::new();
+        let mut codec = RPCCodec::default();
+        for frame in frames.clone() {
+            codec.encode(frame, &mut expected).unwrap();
+        }
+
+        // Written in pieces through a small pipe
+        let (writer, mut reader) = tokio::io::duplex(64);
+        let mut writer = FrameWriter::new(writer, RPCCodec::default());
+        let (written, read) = tokio::join!(async move { writer.write_frames(frames).await }, async move {
+            let mut read = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut read).await.map(|_| read)
+        });
+        written.unwrap();
+        assert_eq!(read.unwrap(), expected);
+
+        // The decoded body shares the read buffer
+        let mut src = encode(&mut codec, RPCResponse::new(3, 7, None, Some(Bytes::from_static(b"body"))).into());
+        let start = src.as_ptr() as usize;
+        match codec.decode(&mut src).unwrap() {
+            Some(RPCFrame::Response(response)) => {
+                assert_eq!(response.body.as_ref().unwrap().as_ptr() as usize, start + LENGTH_SIZE + FIXED_SIZE);
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn test_codec_errors() {
+        let mut codec = RPCCodec::new(64);
+
+        // Oversized frames are rejected on both sides
+        let request = RPCRequest::new(1, 7, Some(Bytes::from(vec![0; 64])));
+        assert!(matches!(codec.encode(request.into(), &mut BytesMut::new()), Err(ProtocolError::FrameTooLarge { .. })));
+        let mut src = BytesMut::from(&[0, 0, 1, 0][..]);
+        assert!(matches!(codec.decode(&mut src), Err(ProtocolError::FrameTooLarge { size: 256, max: 64 })));
+
+        // Truncated at the end of stream, or inside the frame
+        let mut src = encode(&mut codec, RPCRequest::new(1, 7, Some(Bytes::from_static(b"key"))).into());
+        src.truncate(src.len() - 1);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(matches!(codec.decode_eof(&mut src), Err(ProtocolError::Truncated { .. })));
+        let mut src = encode(&mut codec, RPCResponse::new(1, 7, Some(Bytes::from_static(b"ok")), None).into());
+        src[LENGTH_SIZE + FIXED_SIZE + 3] = 3;
+        assert!(matches!(codec.decode(&mut src), Err(ProtocolError::Truncated { needed: 3, remaining: 2 })));
+        let mut src = BytesMut::from(&[0, 0, 0, 1, 0][..]);
+        assert!(matches!(codec.decode(&mut src), Err(ProtocolError::Truncated { .. })));
+
+        // Unknown versions
+        let mut request = RPCRequest::new(1, 7, None);
+        request.header |= 0xff << 56;
+        let mut src = BytesMut::new();
+        codec.encode_fields(request.id, request.header, None, None, None, &mut src).unwrap();
+        assert!(matches!(codec.decode(&mut src), Err(ProtocolError::UnknownVersion(0xff))));
+        assert!(matches!(codec.encode(request.into(), &mut BytesMut::new()), Err(ProtocolError::UnknownVersion(0xff))));
+    }
+}
@@ -0,0 +1,282 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use tracing::warn;
+
+use super::codec::DEFAULT_MAX_FRAME_SIZE;
+use super::protocol::{Header, ProtocolError, FLAG_LZ4, FLAG_ZSTD};
+
+/// The bodies smaller than it are sent raw by default
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// The compression algorithm of the bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// lz4 block format, fast with a moderate ratio
+    Lz4,
+    /// zstd at the default level, slower with a better ratio
+    Zstd,
+}
+
+impl Compression {
+    /// All the algorithms, in the default preference order
+    pub const ALL: [Compression; 2] = [Compression::Lz4, Compression::Zstd];
+
+    /// The id in the negotiation
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    /// Get the algorithm of the id, None if it's unknown
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|compression| compression.id() == id)
+    }
+
+    /// The header flag of the compressed bodies
+    pub fn flag(self) -> u16 {
+        match self {
+            Compression::Lz4 => FLAG_LZ4,
+            Compression::Zstd => FLAG_ZSTD,
+        }
+    }
+
+    /// Get the algorithm from the header flags, None if the body is raw
+    pub fn from_flags(flags: u16) -> Result<Option<Self>, ProtocolError> {
+        match (flags & FLAG_LZ4 != 0, flags & FLAG_ZSTD != 0) {
+            (false, false) => Ok(None),
+            (true, false) => Ok(Some(Compression::Lz4)),
+            (false, true) => Ok(Some(Compression::Zstd)),
+            (true, true) => Err(ProtocolError::Malformed("multiple compression flags")),
+        }
+    }
+
+    /// Compress the data
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    /// Decompress the data, fail if it's corrupted or larger than `max_size`
+    fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            Compression::Lz4 => {
+                let (size, data) = data
+                    .split_first_chunk::<4>()
+                    .ok_or(ProtocolError::Truncated { needed: 4, remaining: data.len() })?;
+                let size = u32::from_le_bytes(*size) as usize;
+                if size > max_size {
+                    return Err(ProtocolError::FrameTooLarge { size, max: max_size });
+                }
+                lz4_flex::block::decompress(data, size).map_err(|_| ProtocolError::Malformed("corrupted lz4 body"))
+            }
+            Compression::Zstd => {
+                zstd::bulk::decompress(data, max_size).map_err(|_| ProtocolError::Malformed("corrupted zstd body"))
+            }
+        }
+    }
+}
+
+/// Counters of the sent bodies
+#[derive(Debug, Default)]
+struct CompressionCounters {
+    /// The bodies sent compressed
+    compressed: AtomicU64,
+    /// The bodies sent raw, because they are small or incompressible
+    raw: AtomicU64,
+    /// The size of the compressed bodies before compression
+    original_bytes: AtomicU64,
+    /// The size of the compressed bodies after compression
+    compressed_bytes: AtomicU64,
+}
+
+/// Snapshot of the counters of `Compressor`, for monitoring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionMetrics {
+    /// The bodies sent compressed
+    pub compressed: u64,
+    /// The bodies sent raw, because they are small or incompressible
+    pub raw: u64,
+    /// The size of the compressed bodies before compression
+    pub original_bytes: u64,
+    /// The size of the compressed bodies after compression
+    pub compressed_bytes: u64,
+    /// The bytes not sent thanks to compression
+    pub bytes_saved: u64,
+}
+
+/// The compression of the bodies of one side of the connections
+///
+/// The algorithm of a connection is negotiated once it's connected, the
+/// client offers the algorithms it supports and the server chooses the first
+/// one it supports too. Then the bodies not smaller than the threshold are
+/// compressed, and flagged in the header so the peer knows how to decompress.
+#[derive(Debug)]
+pub struct Compressor {
+    /// The supported algorithms in preference order
+    algorithms: Vec<Compression>,
+    /// The bodies smaller than it are sent raw
+    threshold: usize,
+    /// The counters of the sent bodies
+    counters: CompressionCounters,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::new(Compression::ALL.to_vec(), DEFAULT_COMPRESSION_THRESHOLD)
+    }
+}
+
+impl Compressor {
+    /// Create a new compressor of the supported algorithms in preference order
+    pub fn new(algorithms: Vec<Compression>, threshold: usize) -> Self {
+        Self {
+            algorithms,
+            threshold,
+            counters: CompressionCounters::default(),
+        }
+    }
+
+    /// Create a compressor without any algorithm, the bodies are always raw
+    pub fn disabled() -> Self {
+        Self::new(Vec::new(), usize::MAX)
+    }
+
+    /// The supported algorithms in preference order
+    pub fn algorithms(&self) -> &[Compression] {
+        &self.algorithms
+    }
+
+    /// Get the counters of the sent bodies
+    pub fn metrics(&self) -> CompressionMetrics {
+        let original_bytes = self.counters.original_bytes.load(Ordering::Relaxed);
+        let compressed_bytes = self.counters.compressed_bytes.load(Ordering::Relaxed);
+        CompressionMetrics {
+            compressed: self.counters.compressed.load(Ordering::Relaxed),
+            raw: self.counters.raw.load(Ordering::Relaxed),
+            original_bytes,
+            compressed_bytes,
+            bytes_saved: original_bytes.saturating_sub(compressed_bytes),
+        }
+    }
+
+    /// The body of the hello request, the ids of the supported algorithms
+    pub fn offer(&self) -> Vec<u8> {
+        self.algorithms.iter().map(|compression| compression.id()).collect()
+    }
+
+    /// Choose the first algorithm of the offer that is supported, None if there is none
+    pub fn negotiate(&self, offer: &[u8]) -> Option<Compression> {
+        offer
+            .iter()
+            .filter_map(|&id| Compression::from_id(id))
+            .find(|compression| self.algorithms.contains(compression))
+    }
+
+    /// Compress the body with the negotiated algorithm, and flag it in the header
+    ///
+    /// The body is kept raw if it's smaller than the threshold, or it does not
+    /// get smaller after compression.
+    pub fn compress(&self, compression: Option<Compression>, header: &mut u64, body: &mut Option<Bytes>) {
+        let (Some(compression), Some(data)) = (compression, body.as_ref()) else {
+            return;
+        };
+        let Ok(mut decoded) = Header::decode(*header) else {
+            return;
+        };
+
+        let compressed = if data.len() < self.threshold {
+            None
+        } else {
+            compression
+                .compress(data)
+                .map_err(|e| warn!("RPC body compression with {:?} failed: {:?}", compression, e))
+                .ok()
+                .filter(|compressed| compressed.len() < data.len())
+        };
+        let Some(compressed) = compressed else {
+            self.counters.raw.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        self.counters.compressed.fetch_add(1, Ordering::Relaxed);
+        self.counters.original_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.counters.compressed_bytes.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+        decoded.flags |= compression.flag();
+        *header = decoded.encode();
+        *body = Some(compressed.into());
+    }
+
+    /// Decompress the body if it's flagged in the header, and clear the flag
+    pub fn decompress(&self, header: &mut u64, body: &mut Option<Bytes>) -> Result<(), ProtocolError> {
+        let mut decoded = Header::decode(*header)?;
+        let Some(compression) = Compression::from_flags(decoded.flags)? else {
+            return Ok(());
+        };
+
+        if let Some(data) = body.as_ref() {
+            *body = Some(compression.decompress(data, DEFAULT_MAX_FRAME_SIZE)?.into());
+        }
+        decoded.flags &= !compression.flag();
+        *header = decoded.encode();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::RPCRequest;
+
+    #[test]
+    fn test_compressor() {
+        let compressor = Compressor::new(vec![Compression::Zstd, Compression::Lz4], 64);
+        assert_eq!(compressor.negotiate(&[3, 1, 2]), Some(Compression::Lz4));
+        assert_eq!(compressor.negotiate(&Compressor::default().offer()), Some(Compression::Lz4));
+        assert_eq!(Compressor::disabled().negotiate(&[1, 2]), None);
+
+        for compression in Compression::ALL {
+            let body = Bytes::from(b"cache proxy ".repeat(100));
+            let mut request = RPCRequest::new(1, 7, Some(body.clone()));
+            compressor.compress(Some(compression), &mut request.header, &mut request.body);
+            let header = Header::decode(request.header).unwrap();
+            assert!(header.has_flag(compression.flag()));
+            assert_eq!(header.message_type, 7);
+            assert!(request.body.as_ref().unwrap().len() < body.len());
+
+            compressor.decompress(&mut request.header, &mut request.body).unwrap();
+            assert_eq!(request.header, RPCRequest::new(1, 7, None).header);
+            assert_eq!(request.body, Some(body));
+        }
+
+        // Small, incompressible, or not negotiated
+        let random: Bytes = (0..1024).map(|_| rand::random::<u8>()).collect();
+        for (compression, body) in [
+            (Some(Compression::Lz4), Bytes::from(vec![0; 63])),
+            (Some(Compression::Zstd), random),
+            (None, Bytes::from(vec![0; 1024])),
+        ] {
+            let mut request = RPCRequest::new(1, 7, Some(body.clone()));
+            compressor.compress(compression, &mut request.header, &mut request.body);
+            assert_eq!(request.header, RPCRequest::new(1, 7, None).header);
+            assert_eq!(request.body, Some(body));
+        }
+
+        let metrics = compressor.metrics();
+        assert_eq!((metrics.compressed, metrics.raw), (2, 2));
+        assert_eq!(metrics.original_bytes, 2400);
+        assert_eq!(metrics.bytes_saved, metrics.original_bytes - metrics.compressed_bytes);
+        assert!(metrics.bytes_saved > 2000);
+
+        // Corrupted, or too large after decompression
+        let mut header = Header::new(7, FLAG_LZ4).encode();
+        assert!(compressor.decompress(&mut header, &mut Some(Bytes::from_static(&[4, 0, 0, 0, 0xff]))).is_err());
+        assert!(compressor.decompress(&mut header, &mut Some(Bytes::from_static(&[0xff, 0xff, 0xff, 0xff]))).is_err());
+        let mut header = Header::new(7, FLAG_LZ4 | FLAG_ZSTD).encode();
+        assert!(compressor.decompress(&mut header, &mut Some(Bytes::from_static(&[0]))).is_err());
+    }
+}
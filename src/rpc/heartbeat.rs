@@ -0,0 +1,72 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The heartbeats missed before the peer is suspected, or the connection is idle
+pub const HEARTBEAT_MISSES: u32 = 3;
+
+/// Liveness event of the RPC connections, with the address of the peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// Connected to the peer
+    Connected(String),
+    /// The peer is unreachable, or it missed the heartbeats so the connection may be half open
+    Suspect(String),
+    /// The connection from the peer is closed by the server as it's idle
+    Idle(String),
+}
+
+impl ConnectionEvent {
+    /// Get the address of the peer
+    pub fn peer(&self) -> &str {
+        match *self {
+            ConnectionEvent::Connected(ref peer) | ConnectionEvent::Suspect(ref peer) | ConnectionEvent::Idle(ref peer) => peer,
+        }
+    }
+}
+
+/// Hook called on the connection events, e.g. to mark the peer node as suspect
+///
+/// It's called on the connection tasks, so it should not block.
+#[derive(Clone)]
+pub struct EventHook(Arc<dyn Fn(ConnectionEvent) + Send + Sync>);
+
+impl fmt::Debug for EventHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventHook").finish_non_exhaustive()
+    }
+}
+
+impl EventHook {
+    /// Create a new hook of the function
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: Fn(ConnectionEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(hook))
+    }
+
+    /// Call the hook with the event
+    pub fn emit(&self, event: ConnectionEvent) {
+        (self.0)(event);
+    }
+}
+
+/// Heartbeat settings of the client connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Interval of the pings
+    pub interval: Duration,
+    /// The peer is suspected if nothing is received from it for so long
+    pub timeout: Duration,
+}
+
+impl Heartbeat {
+    /// Create the heartbeat settings of the interval, it times out after `HEARTBEAT_MISSES` intervals
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            timeout: interval * HEARTBEAT_MISSES,
+        }
+    }
+}
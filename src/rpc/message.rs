@@ -0,0 +1,82 @@
+use anyhow::Context;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::codec::DEFAULT_MAX_FRAME_SIZE;
+
+/// Serialization of the typed messages in the request and response bodies
+///
+/// The client and server of a message type must use the same codec, the
+/// wire is still `RPCRequest` and `RPCResponse`.
+pub trait MessageCodec: Send + Sync + 'static {
+    /// Encode the message to a body
+    fn encode<T: Serialize>(&self, message: &T) -> anyhow::Result<Vec<u8>>;
+
+    /// Decode the message from a body, a missing body is decoded from empty bytes
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> anyhow::Result<T>;
+}
+
+/// The default codec, compact binary with variable length integers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl BincodeCodec {
+    /// The bincode options, the size is limited as the frames are
+    fn options() -> impl Options {
+        bincode::DefaultOptions::new().with_limit(DEFAULT_MAX_FRAME_SIZE as u64)
+    }
+}
+
+impl MessageCodec for BincodeCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> anyhow::Result<Vec<u8>> {
+        Self::options().serialize(message).context("failed to encode the message")
+    }
+
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> anyhow::Result<T> {
+        Self::options().deserialize(body).context("failed to decode the message")
+    }
+}
+
+/// The JSON codec, readable when debugging but larger on the wire
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl MessageCodec for JsonCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> anyhow::Result<Vec<u8>> {
+        serde_json::to_vec(message).context("failed to encode the message")
+    }
+
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> anyhow::Result<T> {
+        serde_json::from_slice(body).context("failed to decode the message")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Lookup {
+        key: String,
+        slots: Vec<u32>,
+        version: Option<u64>,
+    }
+
+    #[test]
+    fn test_message_codec() {
+        let message = Lookup { key: "object".to_owned(), slots: vec![1, 300, 70000], version: Some(7) };
+
+        let body = BincodeCodec.encode(&message).unwrap();
+        assert!(body.len() < JsonCodec.encode(&message).unwrap().len());
+        assert_eq!(BincodeCodec.decode::<Lookup>(&body).unwrap(), message);
+        let body = JsonCodec.encode(&message).unwrap();
+        assert_eq!(JsonCodec.decode::<Lookup>(&body).unwrap(), message);
+
+        BincodeCodec.decode::<()>(&[]).unwrap();
+        assert!(BincodeCodec.decode::<Lookup>(&[1, 2]).is_err());
+        assert!(JsonCodec.decode::<Lookup>(b"{}").is_err());
+    }
+}
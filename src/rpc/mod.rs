@@ -2,6 +2,9 @@
 /// 
 /// 1. Support basic RPC request and response
 /// 2. Support file chunk transfer
+/// 3. Support mutual TLS and negotiated compression
+/// 4. Support heartbeats and idle timeouts of the connections
+/// 5. Support admission control, the busy server asks the clients to back off
 
 /// The RPC client
 pub mod client;
@@ -9,6 +12,32 @@ pub mod client;
 /// The RPC server
 pub mod server;
 
+/// The RPC frame header and protocol errors
+pub mod protocol;
+
+/// The RPC frame codec
+pub mod codec;
+
+/// Chunked transfer of large objects
+pub mod chunk;
+
+/// TLS with mutual authentication
+pub mod tls;
+
+/// Negotiated compression of the bodies
+pub mod compress;
+
+/// Serialization of the typed messages
+pub mod message;
+
+/// Connection heartbeats and liveness events
+pub mod heartbeat;
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use protocol::{Header, ProtocolError, FLAG_BUSY, FLAG_ERROR, FLAG_RESPONSE};
+
 /// The RPC request
 #[derive(Debug, Clone)]
 pub struct RPCRequest {
@@ -16,8 +45,39 @@ pub struct RPCRequest {
     pub id: u64,
     /// The request header, contains the version and type
     pub header: u64,
+    /// The time left to the deadline of the request, None if there is no deadline
+    ///
+    /// It's relative so it does not depend on the clocks of the peers, the
+    /// server aborts the handler once it's passed.
+    pub timeout: Option<Duration>,
     /// The request body
-    pub body: Option<Vec<u8>>,
+    ///
+    /// It's reference counted, so it's shared with the read buffer or the
+    /// cache rather than copied.
+    pub body: Option<Bytes>,
+}
+
+impl RPCRequest {
+    /// Create a new request of the message type
+    pub fn new(id: u64, message_type: u16, body: Option<Bytes>) -> Self {
+        Self {
+            id,
+            header: Header::new(message_type, 0).encode(),
+            timeout: None,
+            body,
+        }
+    }
+
+    /// Set the time left to the deadline of the request
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Get the message type from the header
+    pub fn message_type(&self) -> Result<u16, ProtocolError> {
+        Ok(Header::decode(self.header)?.message_type)
+    }
 }
 
 /// The RPC response
@@ -28,7 +88,59 @@ pub struct RPCResponse {
     /// The request header, contains the version and type
     pub header: u64,
     /// The response msg
-    pub msg: Option<Vec<u8>>,
-    /// The request body
-    pub body: Option<Vec<u8>>,
-}
\ No newline at end of file
+    pub msg: Option<Bytes>,
+    /// The response body, shared rather than copied like the request body
+    pub body: Option<Bytes>,
+}
+
+impl RPCResponse {
+    /// Create a new response to the request `id` of the message type
+    pub fn new(id: u64, message_type: u16, msg: Option<Bytes>, body: Option<Bytes>) -> Self {
+        Self {
+            id,
+            header: Header::new(message_type, FLAG_RESPONSE).encode(),
+            msg,
+            body,
+        }
+    }
+
+    /// Create a new error response to the request `id`, the error message is in `msg`
+    pub fn error(id: u64, message_type: u16, error: &anyhow::Error) -> Self {
+        Self {
+            id,
+            header: Header::new(message_type, FLAG_RESPONSE | FLAG_ERROR).encode(),
+            msg: Some(format!("{:#}", error).into()),
+            body: None,
+        }
+    }
+
+    /// Create a new busy response to the request `id`, the request is rejected without being handled
+    pub fn busy(id: u64, message_type: u16, retry_after: Duration) -> Self {
+        let millis = u32::try_from(retry_after.as_millis()).unwrap_or(u32::MAX);
+        Self {
+            id,
+            header: Header::new(message_type, FLAG_RESPONSE | FLAG_ERROR | FLAG_BUSY).encode(),
+            msg: Some(Bytes::from_static(b"server busy")),
+            body: Some(Bytes::copy_from_slice(&millis.to_be_bytes())),
+        }
+    }
+
+    /// Get the message type from the header
+    pub fn message_type(&self) -> Result<u16, ProtocolError> {
+        Ok(Header::decode(self.header)?.message_type)
+    }
+
+    /// Get the retry-after hint if it's a busy response, None otherwise
+    pub fn retry_after(&self) -> Option<Duration> {
+        if !Header::decode(self.header).map_or(false, |header| header.has_flag(FLAG_BUSY)) {
+            return None;
+        }
+        let millis = self.body.as_deref().and_then(|body| body.try_into().ok()).map_or(0, u32::from_be_bytes);
+        Some(Duration::from_millis(u64::from(millis)))
+    }
+
+    /// The response is an error or not
+    pub fn is_error(&self) -> bool {
+        Header::decode(self.header).map_or(false, |header| header.has_flag(FLAG_ERROR))
+    }
+}
@@ -0,0 +1,173 @@
+use std::error;
+use std::fmt::{self, Display};
+use std::io;
+
+/// The protocol version of this implementation
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The frame is a response, otherwise it's a request
+pub const FLAG_RESPONSE: u16 = 1 << 0;
+
+/// The response is an error, the error message is in `msg`
+pub const FLAG_ERROR: u16 = 1 << 1;
+
+/// The body is compressed with lz4, see `Compression`
+pub const FLAG_LZ4: u16 = 1 << 2;
+
+/// The body is compressed with zstd, see `Compression`
+pub const FLAG_ZSTD: u16 = 1 << 3;
+
+/// The error response is rejected as the server is busy, set with `FLAG_ERROR`
+///
+/// The body is the retry-after hint, the milliseconds as a big endian u32.
+/// The clients should not send requests to the server until it's passed.
+pub const FLAG_BUSY: u16 = 1 << 4;
+
+/// The message types from it are reserved for the protocol, not for handlers
+pub const MSG_CONTROL_MIN: u16 = 0xff00;
+
+/// Sent by the server before it closes the connection, no new request should be sent on it
+pub const MSG_GO_AWAY: u16 = 0xff01;
+
+/// Sent by the client to cancel the in-flight request of the same id, it's not responded
+pub const MSG_CANCEL: u16 = 0xff02;
+
+/// Sent by the client first to negotiate the compression of the connection
+///
+/// The body is the ids of the algorithms it supports in preference order, the
+/// response body is the id of the chosen one, or None if there is none.
+pub const MSG_HELLO: u16 = 0xff03;
+
+/// Sent by the client to check the connection is alive, it's responded at once
+pub const MSG_PING: u16 = 0xff04;
+
+/// The decoded `header` of `RPCRequest` and `RPCResponse`
+///
+/// The header is a big endian u64 with the layout:
+///
+/// ```text
+///  63        56 55                40 39                24 23                 0
+/// +------------+--------------------+--------------------+--------------------+
+/// |  version   |    message type    |       flags        |      reserved      |
+/// +------------+--------------------+--------------------+--------------------+
+/// ```
+///
+/// - version: the protocol version, a frame of other versions is rejected.
+/// - message type: what the frame carries, the server dispatches requests by it.
+/// - flags: bit set of `FLAG_*`, e.g. `FLAG_RESPONSE`, unknown bits are ignored.
+/// - reserved: zero, ignored by the decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// The protocol version
+    pub version: u8,
+    /// The message type
+    pub message_type: u16,
+    /// The flags
+    pub flags: u16,
+}
+
+impl Header {
+    /// Create a header of the current protocol version
+    pub fn new(message_type: u16, flags: u16) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            message_type,
+            flags,
+        }
+    }
+
+    /// Encode the header to the u64 on the wire
+    pub fn encode(&self) -> u64 {
+        u64::from(self.version) << 56 | u64::from(self.message_type) << 40 | u64::from(self.flags) << 24
+    }
+
+    /// Decode the header from the u64 on the wire, fail if the version is unknown
+    pub fn decode(header: u64) -> Result<Self, ProtocolError> {
+        let version = (header >> 56) as u8;
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnknownVersion(version));
+        }
+
+        Ok(Self {
+            version,
+            message_type: (header >> 40) as u16,
+            flags: (header >> 24) as u16,
+        })
+    }
+
+    /// The flag is set or not
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag == flag
+    }
+}
+
+/// Error of encoding or decoding RPC frames
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The frame ends before the fields it declares
+    Truncated {
+        /// The bytes needed by the field
+        needed: usize,
+        /// The bytes left in the frame
+        remaining: usize,
+    },
+    /// The frame is larger than the limit
+    FrameTooLarge {
+        /// The frame size
+        size: usize,
+        /// The max frame size
+        max: usize,
+    },
+    /// The protocol version is not supported
+    UnknownVersion(u8),
+    /// The frame is malformed, e.g. with trailing bytes
+    Malformed(&'static str),
+    /// IO error of the underlying stream
+    Io(io::Error),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Truncated { needed, remaining } => {
+                write!(f, "truncated rpc frame, {} bytes needed but {} left", needed, remaining)
+            }
+            Self::FrameTooLarge { size, max } => write!(f, "rpc frame of {} bytes exceeds the limit {}", size, max),
+            Self::UnknownVersion(version) => write!(f, "unknown rpc protocol version {}", version),
+            Self::Malformed(reason) => write!(f, "malformed rpc frame: {}", reason),
+            Self::Io(ref e) => write!(f, "rpc io error: {}", e),
+        }
+    }
+}
+
+impl error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Self::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let header = Header::new(0x1234, FLAG_RESPONSE);
+        let encoded = header.encode();
+        assert_eq!(encoded, 0x0112_3400_0100_0000);
+        assert_eq!(Header::decode(encoded).unwrap(), header);
+        assert!(Header::decode(encoded).unwrap().has_flag(FLAG_RESPONSE));
+        assert!(!Header::new(1, 0).has_flag(FLAG_RESPONSE));
+
+        assert!(matches!(Header::decode(0x0212_3400_0000_0000), Err(ProtocolError::UnknownVersion(2))));
+    }
+}
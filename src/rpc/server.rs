@@ -1,19 +1,187 @@
-use std::sync::{atomic::AtomicU64, Arc, Mutex};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::Duration;
 
-use tokio::net::TcpStream;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Notify, Semaphore};
+use tokio::{select, time};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio_util::codec::FramedRead;
+use tracing::{debug, info, warn};
 
-/// The RPC server
+use super::codec::{FrameWriter, RPCCodec, RPCFrame};
+use super::compress::{Compression, CompressionMetrics, Compressor};
+use super::message::{BincodeCodec, MessageCodec};
+use super::heartbeat::{ConnectionEvent, EventHook};
+use super::protocol::{MSG_CANCEL, MSG_GO_AWAY, MSG_HELLO, MSG_PING};
+use super::tls::TlsContext;
+use super::{RPCRequest, RPCResponse};
+
+/// RPC request handler trait
+///
+/// The response body is returned on success. On failure the error message is
+/// returned in the response `msg` with `FLAG_ERROR` set.
+#[async_trait]
+pub trait Handler: Send + Sync + 'static {
+    /// Handle the request
+    async fn handle(&self, request: RPCRequest) -> anyhow::Result<Option<Bytes>>;
+}
+
+#[async_trait]
+impl<F, Fut> Handler for F
+where
+    F: Fn(RPCRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<Option<Bytes>>> + Send + 'static,
+{
+    async fn handle(&self, request: RPCRequest) -> anyhow::Result<Option<Bytes>> {
+        self(request).await
+    }
+}
+
+/// Default time to wait for the in-flight requests on stop
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default time to wait for the TLS handshake of a new connection
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default max in-flight requests of a connection
+const DEFAULT_MAX_IN_FLIGHT_PER_CONNECTION: usize = 1024;
+
+/// Default max in-flight requests of all the connections
+const DEFAULT_MAX_IN_FLIGHT: usize = 8192;
+
+/// Default retry-after hint of the busy responses
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_millis(100);
+
+/// The handlers by message type
+type Handlers = Arc<RwLock<HashMap<u16, Arc<dyn Handler>>>>;
+
+/// An accepted connection
 #[derive(Debug)]
-#[allow(dead_code)]
+struct Connection {
+    /// The peer address
+    peer: SocketAddr,
+    /// Abort the connection task
+    task: AbortHandle,
+}
+
+/// The in-flight requests of a connection by id
+///
+/// The requests are aborted once it's dropped with the connection, the
+/// request tasks only hold it weakly, so they can not keep it alive.
+#[derive(Debug, Default)]
+struct InFlight(Mutex<HashMap<u64, AbortHandle>>);
+
+impl InFlight {
+    /// Lock the requests
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, AbortHandle>> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        for (_, task) in self.0.get_mut().unwrap_or_else(PoisonError::into_inner).drain() {
+            task.abort();
+        }
+    }
+}
+
+/// The RPC server
 pub struct RPCServer {
     /// The server ip
     server_ip: String,
     /// The server port
     server_port: u16,
-    /// The server connections
-    connections: Arc<Mutex<Vec<TcpStream>>>,
+    /// The server connections by connection id
+    connections: Arc<Mutex<HashMap<u64, Connection>>>,
     /// connection count
     connection_count: Arc<AtomicU64>,
+    /// The request handlers by message type
+    handlers: Handlers,
+    /// The bound address and the accept loop, None if the server is not started
+    listener: Mutex<Option<(SocketAddr, JoinHandle<()>)>>,
+    /// Tell the connections to drain and close
+    shutdown: watch::Sender<bool>,
+    /// Notified when a connection is closed
+    closed: Arc<Notify>,
+    /// Time to wait for the in-flight requests on stop
+    drain_timeout: Duration,
+    /// The TLS context, the connections are plain if it's None
+    tls: Option<Arc<TlsContext>>,
+    /// Close the new connections not finishing the TLS handshake in time
+    handshake_timeout: Duration,
+    /// The compression of the responses, shared by the connections
+    compressor: Arc<Compressor>,
+    /// Close the connections without any frame for so long, None to keep them
+    idle_timeout: Option<Duration>,
+    /// Called on the connection events
+    events: Option<EventHook>,
+    /// The admission of the requests, shared by the connections
+    admission: Arc<Admission>,
+}
+
+/// The in-flight request limits, the requests over them are rejected as busy
+#[derive(Debug)]
+struct Admission {
+    /// Max in-flight requests of a connection
+    max_per_connection: usize,
+    /// Max in-flight requests of all the connections
+    max_total: usize,
+    /// The permits of the in-flight requests of all the connections
+    permits: Arc<Semaphore>,
+    /// The retry-after hint of the busy responses
+    retry_after: Duration,
+    /// The requests rejected as busy
+    rejected: AtomicU64,
+}
+
+impl Admission {
+    /// Create the limits, the total is capped by the max permits of the semaphore
+    fn new(max_per_connection: usize, max_total: usize, retry_after: Duration) -> Self {
+        let max_total = max_total.min(Semaphore::MAX_PERMITS);
+        Self {
+            max_per_connection,
+            max_total,
+            permits: Arc::new(Semaphore::new(max_total)),
+            retry_after,
+            rejected: AtomicU64::new(0),
+        }
+    }
+}
+
+/// What a connection is served with
+struct ConnectionContext {
+    /// The request handlers
+    handlers: Handlers,
+    /// The compression of the responses
+    compressor: Arc<Compressor>,
+    /// Close the connection without any frame for so long
+    idle_timeout: Option<Duration>,
+    /// Called on the connection events
+    events: Option<EventHook>,
+    /// The admission of the requests
+    admission: Arc<Admission>,
+}
+
+impl fmt::Debug for RPCServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RPCServer")
+            .field("server_ip", &self.server_ip)
+            .field("server_port", &self.server_port)
+            .field("connection_count", &self.connection_count)
+            .finish_non_exhaustive()
+    }
 }
 
 impl RPCServer {
@@ -22,24 +190,759 @@ impl RPCServer {
         Self {
             server_ip,
             server_port,
-            connections: Arc::new(Mutex::new(Vec::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
             connection_count: Arc::new(AtomicU64::new(0)),
+            handlers: Arc::new(RwLock::new(HashMap::new())),
+            listener: Mutex::new(None),
+            shutdown: watch::Sender::new(false),
+            closed: Arc::new(Notify::new()),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            tls: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            compressor: Arc::new(Compressor::default()),
+            idle_timeout: None,
+            events: None,
+            admission: Arc::new(Admission::new(
+                DEFAULT_MAX_IN_FLIGHT_PER_CONNECTION,
+                DEFAULT_MAX_IN_FLIGHT,
+                DEFAULT_RETRY_AFTER,
+            )),
         }
     }
 
+    /// Set the time to wait for the in-flight requests on stop
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Accept the connections over TLS, the clients must present a trusted certificate
+    pub fn with_tls(mut self, tls: Arc<TlsContext>) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Set the time to wait for the TLS handshake, the silent clients are not kept forever
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Set the compression algorithms the clients can negotiate, in preference order
+    ///
+    /// The response bodies smaller than the threshold are sent raw. All the
+    /// algorithms are supported by default.
+    pub fn with_compression(mut self, algorithms: Vec<Compression>, threshold: usize) -> Self {
+        self.compressor = Arc::new(Compressor::new(algorithms, threshold));
+        self
+    }
+
+    /// Get the compression metrics of the responses
+    pub fn compression_metrics(&self) -> CompressionMetrics {
+        self.compressor.metrics()
+    }
+
+    /// Close the connections without any frame for the timeout, unless they have in-flight requests
+    ///
+    /// The clients with heartbeats at a shorter interval are kept, while the
+    /// idle and half open ones are reclaimed.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the hook called on the connection events
+    pub fn with_event_hook(mut self, events: EventHook) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Limit the in-flight requests of a connection and of all the connections
+    ///
+    /// The requests over the limits are not handled, but responded at once as
+    /// busy with the retry-after hint, so a burst does not overload the server.
+    pub fn with_max_in_flight(mut self, per_connection: usize, total: usize, retry_after: Duration) -> Self {
+        self.admission = Arc::new(Admission::new(per_connection, total, retry_after));
+        self
+    }
+
+    /// Get the number of the in-flight requests of all the connections
+    pub fn in_flight_count(&self) -> usize {
+        self.admission.max_total - self.admission.permits.available_permits()
+    }
+
+    /// Get the number of the requests rejected as busy
+    pub fn rejected_count(&self) -> u64 {
+        self.admission.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Register the handler of the message type, it replaces the previous one
+    pub fn register<H: Handler>(&self, message_type: u16, handler: H) {
+        self.handlers.write().unwrap().insert(message_type, Arc::new(handler));
+    }
+
+    /// Register the handler of the typed messages, the bodies are encoded by `BincodeCodec`
+    pub fn register_typed<Req, Resp, F, Fut>(&self, message_type: u16, handler: F)
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Serialize,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Resp>> + Send + 'static,
+    {
+        self.register_with(BincodeCodec, message_type, handler);
+    }
+
+    /// Register the handler of the typed messages, the bodies are encoded by the codec
+    ///
+    /// A request failed to decode is responded with an error, the handler is not called.
+    pub fn register_with<C, Req, Resp, F, Fut>(&self, codec: C, message_type: u16, handler: F)
+    where
+        C: MessageCodec,
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Serialize,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Resp>> + Send + 'static,
+    {
+        let codec = Arc::new(codec);
+        self.register(message_type, move |request: RPCRequest| {
+            let handled = codec.decode(request.body.as_deref().unwrap_or_default()).map(&handler);
+            let codec = Arc::clone(&codec);
+            async move { Ok(Some(codec.encode(&handled?.await?)?.into())) }
+        });
+    }
+
+    /// Get the bound address, None if the server is not started
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.lock().unwrap().as_ref().map(|&(addr, _)| addr)
+    }
+
+    /// Get the number of open connections
+    pub fn connection_count(&self) -> u64 {
+        self.connection_count.load(Ordering::Acquire)
+    }
+
     /// Start the RPC server
+    ///
+    /// It binds the address and accepts connections in background, the
+    /// handlers can be registered before or after it's started.
     pub async fn start(&self) -> anyhow::Result<()> {
-        todo!()
+        if self.listener.lock().unwrap().is_some() {
+            return Err(anyhow!("rpc server is already started"));
+        }
+
+        let listener = TcpListener::bind((self.server_ip.as_str(), self.server_port)).await?;
+        // Restarted after stop
+        self.shutdown.send_replace(false);
+        let addr = listener.local_addr()?;
+        info!("RPC server listening on {}", addr);
+
+        let connections = Arc::clone(&self.connections);
+        let connection_count = Arc::clone(&self.connection_count);
+        let shutdown = self.shutdown.clone();
+        let closed = Arc::clone(&self.closed);
+        let tls = self.tls.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let context = Arc::new(ConnectionContext {
+            handlers: Arc::clone(&self.handlers),
+            compressor: Arc::clone(&self.compressor),
+            idle_timeout: self.idle_timeout,
+            events: self.events.clone(),
+            admission: Arc::clone(&self.admission),
+        });
+        let accept = tokio::spawn(async move {
+            let mut next_id = 0;
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // e.g. too many open files, the listener is still usable
+                        warn!("RPC server accept failed: {:?}", e);
+                        continue;
+                    }
+                };
+                debug!("RPC connection {} accepted from {}", next_id, peer);
+
+                let id = next_id;
+                next_id += 1;
+                // Hold the lock until the connection is tracked, it's untracked by the task at the end
+                let mut tracked = connections.lock().unwrap();
+                connection_count.fetch_add(1, Ordering::AcqRel);
+                let task = tokio::spawn({
+                    let connections = Arc::clone(&connections);
+                    let connection_count = Arc::clone(&connection_count);
+                    let shutdown = shutdown.subscribe();
+                    let closed = Arc::clone(&closed);
+                    let tls = tls.clone();
+                    let context = Arc::clone(&context);
+                    async move {
+                        let served = match tls {
+                            Some(tls) => match time::timeout(handshake_timeout, tls.accept(stream)).await {
+                                Ok(Ok(stream)) => serve(stream, peer, context, shutdown).await,
+                                Ok(Err(e)) => Err(e.context("tls handshake failed")),
+                                Err(_) => Err(anyhow!("tls handshake timed out after {:?}", handshake_timeout)),
+                            },
+                            None => serve(stream, peer, context, shutdown).await,
+                        };
+                        if let Err(e) = served {
+                            warn!("RPC connection {} from {} failed: {:?}", id, peer, e);
+                        }
+                        if connections.lock().unwrap().remove(&id).is_some() {
+                            connection_count.fetch_sub(1, Ordering::AcqRel);
+                        }
+                        closed.notify_waiters();
+                    }
+                });
+                tracked.insert(id, Connection { peer, task: task.abort_handle() });
+            }
+        });
+
+        *self.listener.lock().unwrap() = Some((addr, accept));
+        Ok(())
     }
 
-    /// Stop the RPC server
+    /// Stop the RPC server gracefully
+    ///
+    /// It stops accepting, and sends a go away frame to every connection. The
+    /// idle connections are closed at once, and the others after their
+    /// in-flight requests are responded. The connections still busy after
+    /// the drain timeout are closed forcibly.
     pub async fn stop(&self) -> anyhow::Result<()> {
-        todo!()
+        if let Some((addr, accept)) = self.listener.lock().unwrap().take() {
+            accept.abort();
+            info!("RPC server on {} stopped accepting, draining {} connections", addr, self.connection_count());
+        }
+
+        self.shutdown.send_replace(true);
+        let deadline = time::Instant::now() + self.drain_timeout;
+        loop {
+            let closed = self.closed.notified();
+            tokio::pin!(closed);
+            // Register before checking, so no close is missed
+            closed.as_mut().enable();
+            if self.connection_count() == 0 {
+                break;
+            }
+            if time::timeout_at(deadline, closed).await.is_err() {
+                warn!("RPC server drain timed out, closing {} connections", self.connection_count());
+                break;
+            }
+        }
+
+        self.close_connections();
+        Ok(())
+    }
+
+    /// Abort the connection tasks
+    fn close_connections(&self) {
+        let mut connections = self.connections.lock().unwrap();
+        for (id, connection) in connections.drain() {
+            debug!("RPC connection {} from {} closed", id, connection.peer);
+            connection.task.abort();
+            self.connection_count.fetch_sub(1, Ordering::AcqRel);
+        }
     }
 }
 
 impl Drop for RPCServer {
     fn drop(&mut self) {
-        todo!()
+        if let Some((_, accept)) = self.listener.get_mut().unwrap_or_else(PoisonError::into_inner).take() {
+            accept.abort();
+        }
+        self.close_connections();
     }
-}
\ No newline at end of file
+}
+
+/// Serve the requests of the connection until it's closed or the server is shutting down
+///
+/// The requests are handled concurrently, so the responses may be out of order.
+/// A request is aborted once its deadline is passed, or it's cancelled by the
+/// client. On shutdown, a go away frame is sent and no more requests are
+/// read, the connection is closed once the in-flight requests are responded.
+/// If the client closes the connection, the in-flight requests are aborted.
+/// The response bodies are compressed once the client negotiates it. The
+/// pings are responded at once, and the connection is closed if it's idle
+/// for the idle timeout.
+async fn serve<S>(
+    stream: S,
+    peer: SocketAddr,
+    context: Arc<ConnectionContext>,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = io::split(stream);
+    let mut reader = FramedRead::new(reader, RPCCodec::default());
+    let mut writer = FrameWriter::new(writer, RPCCodec::default());
+    let (sender, mut receiver) = mpsc::unbounded_channel::<RPCFrame>();
+    // The in-flight requests, aborted when the connection is closed forcibly
+    let requests = Arc::new(InFlight::default());
+    let in_flight = &requests;
+    // The negotiated compression of the responses
+    let mut compression = None;
+    // The time the last frame is read
+    let mut last_read = time::Instant::now();
+
+    let read = async move {
+        let result = loop {
+            let idle = context.idle_timeout.map(|timeout| last_read + timeout);
+            let frame = select! {
+                frame = reader.next() => frame,
+                _ = shutdown.wait_for(|&shutdown| shutdown) => {
+                    // The receiver is alive until all the senders are dropped
+                    let _ = sender.send(RPCRequest::new(0, MSG_GO_AWAY, None).into());
+                    break Ok(());
+                }
+                () = time::sleep_until(idle.unwrap_or_else(time::Instant::now)), if idle.is_some() => {
+                    if !in_flight.lock().is_empty() {
+                        // Busy rather than idle
+                        last_read = time::Instant::now();
+                        continue;
+                    }
+                    debug!("RPC connection from {} is idle, closing", peer);
+                    if let Some(ref events) = context.events {
+                        events.emit(ConnectionEvent::Idle(peer.to_string()));
+                    }
+                    break Ok(());
+                }
+            };
+            last_read = time::Instant::now();
+            let request = match frame {
+                Some(Ok(RPCFrame::Request(request))) => request,
+                Some(Ok(RPCFrame::Response(response))) => {
+                    warn!("RPC server got unexpected response {}", response.id);
+                    continue;
+                }
+                Some(Err(e)) => break Err(e.into()),
+                None => break Ok(()),
+            };
+
+            if request.message_type().ok() == Some(MSG_CANCEL) {
+                if let Some(task) = in_flight.lock().remove(&request.id) {
+                    debug!("RPC request {} is cancelled", request.id);
+                    task.abort();
+                }
+                continue;
+            }
+
+            if request.message_type().ok() == Some(MSG_PING) {
+                let _ = sender.send(RPCResponse::new(request.id, MSG_PING, None, None).into());
+                continue;
+            }
+
+            if request.message_type().ok() == Some(MSG_HELLO) {
+                compression = context.compressor.negotiate(request.body.as_deref().unwrap_or_default());
+                debug!("RPC connection negotiated compression {:?}", compression);
+                let body = compression.map(|compression| Bytes::from(vec![compression.id()]));
+                let _ = sender.send(RPCResponse::new(request.id, MSG_HELLO, None, body).into());
+                continue;
+            }
+
+            let id = request.id;
+            // Hold the lock until the request is tracked, it's untracked by the task at the end
+            let mut tracked = in_flight.lock();
+            if tracked.contains_key(&id) {
+                // Tracking it would lose the first one, which could no longer be cancelled
+                drop(tracked);
+                let message_type = request.message_type().unwrap_or_default();
+                let _ = sender.send(RPCResponse::error(id, message_type, &anyhow!("request {} is already in flight", id)).into());
+                continue;
+            }
+            let admission = &context.admission;
+            let permit = if tracked.len() < admission.max_per_connection {
+                Arc::clone(&admission.permits).try_acquire_owned().ok()
+            } else {
+                None
+            };
+            let Some(permit) = permit else {
+                drop(tracked);
+                admission.rejected.fetch_add(1, Ordering::Relaxed);
+                let message_type = request.message_type().unwrap_or_default();
+                let _ = sender.send(RPCResponse::busy(id, message_type, admission.retry_after).into());
+                continue;
+            };
+
+            let sender = sender.clone();
+            let context = Arc::clone(&context);
+            let untrack = Arc::downgrade(in_flight);
+            let task = tokio::spawn(async move {
+                // Released when the request is responded or aborted
+                let _permit = permit;
+                let mut response = match request.timeout {
+                    Some(timeout) => {
+                        let message_type = request.message_type().unwrap_or_default();
+                        time::timeout(timeout, dispatch(&context.handlers, &context.compressor, request)).await.unwrap_or_else(|_| {
+                            RPCResponse::error(id, message_type, &anyhow!("deadline of {:?} exceeded", timeout))
+                        })
+                    }
+                    None => dispatch(&context.handlers, &context.compressor, request).await,
+                };
+                context.compressor.compress(compression, &mut response.header, &mut response.body);
+                if let Some(in_flight) = untrack.upgrade() {
+                    in_flight.lock().remove(&id);
+                }
+                // The connection is closed if it fails
+                let _ = sender.send(response.into());
+            });
+            tracked.insert(id, task.abort_handle());
+        };
+
+        if !*shutdown.borrow() {
+            // Nobody waits for the responses
+            for (_, task) in in_flight.lock().drain() {
+                task.abort();
+            }
+        }
+        result
+    };
+
+    // The write ends after the in-flight requests are responded
+    let write = async move { writer.forward(&mut receiver).await.map_err(anyhow::Error::from) };
+
+    let (read, write) = tokio::join!(read, write);
+    read.and(write)
+}
+
+/// Call the handler of the request, and build the response tied to the request id
+async fn dispatch(handlers: &Handlers, compressor: &Compressor, mut request: RPCRequest) -> RPCResponse {
+    let id = request.id;
+    let message_type = match request.message_type() {
+        Ok(message_type) => message_type,
+        Err(e) => return RPCResponse::error(id, 0, &e.into()),
+    };
+    if let Err(e) = compressor.decompress(&mut request.header, &mut request.body) {
+        return RPCResponse::error(id, message_type, &e.into());
+    }
+
+    let handler = handlers.read().unwrap().get(&message_type).cloned();
+    let Some(handler) = handler else {
+        return RPCResponse::error(id, message_type, &anyhow!("no handler for message type {}", message_type));
+    };
+
+    match handler.handle(request).await {
+        Ok(body) => RPCResponse::new(id, message_type, None, body),
+        Err(e) => RPCResponse::error(id, message_type, &e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::rpc::client::RPCClient;
+
+    #[tokio::test]
+    async fn test_server_dispatch() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0);
+        server.register(1, |request: RPCRequest| async move { Ok(request.body) });
+        server.register(2, |_request: RPCRequest| async move { Err(anyhow!("cache miss")) });
+        server.start().await.unwrap();
+        assert!(server.start().await.is_err());
+
+        let stream = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+        let mut framed = Framed::new(stream, RPCCodec::default());
+        for (id, message_type) in [(10, 1), (11, 2), (12, 3)] {
+            framed.send(RPCRequest::new(id, message_type, Some(Bytes::from_static(b"key"))).into()).await.unwrap();
+        }
+
+        let mut responses = HashMap::new();
+        for _ in 0..3 {
+            match framed.next().await.unwrap().unwrap() {
+                RPCFrame::Response(response) => responses.insert(response.id, response),
+                RPCFrame::Request(request) => panic!("unexpected request {:?}", request),
+            };
+        }
+        assert!(!responses[&10].is_error());
+        assert_eq!(responses[&10].body.as_deref(), Some(b"key".as_slice()));
+        assert!(responses[&11].is_error());
+        assert_eq!(responses[&11].msg.as_deref(), Some(b"cache miss".as_slice()));
+        assert_eq!(responses[&11].message_type().unwrap(), 2);
+        assert!(responses[&12].is_error());
+        assert_eq!(server.connection_count(), 1);
+
+        // The connection is untracked after it's closed
+        drop(framed);
+        for _ in 0..100 {
+            if server.connection_count() == 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.connection_count(), 0);
+
+        let addr = server.local_addr().unwrap();
+        server.stop().await.unwrap();
+        assert!(server.local_addr().is_none());
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    /// Read the frames until the connection is closed
+    async fn read_all(framed: &mut Framed<TcpStream, RPCCodec>) -> Vec<RPCFrame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = framed.next().await {
+            frames.push(frame.unwrap());
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_server_graceful_stop() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0).with_drain_timeout(Duration::from_secs(5));
+        let started = Arc::new(Notify::new());
+        let handled = Arc::clone(&started);
+        server.register(1, move |request: RPCRequest| {
+            let handled = Arc::clone(&handled);
+            async move {
+                handled.notify_one();
+                time::sleep(Duration::from_millis(100)).await;
+                Ok(request.body)
+            }
+        });
+        server.start().await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let mut busy = Framed::new(TcpStream::connect(addr).await.unwrap(), RPCCodec::default());
+        let mut idle = Framed::new(TcpStream::connect(addr).await.unwrap(), RPCCodec::default());
+        busy.send(RPCRequest::new(1, 1, Some(Bytes::from_static(b"key"))).into()).await.unwrap();
+        started.notified().await;
+        while server.connection_count() < 2 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The in-flight request is responded after the go away frame
+        let (stopped, busy_frames, idle_frames) = tokio::join!(server.stop(), read_all(&mut busy), read_all(&mut idle));
+        stopped.unwrap();
+        assert_eq!(busy_frames.len(), 2);
+        assert!(matches!(busy_frames[0], RPCFrame::Request(ref go_away) if go_away.message_type().unwrap() == MSG_GO_AWAY));
+        assert!(matches!(busy_frames[1], RPCFrame::Response(ref response) if response.id == 1 && !response.is_error()));
+        assert_eq!(idle_frames.len(), 1);
+        assert_eq!(server.connection_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_drain_timeout() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0).with_drain_timeout(Duration::from_millis(50));
+        server.register(1, |_request: RPCRequest| async move {
+            time::sleep(Duration::from_secs(3600)).await;
+            Ok(None)
+        });
+        server.start().await.unwrap();
+
+        let mut framed = Framed::new(TcpStream::connect(server.local_addr().unwrap()).await.unwrap(), RPCCodec::default());
+        framed.send(RPCRequest::new(1, 1, None).into()).await.unwrap();
+        while server.connection_count() < 1 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        time::timeout(Duration::from_secs(1), server.stop()).await.unwrap().unwrap();
+        assert_eq!(server.connection_count(), 0);
+        // Only the go away frame is received before the connection is closed
+        assert_eq!(time::timeout(Duration::from_secs(1), read_all(&mut framed)).await.unwrap().len(), 1);
+    }
+
+    /// Count the handlers dropped before they finish
+    struct DropCounter(Arc<AtomicU64>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Wait until the count is reached
+    async fn wait_count(count: &AtomicU64, expected: u64) {
+        for _ in 0..100 {
+            if count.load(Ordering::Acquire) >= expected {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(count.load(Ordering::Acquire), expected);
+    }
+
+    #[tokio::test]
+    async fn test_server_deadline_and_cancel() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0);
+        let aborted = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&aborted);
+        server.register(1, move |_request: RPCRequest| {
+            let guard = DropCounter(Arc::clone(&counter));
+            async move {
+                let _guard = guard;
+                time::sleep(Duration::from_secs(3600)).await;
+                Ok(None)
+            }
+        });
+        server.register(2, |request: RPCRequest| async move { Ok(request.body) });
+        server.start().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut framed = Framed::new(TcpStream::connect(addr).await.unwrap(), RPCCodec::default());
+
+        // Aborted after the deadline
+        framed.send(RPCRequest::new(1, 1, None).with_timeout(Duration::from_millis(20)).into()).await.unwrap();
+        match framed.next().await.unwrap().unwrap() {
+            RPCFrame::Response(response) => assert!(response.id == 1 && response.is_error()),
+            RPCFrame::Request(request) => panic!("unexpected request {:?}", request),
+        }
+        wait_count(&aborted, 1).await;
+
+        // Aborted by the cancel frame without response
+        framed.send(RPCRequest::new(2, 1, None).into()).await.unwrap();
+        framed.send(RPCRequest::new(2, MSG_CANCEL, None).into()).await.unwrap();
+        wait_count(&aborted, 2).await;
+        framed.send(RPCRequest::new(3, 2, None).into()).await.unwrap();
+        assert!(matches!(framed.next().await.unwrap().unwrap(), RPCFrame::Response(ref response) if response.id == 3));
+
+        // Aborted when the client closes the connection
+        framed.send(RPCRequest::new(4, 1, None).into()).await.unwrap();
+        time::sleep(Duration::from_millis(10)).await;
+        drop(framed);
+        wait_count(&aborted, 3).await;
+    }
+
+    #[tokio::test]
+    async fn test_server_stop_aborts_handlers() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0).with_drain_timeout(Duration::from_millis(50));
+        let aborted = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&aborted);
+        server.register(1, move |_request: RPCRequest| {
+            let guard = DropCounter(Arc::clone(&counter));
+            async move {
+                let _guard = guard;
+                futures::future::pending::<()>().await;
+                Ok(None)
+            }
+        });
+        server.start().await.unwrap();
+
+        let mut framed = Framed::new(TcpStream::connect(server.local_addr().unwrap()).await.unwrap(), RPCCodec::default());
+        framed.send(RPCRequest::new(1, 1, None).into()).await.unwrap();
+        while server.in_flight_count() < 1 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The handler never finishes, it's aborted with the connection and its permit is released
+        server.stop().await.unwrap();
+        wait_count(&aborted, 1).await;
+        assert_eq!(server.in_flight_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_idle_timeout() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0)
+            .with_idle_timeout(Duration::from_millis(100))
+            .with_event_hook(EventHook::new(move |event| recorded.lock().unwrap().push(event)));
+        server.register(1, |request: RPCRequest| async move { Ok(request.body) });
+        server.start().await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // The ping is answered, then the silent connection is closed and untracked
+        let mut framed = Framed::new(TcpStream::connect(addr).await.unwrap(), RPCCodec::default());
+        framed.send(RPCRequest::new(1, MSG_PING, None).into()).await.unwrap();
+        let frames = time::timeout(Duration::from_secs(1), read_all(&mut framed)).await.unwrap();
+        assert!(matches!(frames[..], [RPCFrame::Response(ref pong)] if pong.id == 1 && !pong.is_error()));
+        wait_count_of(&server, 0).await;
+        let local = framed.get_ref().local_addr().unwrap().to_string();
+        assert_eq!(*events.lock().unwrap(), vec![ConnectionEvent::Idle(local)]);
+
+        // The heartbeats keep the connection alive
+        let client = RPCClient::new("127.0.0.1".to_owned(), addr.port(), 1000).with_heartbeat(Duration::from_millis(20));
+        assert!(client.send_request(RPCRequest::new(2, 1, None)).await.is_ok());
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(server.connection_count(), 1);
+        assert!(client.send_request(RPCRequest::new(3, 1, None)).await.is_ok());
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    /// Wait until the server has the count of connections
+    async fn wait_count_of(server: &RPCServer, expected: u64) {
+        for _ in 0..100 {
+            if server.connection_count() == expected {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.connection_count(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_server_admission() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0).with_max_in_flight(2, 3, Duration::from_millis(50));
+        server.register(1, |request: RPCRequest| async move {
+            time::sleep(Duration::from_millis(200)).await;
+            Ok(request.body)
+        });
+        server.start().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut first = Framed::new(TcpStream::connect(addr).await.unwrap(), RPCCodec::default());
+        let mut second = Framed::new(TcpStream::connect(addr).await.unwrap(), RPCCodec::default());
+
+        // Over the limit of the connection, then over the limit of the server
+        for id in 1..=3 {
+            first.send(RPCRequest::new(id, 1, None).into()).await.unwrap();
+        }
+        let busy = match first.next().await.unwrap().unwrap() {
+            RPCFrame::Response(response) => response,
+            RPCFrame::Request(request) => panic!("unexpected request {:?}", request),
+        };
+        assert_eq!((busy.id, busy.retry_after()), (3, Some(Duration::from_millis(50))));
+        assert!(busy.is_error());
+        for id in 4..=5 {
+            second.send(RPCRequest::new(id, 1, None).into()).await.unwrap();
+        }
+        assert!(matches!(second.next().await.unwrap().unwrap(), RPCFrame::Response(ref busy) if busy.id == 5 && busy.retry_after().is_some()));
+        assert_eq!((server.in_flight_count(), server.rejected_count()), (3, 2));
+
+        // The admitted requests are handled, and the permits are released
+        let mut handled = Vec::new();
+        for (framed, count) in [(&mut first, 2), (&mut second, 1)] {
+            for _ in 0..count {
+                match framed.next().await.unwrap().unwrap() {
+                    RPCFrame::Response(response) => {
+                        assert!(response.retry_after().is_none() && !response.is_error());
+                        handled.push(response.id);
+                    }
+                    RPCFrame::Request(request) => panic!("unexpected request {:?}", request),
+                }
+            }
+        }
+        handled.sort_unstable();
+        assert_eq!(handled, vec![1, 2, 4]);
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(server.in_flight_count(), 0);
+        second.send(RPCRequest::new(6, 1, None).into()).await.unwrap();
+        assert!(matches!(second.next().await.unwrap().unwrap(), RPCFrame::Response(ref response) if response.id == 6 && !response.is_error()));
+    }
+
+    #[tokio::test]
+    async fn test_server_duplicate_request() {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0);
+        server.register(1, |request: RPCRequest| async move {
+            time::sleep(Duration::from_millis(100)).await;
+            Ok(request.body)
+        });
+        server.start().await.unwrap();
+        let mut framed = Framed::new(TcpStream::connect(server.local_addr().unwrap()).await.unwrap(), RPCCodec::default());
+
+        // The duplicate is rejected, and the first one is still tracked and responded
+        framed.send(RPCRequest::new(1, 1, Some(Bytes::from_static(b"first"))).into()).await.unwrap();
+        framed.send(RPCRequest::new(1, 1, Some(Bytes::from_static(b"second"))).into()).await.unwrap();
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            match framed.next().await.unwrap().unwrap() {
+                RPCFrame::Response(response) => responses.push(response),
+                RPCFrame::Request(request) => panic!("unexpected request {:?}", request),
+            }
+        }
+        assert!(responses[0].id == 1 && responses[0].is_error() && responses[0].retry_after().is_none());
+        assert_eq!((responses[1].id, responses[1].body.as_deref()), (1, Some(b"first".as_slice())));
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(server.in_flight_count(), 0);
+    }
+}
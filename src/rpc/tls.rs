@@ -0,0 +1,296 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::info;
+
+use crate::config::TlsConfig;
+
+/// TLS context of the RPC server and client, with mutual authentication
+///
+/// The certificates are loaded from the files of `TlsConfig`, and can be
+/// reloaded without restart. The new certificates are used by the new
+/// connections, the established ones keep the old.
+#[derive(Debug)]
+pub struct TlsContext {
+    /// The certificate files
+    config: TlsConfig,
+    /// The server side config
+    server: RwLock<Arc<ServerConfig>>,
+    /// The client side config
+    client: RwLock<Arc<ClientConfig>>,
+    /// The latest modified time of the files when they are loaded
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl TlsContext {
+    /// Create a new TLS context from the certificate files
+    pub fn new(config: TlsConfig) -> anyhow::Result<Self> {
+        let modified = modified(&config);
+        let (server, client) = load(&config)?;
+
+        Ok(Self {
+            config,
+            server: RwLock::new(Arc::new(server)),
+            client: RwLock::new(Arc::new(client)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reload the certificate files, the current ones are kept if they are invalid
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let modified = modified(&self.config);
+        let (server, client) = load(&self.config)?;
+        *self.server.write().unwrap() = Arc::new(server);
+        *self.client.write().unwrap() = Arc::new(client);
+        *self.modified.lock().unwrap() = modified;
+        info!("RPC TLS certificates are reloaded from {:?}", self.config.cert_path);
+        Ok(())
+    }
+
+    /// Reload the certificate files if any of them is modified, return it's reloaded or not
+    pub fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        if modified(&self.config) == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Accept a TLS connection, fail if the client is not authenticated
+    pub async fn accept<S>(&self, stream: S) -> anyhow::Result<tokio_rustls::server::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = Arc::clone(&self.server.read().unwrap());
+        Ok(TlsAcceptor::from(config).accept(stream).await?)
+    }
+
+    /// Connect to the server over TLS, the server is verified by its ip or host name
+    pub async fn connect<S>(&self, server: &str, stream: S) -> anyhow::Result<tokio_rustls::client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let name = ServerName::try_from(server.to_owned()).with_context(|| format!("invalid server name {}", server))?;
+        let config = Arc::clone(&self.client.read().unwrap());
+        Ok(TlsConnector::from(config).connect(name, stream).await?)
+    }
+}
+
+/// The latest modified time of the certificate files, None if any of them is unreadable
+fn modified(config: &TlsConfig) -> Option<SystemTime> {
+    [&config.cert_path, &config.key_path, &config.ca_path]
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .max()
+}
+
+/// Load the server and client configs from the certificate files
+fn load(config: &TlsConfig) -> anyhow::Result<(ServerConfig, ClientConfig)> {
+    let certs = load_certs(&config.cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .with_context(|| format!("failed to load the private key from {:?}", config.key_path))?;
+    let mut roots = RootCertStore::empty();
+    for ca in load_certs(&config.ca_path)? {
+        roots.add(ca)?;
+    }
+    let roots = Arc::new(roots);
+    let provider = Arc::new(ring::default_provider());
+
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::clone(&roots), Arc::clone(&provider)).build()?;
+    let server = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs.clone(), key.clone_key())?;
+    let client = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)?;
+
+    Ok((server, client))
+}
+
+/// Load the certificates from the PEM file
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .with_context(|| format!("failed to load the certificates from {:?}", path))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate is found in {:?}", path));
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    use tokio::time;
+
+    use super::*;
+    use crate::rpc::client::RPCClient;
+    use crate::rpc::server::RPCServer;
+    use crate::rpc::RPCRequest;
+
+    /// A self signed CA
+    struct Authority {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Authority {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        /// Issue a certificate of 127.0.0.1, and write the files into the directory
+        fn issue(&self, dir: &Path) -> TlsConfig {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["127.0.0.1".to_owned()])
+                .unwrap()
+                .signed_by(&key, &self.cert, &self.key)
+                .unwrap();
+
+            fs::create_dir_all(dir).unwrap();
+            let config = TlsConfig {
+                cert_path: dir.join("cert.pem"),
+                key_path: dir.join("key.pem"),
+                ca_path: dir.join("ca.pem"),
+            };
+            fs::write(&config.cert_path, cert.pem()).unwrap();
+            fs::write(&config.key_path, key.serialize_pem()).unwrap();
+            fs::write(&config.ca_path, self.cert.pem()).unwrap();
+            config
+        }
+    }
+
+    /// A temporary directory of the certificate files, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            Self(env::temp_dir().join(format!("cache_proxy_tls_{}_{}", name, process::id())))
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn echo_server(tls: Arc<TlsContext>) -> RPCServer {
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0).with_tls(tls);
+        server.register(1, |request: RPCRequest| async move { Ok(request.body) });
+        server.start().await.unwrap();
+        server
+    }
+
+    fn client(server: &RPCServer, tls: Option<&TlsConfig>) -> RPCClient {
+        let client = RPCClient::new("127.0.0.1".to_owned(), server.local_addr().unwrap().port(), 1000);
+        match tls {
+            Some(tls) => client.with_tls(Arc::new(TlsContext::new(tls.clone()).unwrap())),
+            None => client,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_mutual_auth() {
+        let authority = Authority::new();
+        let dirs = [TempDir::new("auth_server"), TempDir::new("auth_client"), TempDir::new("auth_untrusted")];
+        let server_tls = authority.issue(dirs[0].path());
+        let client_tls = authority.issue(dirs[1].path());
+        let untrusted_tls = Authority::new().issue(dirs[2].path());
+        let server = echo_server(Arc::new(TlsContext::new(server_tls).unwrap())).await;
+
+        let response = client(&server, Some(&client_tls))
+            .send_request(RPCRequest::new(1, 1, Some(Bytes::from_static(b"hello"))))
+            .await
+            .unwrap();
+        assert_eq!(response.body, Some(Bytes::from_static(b"hello")));
+
+        // Neither a plain client, nor a client of an untrusted CA is served
+        assert!(client(&server, None).send_request(RPCRequest::new(2, 1, None)).await.is_err());
+        assert!(client(&server, Some(&untrusted_tls)).send_request(RPCRequest::new(3, 1, None)).await.is_err());
+
+        // The files are invalid
+        let mut invalid_tls = client_tls.clone();
+        invalid_tls.key_path = client_tls.cert_path.clone();
+        assert!(TlsContext::new(invalid_tls).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_reload() {
+        let (old, new) = (Authority::new(), Authority::new());
+        let dirs = [TempDir::new("reload_server"), TempDir::new("reload_old"), TempDir::new("reload_new")];
+        let dir = dirs[0].path();
+        let old_client_tls = old.issue(dirs[1].path());
+        let new_client_tls = new.issue(dirs[2].path());
+        let tls = Arc::new(TlsContext::new(old.issue(dir)).unwrap());
+        let server = echo_server(Arc::clone(&tls)).await;
+        assert!(!tls.reload_if_changed().unwrap());
+        assert!(client(&server, Some(&old_client_tls)).send_request(RPCRequest::new(1, 1, None)).await.is_ok());
+
+        // The certificates are renewed with a new CA, and picked up without restart
+        time::sleep(Duration::from_millis(20)).await;
+        new.issue(dir);
+        assert!(tls.reload_if_changed().unwrap());
+        assert!(!tls.reload_if_changed().unwrap());
+        assert!(client(&server, Some(&new_client_tls)).send_request(RPCRequest::new(2, 1, None)).await.is_ok());
+        assert!(client(&server, Some(&old_client_tls)).send_request(RPCRequest::new(3, 1, None)).await.is_err());
+
+        // The current certificates are kept if the renewed ones are broken
+        time::sleep(Duration::from_millis(20)).await;
+        fs::write(dir.join("cert.pem"), "broken").unwrap();
+        assert!(tls.reload_if_changed().is_err());
+        assert!(client(&server, Some(&new_client_tls)).send_request(RPCRequest::new(4, 1, None)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_timeout() {
+        let dir = TempDir::new("handshake_server");
+        let tls = Arc::new(TlsContext::new(Authority::new().issue(dir.path())).unwrap());
+        let server = RPCServer::new("127.0.0.1".to_owned(), 0)
+            .with_tls(tls)
+            .with_handshake_timeout(Duration::from_millis(100));
+        server.start().await.unwrap();
+
+        // The client connects but never starts the handshake
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+        let mut buf = [0; 1];
+        let read = time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+        for _ in 0..100 {
+            if server.connection_count() == 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.connection_count(), 0);
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, Context, Error};
+use serde_json::{json, Map, Value};
+use tracing::warn;
+
+use crate::client::namespace::{namespace_prefix, NAMESPACE_PATH};
+use crate::client::{Compare, MetaClient, Txn};
+use crate::node::Node;
+use crate::slot::Slot;
+
+/// The schema version of the records written by this proxy
+pub const SCHEMA_VERSION: u64 = 1;
+/// The oldest schema version able to read the records written by this proxy
+pub const MIN_READER_VERSION: u64 = 1;
+
+/// The prefix of the cluster topology
+pub const CLUSTER_PATH: &str = "/cluster/";
+/// The prefix of the registered nodes, a node is stored at `/cluster/nodes/<id>`
+pub const NODES_PATH: &str = "/cluster/nodes/";
+/// The slot assignments
+pub const SLOTS_PATH: &str = "/cluster/slots";
+/// The ring version, bumped with every slot assignment change
+pub const RING_VERSION_PATH: &str = "/cluster/ring_version";
+
+/// Field of the schema version the record is written with
+const VERSION_FIELD: &str = "version";
+/// Field of the oldest schema version able to read the record
+const MIN_READER_VERSION_FIELD: &str = "min_reader_version";
+
+/// The meta data path of a node
+pub fn node_path(id: u64) -> String {
+    format!("{}{}", NODES_PATH, id)
+}
+
+/// Encode a node record
+pub fn encode_node(node: &Node) -> Vec<u8> {
+    encode_record(json!({
+        "id": node.id(),
+        "ip": node.ip(),
+        "port": node.port(),
+        "weight": node.weight(),
+    }))
+}
+
+/// Decode a node record at the path
+pub fn decode_node(path: &str, data: &[u8]) -> Result<Node, Error> {
+    let record = decode_record(data).with_context(|| format!("invalid node record {}", path))?;
+    let weight = optional_u64(&record, "weight")?.unwrap_or(1);
+    Ok(Node::new(
+        field_u64(&record, "id")?,
+        field_str(&record, "ip")?.to_owned(),
+        u16::try_from(field_u64(&record, "port")?)?,
+        u32::try_from(weight)?,
+    ))
+}
+
+/// Encode the slot assignments
+pub fn encode_slots(slots: &[Slot]) -> Vec<u8> {
+    let slots: Vec<Value> = slots
+        .iter()
+        .map(|slot| {
+            json!({
+                "id": slot.id(),
+                "node": slot.backend_node_id(),
+                "migrating": slot.is_migrating(),
+            })
+        })
+        .collect();
+    encode_record(json!({ "slots": slots }))
+}
+
+/// Decode the slot assignments
+pub fn decode_slots(data: &[u8]) -> Result<Vec<Slot>, Error> {
+    let record = decode_record(data).context("invalid slots record")?;
+    let slots = record
+        .get("slots")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("slots record has no slots"))?;
+
+    slots
+        .iter()
+        .map(|value| {
+            let slot = value.as_object().ok_or_else(|| anyhow!("invalid slot {}", value))?;
+            let mut decoded = Slot::new(field_u64(slot, "id")?, field_u64(slot, "node")?);
+            decoded.set_migrating(slot.get("migrating").and_then(Value::as_bool).unwrap_or(false));
+            Ok(decoded)
+        })
+        .collect()
+}
+
+/// Encode the ring version
+pub fn encode_ring_version(ring_version: u64) -> Vec<u8> {
+    encode_record(json!({ "ring_version": ring_version }))
+}
+
+/// Decode the ring version
+pub fn decode_ring_version(data: &[u8]) -> Result<u64, Error> {
+    let record = decode_record(data).context("invalid ring version record")?;
+    field_u64(&record, "ring_version")
+}
+
+/// Load the registered nodes with the count of the unreadable ones, skip the ones deleted after listed
+///
+/// A node record this proxy can not read, e.g. it's written by a newer proxy
+/// with an incompatible schema, is skipped rather than failing the load, so
+/// the proxies of different versions can run in the same cluster.
+pub async fn load_nodes<C: MetaClient>(client: &C) -> Result<(Vec<Node>, usize), Error> {
+    let mut nodes = Vec::new();
+    let mut unreadable = 0;
+    for path in client.list(NODES_PATH, false).await? {
+        let data = client.read(&path, false).await?;
+        if data.is_empty() {
+            continue;
+        }
+        match decode_node(&path, &data) {
+            Ok(node) => nodes.push(node),
+            Err(e) => {
+                warn!("Skip the unreadable node record {}: {:?}", path, e);
+                unreadable += 1;
+            }
+        }
+    }
+
+    Ok((nodes, unreadable))
+}
+
+/// Load the slot assignments with the mod revision, None if they are not published yet
+pub async fn load_slots<C: MetaClient>(client: &C) -> Result<Option<(Vec<Slot>, u64)>, Error> {
+    client
+        .read_revision(SLOTS_PATH)
+        .await?
+        .map(|(data, revision)| Ok((decode_slots(&data)?, revision)))
+        .transpose()
+}
+
+/// Load the ring version, 0 if the slot assignments are not published yet
+pub async fn load_ring_version<C: MetaClient>(client: &C) -> Result<u64, Error> {
+    match client.read_revision(RING_VERSION_PATH).await? {
+        Some((data, _)) => decode_ring_version(&data),
+        None => Ok(0),
+    }
+}
+
+/// Publish the slot assignments and bump the ring version atomically, return the new ring version
+///
+/// It's conditioned on the slots mod revision they are computed from, 0 if
+/// they are not published yet, so it fails with `ConflictError` if another
+/// proxy publishes first.
+pub async fn publish_slots<C: MetaClient>(client: &C, slots: &[Slot], revision: u64) -> Result<u64, Error> {
+    let (ring_version, ring_revision) = match client.read_revision(RING_VERSION_PATH).await? {
+        Some((data, ring_revision)) => (decode_ring_version(&data)?, ring_revision),
+        None => (0, 0),
+    };
+
+    let txn = Txn::new()
+        .when(Compare::ModRevision(SLOTS_PATH.to_owned(), revision))
+        .when(Compare::ModRevision(RING_VERSION_PATH.to_owned(), ring_revision))
+        .put(SLOTS_PATH, &encode_slots(slots))
+        .put(RING_VERSION_PATH, &encode_ring_version(ring_version + 1));
+    client.txn(txn).await?;
+
+    Ok(ring_version + 1)
+}
+
+/// List the namespaces of the clusters in the meta data, the root namespace is empty
+///
+/// The client should not be namespaced. A namespace is a cluster if it has
+/// any topology record.
+pub async fn list_clusters<C: MetaClient>(client: &C) -> Result<Vec<String>, Error> {
+    let clusters: BTreeSet<String> = client
+        .list("/", false)
+        .await?
+        .iter()
+        .filter_map(|key| {
+            key.strip_suffix(SLOTS_PATH)
+                .or_else(|| key.strip_suffix(RING_VERSION_PATH))
+                .or_else(|| key.rfind(NODES_PATH).map(|index| &key[..index]))
+        })
+        .filter_map(|prefix| match prefix {
+            "" => Some(String::new()),
+            prefix => prefix.strip_prefix(NAMESPACE_PATH).map(str::to_owned),
+        })
+        .collect();
+
+    Ok(clusters.into_iter().collect())
+}
+
+/// Move the topology of a cluster to a new namespace atomically, return the moved path count
+///
+/// The client should not be namespaced. The registered nodes are not moved,
+/// they are bound to the leases of the running proxies, which register again
+/// under the new namespace once they are restarted with it. It fails if the
+/// new namespace already has a topology, or with `ConflictError` if the
+/// topology is changed during the migration.
+pub async fn migrate_cluster<C: MetaClient>(client: &C, from: &str, to: &str) -> Result<usize, Error> {
+    let (from, to) = (namespace_prefix(from)?, namespace_prefix(to)?);
+    if from == to {
+        return Err(anyhow!("cluster is already in namespace {:?}", to));
+    }
+    if !client.list(&format!("{}{}", to, CLUSTER_PATH), false).await?.is_empty() {
+        return Err(anyhow!("namespace {:?} already has a cluster", to));
+    }
+
+    let mut txn = Txn::new();
+    let mut moved = 0;
+    for key in client.list(&format!("{}{}", from, CLUSTER_PATH), false).await? {
+        let path = &key[from.len()..];
+        if path.starts_with(NODES_PATH) {
+            continue;
+        }
+        // Deleted after listed
+        let (data, revision) = match client.read_revision(&key).await? {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        let target = format!("{}{}", to, path);
+        txn = txn
+            .when(Compare::ModRevision(key.clone(), revision))
+            .when(Compare::ModRevision(target.clone(), 0))
+            .put(&target, &data)
+            .delete(&key);
+        moved += 1;
+    }
+
+    if moved > 0 {
+        client.txn(txn).await?;
+    }
+    Ok(moved)
+}
+
+/// Encode the fields with the schema versions
+fn encode_record(mut record: Value) -> Vec<u8> {
+    record[VERSION_FIELD] = json!(SCHEMA_VERSION);
+    record[MIN_READER_VERSION_FIELD] = json!(MIN_READER_VERSION);
+    record.to_string().into_bytes()
+}
+
+/// Decode a record, fail if it's too new to read
+///
+/// The unknown fields are ignored, so a newer proxy can add fields without
+/// breaking the older ones, and bump `min_reader_version` only when the old
+/// fields change their meaning.
+fn decode_record(data: &[u8]) -> Result<Map<String, Value>, Error> {
+    let record: Map<String, Value> = serde_json::from_slice(data)?;
+    let min_reader_version = optional_u64(&record, MIN_READER_VERSION_FIELD)?.unwrap_or(0);
+    if min_reader_version > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "record needs schema version {}, the current version is {}",
+            min_reader_version,
+            SCHEMA_VERSION
+        ));
+    }
+
+    Ok(record)
+}
+
+/// Get a required u64 field
+fn field_u64(record: &Map<String, Value>, name: &str) -> Result<u64, Error> {
+    optional_u64(record, name)?.ok_or_else(|| anyhow!("field {} is missing", name))
+}
+
+/// Get an optional u64 field
+fn optional_u64(record: &Map<String, Value>, name: &str) -> Result<Option<u64>, Error> {
+    record
+        .get(name)
+        .map(|value| value.as_u64().ok_or_else(|| anyhow!("field {} is not an integer: {}", name, value)))
+        .transpose()
+}
+
+/// Get a required string field
+fn field_str<'a>(record: &'a Map<String, Value>, name: &str) -> Result<&'a str, Error> {
+    record
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("field {} is missing", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::is_conflict;
+    use crate::client::memory::InMemoryMetaClient;
+    use crate::client::namespace::Namespaced;
+
+    #[test]
+    fn test_node_record() {
+        let node = Node::new(7, "10.0.0.1".to_owned(), 8000, 2);
+        let decoded = decode_node(&node_path(7), &encode_node(&node)).unwrap();
+        assert_eq!((decoded.id(), decoded.ip(), decoded.port(), decoded.weight()), (7, "10.0.0.1", 8000, 2));
+        assert!(decode_node("/cluster/nodes/9", b"10.0.0.3:8002").is_err());
+    }
+
+    #[test]
+    fn test_slots_record() {
+        let mut slots = vec![Slot::new(0, 7), Slot::new(1, 8)];
+        slots[1].set_migrating(true);
+        assert_eq!(decode_slots(&encode_slots(&slots)).unwrap(), slots);
+        assert_eq!(decode_ring_version(&encode_ring_version(3)).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_record_compatibility() {
+        // A newer proxy adds fields, and an older one omits the optional fields
+        let newer = br#"{"version":2,"min_reader_version":1,"id":7,"ip":"10.0.0.1","port":8000,"zone":"a"}"#;
+        let node = decode_node("/cluster/nodes/7", newer).unwrap();
+        assert_eq!((node.id(), node.weight()), (7, 1));
+        let slots = decode_slots(br#"{"slots":[{"id":0,"node":7}]}"#).unwrap();
+        assert_eq!(slots, vec![Slot::new(0, 7)]);
+
+        // The fields change their meaning
+        let incompatible = br#"{"version":3,"min_reader_version":3,"ring_version":1}"#;
+        assert!(decode_ring_version(incompatible).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_nodes_skip_unreadable() {
+        let client = InMemoryMetaClient::new();
+        client.create(&node_path(7), &encode_node(&Node::new(7, "10.0.0.1".to_owned(), 8000, 1))).await.unwrap();
+        // Written by a newer proxy with an incompatible schema, and broken
+        let newer = br#"{"version":3,"min_reader_version":3,"id":8,"ip":"10.0.0.2","port":8001}"#;
+        client.create(&node_path(8), newer).await.unwrap();
+        client.create(&node_path(9), b"broken").await.unwrap();
+
+        let (nodes, unreadable) = load_nodes(&client).await.unwrap();
+        assert_eq!((nodes.len(), nodes[0].id(), unreadable), (1, 7, 2));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_cluster() {
+        let client = InMemoryMetaClient::new();
+        let cluster1 = Namespaced::new(client.clone(), "cluster1").unwrap();
+        let cluster2 = Namespaced::new(client.clone(), "team/cluster2").unwrap();
+        let node = Node::new(7, "10.0.0.1".to_owned(), 8000, 1);
+        cluster1.create(&node_path(7), &encode_node(&node)).await.unwrap();
+        publish_slots(&cluster1, &[Slot::new(0, 7)], 0).await.unwrap();
+        publish_slots(&cluster2, &[Slot::new(0, 8)], 0).await.unwrap();
+        publish_slots(&client, &[Slot::new(0, 9)], 0).await.unwrap();
+        client.create("/election/rebalancing", b"10.0.0.1:8000").await.unwrap();
+        assert_eq!(list_clusters(&client).await.unwrap(), vec!["", "cluster1", "team/cluster2"]);
+
+        assert!(migrate_cluster(&client, "cluster1", "/cluster1/").await.is_err());
+        assert!(migrate_cluster(&client, "cluster1", "team/cluster2").await.is_err());
+        assert_eq!(migrate_cluster(&client, "cluster1", "cluster3").await.unwrap(), 2);
+
+        // The nodes are left to expire with their leases
+        let cluster3 = Namespaced::new(client.clone(), "cluster3").unwrap();
+        assert_eq!(load_slots(&cluster3).await.unwrap().unwrap().0, vec![Slot::new(0, 7)]);
+        assert_eq!(load_ring_version(&cluster3).await.unwrap(), 1);
+        assert!(load_slots(&cluster1).await.unwrap().is_none());
+        assert_eq!(load_nodes(&cluster1).await.unwrap().0.len(), 1);
+        assert_eq!(list_clusters(&client).await.unwrap(), vec!["", "cluster1", "cluster3", "team/cluster2"]);
+    }
+
+    #[tokio::test]
+    async fn test_publish_slots() {
+        let client = InMemoryMetaClient::new();
+        client.create(&node_path(7), &encode_node(&Node::new(7, "10.0.0.1".to_owned(), 8000, 1))).await.unwrap();
+        client.create(&node_path(8), &encode_node(&Node::new(8, "10.0.0.2".to_owned(), 8001, 1))).await.unwrap();
+        assert_eq!(load_nodes(&client).await.unwrap().0.len(), 2);
+        assert!(load_slots(&client).await.unwrap().is_none());
+        assert_eq!(load_ring_version(&client).await.unwrap(), 0);
+
+        let slots = vec![Slot::new(0, 7), Slot::new(1, 8)];
+        assert_eq!(publish_slots(&client, &slots, 0).await.unwrap(), 1);
+        let (loaded, revision) = load_slots(&client).await.unwrap().unwrap();
+        assert_eq!(loaded, slots);
+
+        // Computed from stale slots
+        assert!(is_conflict(&publish_slots(&client, &slots, 0).await.unwrap_err()));
+        assert_eq!(publish_slots(&client, &slots, revision).await.unwrap(), 2);
+        assert_eq!(load_ring_version(&client).await.unwrap(), 2);
+    }
+}
@@ -77,6 +77,11 @@ impl SlotMapping {
         }
     }
 
+    /// Replace all the slots
+    pub fn replace(&self, slots: Vec<Slot>) {
+        *self.inner.lock().unwrap() = slots;
+    }
+
     /// Get the slot mapping
     pub fn inner(&self) -> Vec<Slot> {
         self.inner.lock().unwrap().clone()